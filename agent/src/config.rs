@@ -0,0 +1,222 @@
+use etsi014_client::KeyQuota;
+use serde::Deserialize;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Operation a client may be allowed to request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Status,
+    GetKeys,
+    GetKeysByIds,
+}
+
+impl Operation {
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Status => "status",
+            Operation::GetKeys => "get_keys",
+            Operation::GetKeysByIds => "get_keys_by_ids",
+        }
+    }
+}
+
+/// Operations and target SAEs allowed for processes of a user.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Allow {
+    pub uid: u32,
+    /// All target SAEs if not given.
+    pub target_sae_ids: Option<Vec<String>>,
+    /// All operations if not given.
+    pub operations: Option<Vec<Operation>>,
+    /// Allow requesting keys from the reserve of the quota.
+    #[serde(default)]
+    pub priority: bool,
+}
+
+impl Allow {
+    pub fn permits(&self, operation: &str, target_sae_id: &str) -> bool {
+        let operation_allowed = self
+            .operations
+            .as_ref()
+            .is_none_or(|operations| operations.iter().any(|o| o.name() == operation));
+        let target_allowed = self
+            .target_sae_ids
+            .as_ref()
+            .is_none_or(|ids| ids.iter().any(|id| id == target_sae_id));
+        operation_allowed && target_allowed
+    }
+}
+
+/// Limits on keys requested per target SAE, see [`KeyQuota`].
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub keys_per_second: Option<f64>,
+    /// Keys that can be requested at once, `keys_per_second` rounded up if not given.
+    pub burst: Option<u32>,
+    pub bits_per_hour: Option<u64>,
+    pub daily_cap: Option<u64>,
+    pub reserve: Option<u32>,
+}
+
+impl QuotaConfig {
+    pub fn key_quota(&self) -> KeyQuota {
+        let mut quota = KeyQuota::new();
+        if let Some(rate) = self.keys_per_second {
+            quota.keys_per_second(rate, self.burst.unwrap_or(rate.ceil() as u32));
+        }
+        if let Some(bits) = self.bits_per_hour {
+            quota.bits_per_hour(bits);
+        }
+        if let Some(keys) = self.daily_cap {
+            quota.daily_cap(keys);
+        }
+        if let Some(stored_key_count) = self.reserve {
+            quota.reserve(stored_key_count);
+        }
+        quota
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentConfig {
+    pub socket: PathBuf,
+    /// Permissions of the socket, access is further restricted by `allow`.
+    #[serde(default = "default_socket_mode")]
+    pub socket_mode: u32,
+    pub host: String,
+    pub port: u16,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub server_ca: PathBuf,
+    /// Append a hash-chained audit record of every request to this file.
+    pub audit_log: Option<PathBuf>,
+    /// Authenticate audit records with the HMAC key in this file, which is created if it
+    /// does not exist.
+    pub audit_key_file: Option<PathBuf>,
+    pub quota: Option<QuotaConfig>,
+    /// Users allowed to use the agent. Processes of other users are refused.
+    #[serde(default)]
+    pub allow: Vec<Allow>,
+}
+
+fn default_socket_mode() -> u32 {
+    0o666
+}
+
+impl AgentConfig {
+    /// Load the configuration at `path`. Relative paths are relative to its directory.
+    pub fn load(path: &Path) -> Result<AgentConfig, Box<dyn Error>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading config {}: {e}", path.display()))?;
+        let mut config: AgentConfig = toml::from_str(&content)
+            .map_err(|e| format!("Error parsing config {}: {e}", path.display()))?;
+        if let Some(dir) = path.parent() {
+            for file in [
+                &mut config.socket,
+                &mut config.cert,
+                &mut config.key,
+                &mut config.server_ca,
+            ] {
+                *file = dir.join(&*file);
+            }
+            for file in [&mut config.audit_log, &mut config.audit_key_file]
+                .into_iter()
+                .flatten()
+            {
+                *file = dir.join(&*file);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Rule for `uid`, `None` if the user is not allowed to use the agent.
+    pub fn allowed(&self, uid: u32) -> Option<&Allow> {
+        self.allow.iter().find(|allow| allow.uid == uid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        socket = "agent.sock"
+        host = "localhost"
+        port = 8443
+        cert = "client.crt"
+        key = "client.key"
+        server_ca = "ca.crt"
+
+        [[allow]]
+        uid = 1000
+
+        [[allow]]
+        uid = 1001
+        operations = ["status", "get_keys"]
+
+        [[allow]]
+        uid = 1002
+        target_sae_ids = ["client-2"]
+        operations = []
+    "#;
+
+    fn config() -> AgentConfig {
+        toml::from_str(CONFIG).unwrap()
+    }
+
+    #[test]
+    fn unknown_users_are_refused() {
+        let config = config();
+        assert!(config.allowed(0).is_none());
+        assert!(config.allowed(1003).is_none());
+        let no_users: AgentConfig =
+            toml::from_str(&CONFIG[..CONFIG.find("[[").unwrap()]).unwrap();
+        assert!(no_users.allowed(1000).is_none());
+    }
+
+    #[test]
+    fn missing_restrictions_allow_everything() {
+        let config = config();
+        let allow = config.allowed(1000).unwrap();
+        for operation in ["status", "get_keys", "get_keys_by_ids"] {
+            assert!(allow.permits(operation, "client-2"), "{operation}");
+            assert!(allow.permits(operation, "client-3"), "{operation}");
+        }
+        assert!(!allow.priority);
+    }
+
+    #[test]
+    fn operations_restrict_access() {
+        let config = config();
+        let allow = config.allowed(1001).unwrap();
+        assert!(allow.permits("status", "client-2"));
+        assert!(allow.permits("get_keys", "client-3"));
+        assert!(!allow.permits("get_keys_by_ids", "client-2"));
+        assert!(!allow.permits("unknown", "client-2"));
+    }
+
+    #[test]
+    fn target_sae_ids_restrict_access() {
+        let mut config = config();
+        config.allow[2].operations = None;
+        let allow = config.allowed(1002).unwrap();
+        assert!(allow.permits("get_keys", "client-2"));
+        assert!(!allow.permits("get_keys", "client-3"));
+        assert!(!allow.permits("get_keys", "client-2/../client-3"));
+        // No operations at all
+        config.allow[2].operations = Some(Vec::new());
+        assert!(!config.allowed(1002).unwrap().permits("status", "client-2"));
+    }
+
+    #[test]
+    fn unknown_operations_are_rejected() {
+        let config = CONFIG.replace(r#""get_keys"]"#, r#""delete_keys"]"#);
+        assert!(toml::from_str::<AgentConfig>(&config).is_err());
+    }
+}
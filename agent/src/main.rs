@@ -0,0 +1,244 @@
+mod config;
+
+use crate::config::AgentConfig;
+use clap::Parser;
+use etsi014_client::agent::{AgentReply, read_request, refused, write_response};
+use etsi014_client::{AuditLog, CredentialFormat, ETSI014Client, ETSI014ClientBuilder};
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::rc::Rc;
+use std::sync::Arc;
+use tokio::io::BufReader;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::task::LocalSet;
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// Hold the SAE credentials and serve keys to local applications over a Unix socket.
+#[derive(Debug, Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    #[arg(
+        long,
+        env = "ETSI014_AGENT_CONFIG",
+        value_name = "FILE",
+        default_value = "/etc/etsi014-agent/config.toml"
+    )]
+    config: PathBuf,
+    #[arg(short, long, action = clap::ArgAction::Count, help = "Log more, -vv for debug")]
+    verbose: u8,
+}
+
+struct Agent {
+    config: AgentConfig,
+    client: Arc<ETSI014Client>,
+}
+
+/// Remove a socket left behind by an agent that is no longer running. Anything that is
+/// not a socket, or a socket that may still be in use, is left alone.
+async fn remove_stale_socket(path: &Path) -> Result<(), Box<dyn Error>> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {}
+        Ok(_) => {
+            return Err(format!("{} exists and is not a socket", path.display()).into());
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("Error checking {}: {e}", path.display()).into()),
+    }
+    match UnixStream::connect(path).await {
+        Ok(_) => {
+            Err(format!("An agent is already listening on {}", path.display()).into())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        // Nothing listens on the socket any more
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => fs::remove_file(path)
+            .map_err(|e| {
+                format!("Error removing stale socket {}: {e}", path.display()).into()
+            }),
+        Err(e) => Err(format!("Error checking socket {}: {e}", path.display()).into()),
+    }
+}
+
+impl Agent {
+    async fn handle(&self, stream: UnixStream) -> Result<(), Box<dyn Error>> {
+        let peer = stream
+            .peer_cred()
+            .map_err(|e| format!("Error getting peer credentials: {e}"))?;
+        let (uid, pid) = (peer.uid(), peer.pid());
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        while let Some(request) = read_request(&mut reader).await? {
+            let operation = request.operation();
+            let target_sae_id = request.target_sae_id().as_str();
+            let result = match self.config.allowed(uid) {
+                Some(allow) if allow.permits(operation, target_sae_id) => {
+                    request.execute(&self.client, allow.priority).await
+                }
+                _ => {
+                    warn!(uid, pid, operation, target_sae_id, "Refused request");
+                    Err(refused(format!(
+                        "User {uid} is not allowed to request {operation} for \
+                         {target_sae_id}"
+                    )))
+                }
+            };
+            match &result {
+                Ok(AgentReply::Keys(keys)) => {
+                    info!(
+                        uid,
+                        pid,
+                        operation,
+                        target_sae_id,
+                        key_count = keys.len(),
+                        "Served keys"
+                    )
+                }
+                Ok(AgentReply::Status(_)) => {
+                    info!(uid, pid, operation, target_sae_id, "Served status")
+                }
+                Err(e) => {
+                    info!(uid, pid, operation, target_sae_id, kind = ?e.kind, "Request failed")
+                }
+            }
+            write_response(&mut writer, &result).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn serve(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let config = AgentConfig::load(&cli.config)?;
+    let mut builder = ETSI014ClientBuilder::new(&config.host, config.port);
+    builder
+        .identity_files(
+            CredentialFormat::Pem,
+            config.cert.clone(),
+            config.key.clone(),
+        )
+        .server_ca_file(CredentialFormat::Pem, config.server_ca.clone());
+    if let Some(path) = &config.audit_log {
+        let audit_log = AuditLog::file(path)?;
+        let audit_log = match &config.audit_key_file {
+            Some(key_file) => audit_log.with_key_file(key_file)?,
+            None => audit_log,
+        };
+        builder.audit_log(Arc::new(audit_log));
+    }
+    if let Some(quota) = &config.quota {
+        builder.key_quota(quota.key_quota());
+    }
+    let client = Arc::new(builder.build()?);
+    // Renewed certificates are picked up without restarting
+    let _watcher = client.watch_credentials()?;
+    remove_stale_socket(&config.socket).await?;
+    let listener = UnixListener::bind(&config.socket)
+        .map_err(|e| format!("Error binding {}: {e}", config.socket.display()))?;
+    fs::set_permissions(
+        &config.socket,
+        fs::Permissions::from_mode(config.socket_mode),
+    )?;
+    info!(socket = %config.socket.display(), "Listening");
+    let socket = config.socket.clone();
+    let agent = Rc::new(Agent {
+        config,
+        client: client.clone(),
+    });
+    // Client errors are not Send, so connections are served on the current thread
+    let local = LocalSet::new();
+    let accept = local.run_until(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!(error = %e, "Error accepting connection");
+                    continue;
+                }
+            };
+            let agent = agent.clone();
+            tokio::task::spawn_local(async move {
+                if let Err(e) = agent.handle(stream).await {
+                    warn!("Error serving connection: {e}");
+                }
+            });
+        }
+    });
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::pin!(accept);
+    loop {
+        tokio::select! {
+            _ = &mut accept => break,
+            _ = tokio::signal::ctrl_c() => {
+                info!("Stopping");
+                break;
+            }
+            _ = terminate.recv() => {
+                info!("Stopping");
+                break;
+            }
+            _ = hangup.recv() => {
+                if let Err(e) = client.reload_credentials() {
+                    warn!("Error reloading credentials: {e}");
+                }
+            }
+        }
+    }
+    let _ = fs::remove_file(socket);
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let cli = Cli::parse();
+    let level = match cli.verbose {
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
+    };
+    // Dependencies might log buffers containing key material at trace level
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(format!("warn,etsi014_client={level},etsi014_agent={level}"))
+    });
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+    if let Err(e) = serve(cli).await {
+        eprintln!("{e}");
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("etsi014-agent-{}-{name}", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn only_stale_sockets_are_removed() {
+        let file = temp_path("file");
+        fs::write(&file, "data").unwrap();
+        let e = remove_stale_socket(&file).await.unwrap_err();
+        assert!(e.to_string().contains("is not a socket"), "{e}");
+        assert!(file.exists());
+        fs::remove_file(&file).unwrap();
+
+        let socket = temp_path("socket");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let e = remove_stale_socket(&socket).await.unwrap_err();
+        assert!(e.to_string().contains("already listening"), "{e}");
+        assert!(socket.exists());
+        drop(listener);
+        remove_stale_socket(&socket).await.unwrap();
+        assert!(!socket.exists());
+        remove_stale_socket(&socket).await.unwrap();
+    }
+}
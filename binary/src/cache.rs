@@ -0,0 +1,119 @@
+use crate::cli::{CacheCommands, CacheKeySource, Cli};
+use crate::output::Format;
+use etsi014_client::{
+    CachedKeyInfo, ETSI014Client, KeyCache, SaeId, SealingKeySource, SecretVec,
+};
+use std::error::Error;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+const PASSPHRASE_ENV: &str = "ETSI014_CACHE_PASSPHRASE";
+
+fn passphrase() -> Result<SecretVec<u8>, Box<dyn Error>> {
+    let mut passphrase = match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => passphrase,
+        Err(_) => rpassword::prompt_password("Key cache passphrase: ").map_err(|e| {
+            format!("Error reading passphrase, set {PASSPHRASE_ENV}: {e}")
+        })?,
+    };
+    let secret = SecretVec::new(passphrase.len(), |s| {
+        s.copy_from_slice(passphrase.as_bytes())
+    });
+    // Safe, as zeroes are valid UTF-8
+    unsafe { passphrase.as_bytes_mut().fill(0) };
+    Ok(secret)
+}
+
+/// Open the key cache in `dir` with the sealing key selected on the command line.
+pub fn open(cli: &Cli, dir: &Path) -> Result<KeyCache, Box<dyn Error>> {
+    let source = match cli.cache_key_source {
+        CacheKeySource::Keyring => SealingKeySource::Keyring {
+            service: "etsi014-cli".to_owned(),
+            user: dir.display().to_string(),
+        },
+        CacheKeySource::File => SealingKeySource::File(
+            cli.cache_key_file
+                .clone()
+                .ok_or("--cache-key-source file requires --cache-key-file")?,
+        ),
+        CacheKeySource::Passphrase => SealingKeySource::Passphrase(passphrase()?),
+    };
+    Ok(KeyCache::open(dir, source)?)
+}
+
+fn time(seconds: u64) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(seconds))
+        .to_string()
+}
+
+fn print_entry(
+    out: &mut impl Write,
+    info: &CachedKeyInfo,
+    format: Format,
+) -> io::Result<()> {
+    if format == Format::Json {
+        serde_json::to_writer(&mut *out, info)?;
+        return writeln!(out);
+    }
+    let expires = match info.expires {
+        Some(_) if info.is_expired() => "expired".to_owned(),
+        Some(expires) => time(expires),
+        None => "-".to_owned(),
+    };
+    writeln!(
+        out,
+        "{}\t{}\t{}\t{}\t{}",
+        info.key_id,
+        info.target_sae_id,
+        info.key_size_bits,
+        time(info.created),
+        expires
+    )
+}
+
+/// Run a cache subcommand. Only `fill` needs a client.
+pub async fn run(
+    key_cache: &KeyCache,
+    client: Option<&ETSI014Client>,
+    target_sae_id: Option<&SaeId>,
+    command: &CacheCommands,
+    format: Format,
+) -> Result<(), Box<dyn Error>> {
+    let mut out = io::stdout().lock();
+    match command {
+        CacheCommands::Fill {
+            key_size_bits,
+            allowed_sae_ids,
+            amount,
+            ttl,
+        } => {
+            let (Some(client), Some(target_sae_id)) = (client, target_sae_id) else {
+                unreachable!("A client is created for filling the cache");
+            };
+            let key_ids = client
+                .fill_key_cache(
+                    *key_size_bits,
+                    target_sae_id,
+                    allowed_sae_ids,
+                    *amount,
+                    *ttl,
+                )
+                .await?;
+            for key_id in key_ids {
+                writeln!(out, "{key_id}")?;
+            }
+        }
+        CacheCommands::List => {
+            for info in key_cache.entries()? {
+                print_entry(&mut out, &info, format)?;
+            }
+        }
+        CacheCommands::Purge { expired } => {
+            let purged = key_cache.purge(*expired)?;
+            eprintln!("Deleted {purged} keys from {}", key_cache.dir().display());
+        }
+    }
+    Ok(())
+}
@@ -1,42 +1,468 @@
-use clap::{Parser, Subcommand};
+use crate::output::{Format, KeyEncoding};
+use clap::{ArgAction, ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use etsi014_client::{KeyId, SaeId, TlsVersion};
+use serde::Deserialize;
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
-    #[arg(long)]
-    pub host: String,
-    #[arg(long, default_value_t = 443)]
-    pub port: u16,
-    #[arg(long, value_name = "FILE")]
-    pub cert: PathBuf,
-    #[arg(long, value_name = "FILE")]
-    pub key: PathBuf,
-    #[arg(long, value_name = "FILE")]
-    pub server_ca: PathBuf,
-    #[arg(long)]
-    pub target_sae_id: String,
+    #[arg(
+        long,
+        env = "ETSI014_CONFIG",
+        value_name = "FILE",
+        help = "Configuration file [default: $XDG_CONFIG_HOME/etsi014-cli/config.toml]",
+        global = true
+    )]
+    pub config: Option<PathBuf>,
+    #[arg(
+        short,
+        long,
+        env = "ETSI014_PROFILE",
+        help = "Profile from the configuration file",
+        global = true
+    )]
+    pub profile: Option<String>,
+    #[arg(long, env = "ETSI014_HOST", global = true)]
+    pub host: Option<String>,
+    #[arg(long, env = "ETSI014_PORT", help = "[default: 443]", global = true)]
+    pub port: Option<u16>,
+    #[arg(long, env = "ETSI014_CERT", value_name = "FILE", global = true)]
+    pub cert: Option<PathBuf>,
+    #[arg(long, env = "ETSI014_KEY", value_name = "FILE", global = true)]
+    pub key: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "ETSI014_PKCS12",
+        value_name = "FILE",
+        help = "PKCS#12 bundle with the client certificate and key, instead of --cert and \
+                --key",
+        conflicts_with_all = ["cert", "key"],
+        global = true
+    )]
+    pub pkcs12: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FD",
+        help = "Read the passphrase of an encrypted key or PKCS#12 bundle from this file \
+                descriptor instead of ETSI014_KEY_PASSPHRASE or a prompt",
+        global = true
+    )]
+    pub key_passphrase_fd: Option<i32>,
+    #[arg(long, env = "ETSI014_SERVER_CA", value_name = "FILE", global = true)]
+    pub server_ca: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "ETSI014_SERVER_CA_DIR",
+        value_name = "DIR",
+        help = "Also trust the CA certificates in the .pem, .crt and .cer files in DIR",
+        global = true
+    )]
+    pub server_ca_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "ETSI014_PIN_SPKI",
+        value_name = "PINS",
+        help = "Only accept a KME certificate with one of these public keys, as \
+                sha256//BASE64 hashes of the SubjectPublicKeyInfo",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub pin_spki: Vec<String>,
+    #[arg(
+        long,
+        env = "ETSI014_SERVER_NAME",
+        help = "Verify the KME certificate against this DNS name instead of --host",
+        global = true
+    )]
+    pub server_name: Option<String>,
+    #[arg(long, env = "ETSI014_MIN_TLS_VERSION", value_enum, global = true)]
+    pub min_tls_version: Option<MinTlsVersion>,
+    #[arg(long, env = "ETSI014_TARGET_SAE_ID", global = true, value_parser = sae_id)]
+    pub target_sae_id: Option<SaeId>,
+    #[arg(
+        long,
+        help = "Fail if the KME identifies the client by another SAE ID than the common \
+                name of the client certificate",
+        global = true
+    )]
+    pub verify_sae_id: bool,
+    #[arg(long, value_enum, default_value_t = Format::Hex, global = true)]
+    pub format: Format,
+    #[arg(
+        short,
+        long,
+        action = ArgAction::Count,
+        help = "Log more, repeat for debug and trace messages. RUST_LOG overrides this",
+        global = true
+    )]
+    pub verbose: u8,
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    pub log_format: LogFormat,
+    #[arg(
+        long,
+        help = "Include request and response bodies, which may contain keys, in errors",
+        global = true
+    )]
+    pub verbose_diagnostics: bool,
+    #[arg(
+        long,
+        env = "ETSI014_LEDGER",
+        value_name = "FILE",
+        help = "Record retrieved keys in a ledger and refuse keys retrieved before",
+        global = true
+    )]
+    pub ledger: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "ETSI014_CONSUMER",
+        help = "Consumer recorded in the ledger",
+        global = true
+    )]
+    pub consumer: Option<String>,
+    #[arg(
+        long,
+        help = "Record keys in the ledger that were retrieved or used before",
+        global = true
+    )]
+    pub allow_key_reuse: bool,
+    #[arg(
+        long,
+        env = "ETSI014_AUDIT_LOG",
+        value_name = "FILE",
+        help = "Append a hash-chained audit record of every request to FILE",
+        global = true
+    )]
+    pub audit_log: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Send audit records to syslog instead of a file",
+        conflicts_with = "audit_log",
+        global = true
+    )]
+    pub audit_syslog: bool,
+    #[arg(
+        long,
+        env = "ETSI014_AUDIT_KEY_FILE",
+        value_name = "FILE",
+        help = "Authenticate audit records with the HMAC key in FILE, which is created if it \
+                does not exist. Keep it apart from the audit log",
+        global = true
+    )]
+    pub audit_key_file: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "ETSI014_KEY_CACHE",
+        value_name = "DIR",
+        help = "Encrypted cache of keys to use when the KME cannot be reached",
+        global = true
+    )]
+    pub key_cache: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        env = "ETSI014_CACHE_KEY_SOURCE",
+        help = "Source of the key that encrypts the key cache",
+        default_value_t = CacheKeySource::Keyring,
+        global = true
+    )]
+    pub cache_key_source: CacheKeySource,
+    #[arg(
+        long,
+        env = "ETSI014_CACHE_KEY_FILE",
+        value_name = "FILE",
+        help = "Key file with --cache-key-source file, created if it does not exist",
+        global = true
+    )]
+    pub cache_key_file: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "ETSI014_AGENT_SOCKET",
+        value_name = "SOCKET",
+        help = "Request status and keys through etsi014-agent instead of connecting to the \
+                KME, no credentials are needed",
+        global = true
+    )]
+    pub agent_socket: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "File descriptor to write key bytes to with --format raw",
+        default_value_t = 1,
+        global = true
+    )]
+    pub fd: i32,
     #[command(subcommand)]
     pub command: Commands,
 }
 
+/// Where the key that encrypts the key cache comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CacheKeySource {
+    /// OS keyring entry, created if it does not exist
+    Keyring,
+    /// File given with --cache-key-file
+    File,
+    /// Passphrase from ETSI014_CACHE_PASSPHRASE or prompted for
+    Passphrase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+pub enum MinTlsVersion {
+    #[value(name = "1.2")]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl From<MinTlsVersion> for TlsVersion {
+    fn from(version: MinTlsVersion) -> Self {
+        match version {
+            MinTlsVersion::Tls12 => TlsVersion::Tls12,
+            MinTlsVersion::Tls13 => TlsVersion::Tls13,
+        }
+    }
+}
+
+/// Format of log messages on stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    /// JSON object per line
+    Json,
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Commands {
-    Status,
+    Status {
+        #[command(flatten)]
+        watch: WatchOptions,
+    },
     GetKeys {
         #[arg(long = "key-size", help = "Key size in bits", default_value_t = 256)]
         key_size_bits: u32,
         #[arg(
             long,
             help = "Additional SAE IDs allowed to retrieve the key",
-            value_delimiter = ','
+            value_delimiter = ',',
+            value_parser = sae_id
         )]
-        allowed_sae_ids: Vec<String>,
+        allowed_sae_ids: Vec<SaeId>,
         #[arg(long, help = "Amount of keys", default_value_t = 1)]
         amount: u32,
+        #[arg(long, help = "Record in the ledger that the keys are used for PURPOSE")]
+        purpose: Option<String>,
+        #[command(flatten)]
+        output: KeyOutput,
     },
     GetKeysByIds {
-        #[arg(long, help = "Ids of keys to retrieve", value_delimiter = ',')]
+        #[arg(
+            long,
+            help = "Ids of keys to retrieve",
+            value_delimiter = ',',
+            value_parser = key_id
+        )]
+        ids: Vec<KeyId>,
+        #[arg(long, help = "Record in the ledger that the keys are used for PURPOSE")]
+        purpose: Option<String>,
+        #[command(flatten)]
+        output: KeyOutput,
+    },
+    /// Serve Prometheus metrics, including the KME status on every scrape
+    Exporter {
+        #[arg(long, help = "Address to listen on", default_value = "127.0.0.1:9014")]
+        listen: SocketAddr,
+        #[arg(
+            long,
+            help = "Additional SAE IDs to report the status of",
+            value_delimiter = ',',
+            value_parser = sae_id
+        )]
+        status_sae_ids: Vec<SaeId>,
+    },
+    /// Query and export the key ledger, or record key usage
+    Ledger {
+        #[command(subcommand)]
+        command: LedgerCommands,
+    },
+    /// Fill, list or purge the key cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+    /// Verify audit logs
+    Audit {
+        #[command(subcommand)]
+        command: AuditCommands,
+    },
+    /// Print the identity in the client certificate and warn before it expires
+    Whoami {
+        #[arg(
+            long,
+            help = "Warn when the certificate expires within this time",
+            default_value = "30days",
+            value_parser = humantime::parse_duration
+        )]
+        warn_before: Duration,
+        #[arg(
+            long,
+            help = "Check with a status request that the KME identifies the client by the \
+                    common name of the certificate"
+        )]
+        check: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommands {
+    /// Request keys from the KME and store them in the cache
+    Fill {
+        #[arg(long = "key-size", help = "Key size in bits", default_value_t = 256)]
+        key_size_bits: u32,
+        #[arg(
+            long,
+            help = "Additional SAE IDs allowed to retrieve the key",
+            value_delimiter = ',',
+            value_parser = sae_id
+        )]
+        allowed_sae_ids: Vec<SaeId>,
+        #[arg(long, help = "Amount of keys", default_value_t = 1)]
+        amount: u32,
+        #[arg(
+            long,
+            help = "Time after which the keys are no longer used",
+            value_parser = humantime::parse_duration
+        )]
+        ttl: Option<Duration>,
+    },
+    /// List cached key IDs, never the keys
+    List,
+    /// Delete cached keys
+    Purge {
+        #[arg(long, help = "Only delete expired keys")]
+        expired: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AuditCommands {
+    /// Verify the hash chain of an audit log, with the key from --audit-key-file if given
+    Verify {
+        #[arg(help = "Audit log to verify [default: --audit-log]")]
+        file: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "Last hash printed by an earlier verification, which must still be in \
+                    the log"
+        )]
+        last_hash: Option<String>,
+        #[arg(
+            long,
+            help = "The log was collected from syslog and contains a chain per process"
+        )]
+        syslog: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum LedgerCommands {
+    /// List ledger entries, one per line or as JSON lines with --format json
+    Query {
+        #[arg(long)]
+        key_id: Option<String>,
+        #[arg(long, value_enum)]
+        event: Option<LedgerEventArg>,
+        #[arg(long)]
+        purpose: Option<String>,
+    },
+    /// Export all ledger entries
+    Export {
+        #[arg(long, help = "Export as CSV instead of a JSON array")]
+        csv: bool,
+    },
+    /// Record that keys are used for a purpose
+    Use {
+        #[arg(long, required = true, help = "Key IDs", value_delimiter = ',')]
         ids: Vec<String>,
+        #[arg(long)]
+        purpose: String,
     },
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LedgerEventArg {
+    Retrieved,
+    Used,
+}
+
+/// Write keys to files instead of stdout.
+#[derive(Args, Debug)]
+#[group(skip)]
+#[command(group(ArgGroup::new("destination").args(["output_dir", "output_file"])))]
+pub struct KeyOutput {
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Write every key to a new file named by its key ID in DIR"
+    )]
+    pub output_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write all keys to a new file, one per line unless the encoding is raw"
+    )]
+    pub output_file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Encoding of keys in output files",
+        default_value_t = KeyEncoding::Raw
+    )]
+    pub encoding: KeyEncoding,
+    #[arg(
+        long,
+        help = "Only print key IDs instead of ID=path lines",
+        requires = "destination"
+    )]
+    pub ids_only: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct WatchOptions {
+    #[arg(long, help = "Keep polling the status")]
+    pub watch: bool,
+    #[arg(
+        long,
+        help = "Time between polls with --watch",
+        value_parser = humantime::parse_duration,
+        default_value = "5s",
+        requires = "watch"
+    )]
+    pub interval: Duration,
+    #[arg(long, value_name = "COUNT", help = "Alert when fewer keys are stored")]
+    pub min_keys: Option<u32>,
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        help = "Shell command run when the stored key count drops below --min-keys",
+        requires = "min_keys"
+    )]
+    pub alert_command: Option<String>,
+    #[arg(
+        long,
+        help = "Exit with status 2 when the stored key count is below --min-keys",
+        requires = "min_keys"
+    )]
+    pub exit_on_alert: bool,
+}
+
+fn sae_id(id: &str) -> Result<SaeId, String> {
+    SaeId::new(id).map_err(|e| e.to_string())
+}
+
+fn key_id(id: &str) -> Result<KeyId, String> {
+    KeyId::new(id).map_err(|e| e.to_string())
+}
@@ -0,0 +1,78 @@
+#[cfg(unix)]
+use etsi014_client::agent::AgentClient;
+use etsi014_client::{ETSI014Client, Error, KeyId, SaeId, SecretVec, Status};
+
+/// Client for the commands that only request status and keys, which connects to the KME
+/// directly or goes through `etsi014-agent`.
+pub enum Client {
+    Direct(Box<ETSI014Client>),
+    #[cfg(unix)]
+    Agent(AgentClient),
+}
+
+impl Client {
+    /// The direct client, for commands the agent does not serve.
+    pub fn direct(self, command: &str) -> Result<ETSI014Client, String> {
+        match self {
+            Client::Direct(client) => Ok(*client),
+            #[cfg(unix)]
+            Client::Agent(_) => {
+                Err(format!("{command} is not supported with --agent-socket"))
+            }
+        }
+    }
+
+    pub async fn get_status(&mut self, target_sae_id: &SaeId) -> Result<Status, Error> {
+        match self {
+            Client::Direct(client) => client.get_status(target_sae_id).await,
+            #[cfg(unix)]
+            Client::Agent(agent) => agent.get_status(target_sae_id).await,
+        }
+    }
+
+    pub async fn get_keys(
+        &mut self,
+        key_size_bits: u32,
+        target_sae_id: &SaeId,
+        additional_target_sae_ids: &[SaeId],
+        amount_of_keys: u32,
+    ) -> Result<Vec<(KeyId, SecretVec<u8>)>, Error> {
+        match self {
+            Client::Direct(client) => {
+                client
+                    .get_keys(
+                        key_size_bits,
+                        target_sae_id,
+                        additional_target_sae_ids,
+                        amount_of_keys,
+                    )
+                    .await
+            }
+            #[cfg(unix)]
+            Client::Agent(agent) => {
+                agent
+                    .get_keys(
+                        key_size_bits,
+                        target_sae_id,
+                        additional_target_sae_ids,
+                        amount_of_keys,
+                    )
+                    .await
+            }
+        }
+    }
+
+    pub async fn get_keys_by_ids(
+        &mut self,
+        target_sae_id: &SaeId,
+        key_ids: &[KeyId],
+    ) -> Result<Vec<(KeyId, SecretVec<u8>)>, Error> {
+        match self {
+            Client::Direct(client) => {
+                client.get_keys_by_ids(target_sae_id, key_ids).await
+            }
+            #[cfg(unix)]
+            Client::Agent(agent) => agent.get_keys_by_ids(target_sae_id, key_ids).await,
+        }
+    }
+}
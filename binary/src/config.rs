@@ -0,0 +1,252 @@
+use crate::cli::{Cli, MinTlsVersion};
+use etsi014_client::SaeId;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_PORT: u16 = 443;
+
+/// Settings of one KME/SAE pair. Relative paths are relative to the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// PKCS#12 bundle instead of `cert` and `key`, see `--pkcs12`.
+    pub pkcs12: Option<PathBuf>,
+    pub server_ca: Option<PathBuf>,
+    /// Directory with more CA certificates, see `--server-ca-dir`.
+    pub server_ca_dir: Option<PathBuf>,
+    /// SPKI pins of the KME, see `--pin-spki`.
+    #[serde(default)]
+    pub pin_spki: Vec<String>,
+    /// Name in the KME certificate, see `--server-name`.
+    pub server_name: Option<String>,
+    /// `"1.2"` or `"1.3"`.
+    pub min_tls_version: Option<MinTlsVersion>,
+    pub target_sae_id: Option<SaeId>,
+    /// Key ledger, see `--ledger`.
+    pub ledger: Option<PathBuf>,
+    /// Audit log, see `--audit-log`.
+    pub audit_log: Option<PathBuf>,
+    /// Key authenticating audit records, see `--audit-key-file`.
+    pub audit_key_file: Option<PathBuf>,
+    /// Key cache directory, see `--key-cache`.
+    pub key_cache: Option<PathBuf>,
+    /// Socket of `etsi014-agent`, see `--agent-socket`.
+    pub agent_socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Profile used when no `--profile` is given.
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Files with the client certificate and private key.
+#[derive(Debug)]
+pub enum Credentials {
+    CertKey { cert: PathBuf, key: PathBuf },
+    Pkcs12(PathBuf),
+}
+
+/// Connection settings after combining the command line, environment and profile.
+#[derive(Debug)]
+pub struct Connection {
+    pub host: String,
+    pub port: u16,
+    pub credentials: Credentials,
+    pub server_ca: Option<PathBuf>,
+    pub server_ca_dir: Option<PathBuf>,
+    pub pin_spki: Vec<String>,
+    pub server_name: Option<String>,
+    pub min_tls_version: Option<MinTlsVersion>,
+    pub target_sae_id: SaeId,
+    pub ledger: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+    pub audit_key_file: Option<PathBuf>,
+    pub key_cache: Option<PathBuf>,
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("etsi014-cli").join("config.toml"))
+}
+
+impl Config {
+    /// Load the configuration from `path`, or from the default location if `path` is
+    /// `None`. A missing file at the default location is treated as an empty configuration.
+    pub fn load(
+        path: Option<&Path>,
+    ) -> Result<(Config, Option<PathBuf>), Box<dyn Error>> {
+        let (path, explicit) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_config_path() {
+                Some(path) => (path, false),
+                None => return Ok((Config::default(), None)),
+            },
+        };
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if !explicit && e.kind() == io::ErrorKind::NotFound => {
+                return Ok((Config::default(), None));
+            }
+            Err(e) => {
+                return Err(
+                    format!("Error reading config {}: {e}", path.display()).into()
+                );
+            }
+        };
+        let mut config: Config = toml::from_str(&content)
+            .map_err(|e| format!("Error parsing config {}: {e}", path.display()))?;
+        if let Some(dir) = path.parent() {
+            for profile in config.profiles.values_mut() {
+                let files = [
+                    &mut profile.cert,
+                    &mut profile.key,
+                    &mut profile.pkcs12,
+                    &mut profile.server_ca,
+                    &mut profile.server_ca_dir,
+                    &mut profile.ledger,
+                    &mut profile.audit_log,
+                    &mut profile.audit_key_file,
+                    &mut profile.key_cache,
+                    &mut profile.agent_socket,
+                ];
+                for file in files.into_iter().flatten() {
+                    *file = dir.join(&*file);
+                }
+            }
+        }
+        Ok((config, Some(path)))
+    }
+}
+
+impl Profile {
+    /// The profile selected with `--profile` or `default_profile`, or an empty profile.
+    pub fn selected(cli: &Cli) -> Result<Profile, Box<dyn Error>> {
+        let (mut config, path) = Config::load(cli.config.as_deref())?;
+        let profile_name = cli.profile.as_ref().or(config.default_profile.as_ref());
+        Ok(match profile_name {
+            Some(name) => config.profiles.remove(name).ok_or_else(|| match &path {
+                Some(path) => format!("Profile '{name}' not found in {}", path.display()),
+                None => format!("Profile '{name}' not found, no config file"),
+            })?,
+            None => Profile::default(),
+        })
+    }
+}
+
+/// A file given on the command line or in an environment variable, or else in the profile.
+fn file_setting(
+    cli: &Cli,
+    arg: &Option<PathBuf>,
+    profile_value: impl FnOnce(Profile) -> Option<PathBuf>,
+) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    match arg {
+        Some(path) => Ok(Some(path.clone())),
+        None => Ok(profile_value(Profile::selected(cli)?)),
+    }
+}
+
+/// The certificate and key or PKCS#12 bundle, `--cert` and `--key` on the command line
+/// take precedence over a bundle in the profile.
+fn credentials(cli: &Cli, profile: &mut Profile) -> Result<Credentials, String> {
+    let pkcs12 = match (&cli.pkcs12, &cli.cert) {
+        (Some(pkcs12), _) => Some(pkcs12.clone()),
+        (None, Some(_)) => None,
+        (None, None) => profile.pkcs12.take(),
+    };
+    match pkcs12 {
+        Some(pkcs12) => Ok(Credentials::Pkcs12(pkcs12)),
+        None => Ok(Credentials::CertKey {
+            cert: required(cli.cert.clone().or(profile.cert.take()), "cert")?,
+            key: required(cli.key.clone().or(profile.key.take()), "key")?,
+        }),
+    }
+}
+
+pub fn client_credentials(cli: &Cli) -> Result<Credentials, Box<dyn Error>> {
+    Ok(credentials(cli, &mut Profile::selected(cli)?)?)
+}
+
+pub fn ledger_path(cli: &Cli) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    file_setting(cli, &cli.ledger, |profile| profile.ledger)
+}
+
+pub fn key_cache_path(cli: &Cli) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    file_setting(cli, &cli.key_cache, |profile| profile.key_cache)
+}
+
+pub fn audit_log_path(cli: &Cli) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    file_setting(cli, &cli.audit_log, |profile| profile.audit_log)
+}
+
+pub fn audit_key_file_path(cli: &Cli) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    file_setting(cli, &cli.audit_key_file, |profile| profile.audit_key_file)
+}
+
+pub fn agent_socket_path(cli: &Cli) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    file_setting(cli, &cli.agent_socket, |profile| profile.agent_socket)
+}
+
+fn required<T>(value: Option<T>, name: &str) -> Result<T, String> {
+    let env = format!("ETSI014_{}", name.to_uppercase().replace('-', "_"));
+    value.ok_or_else(|| {
+        format!(
+            "Missing --{name}, set it on the command line, with {env} or in a profile"
+        )
+    })
+}
+
+/// The target SAE, which is all that is needed with `--agent-socket`.
+pub fn target_sae_id(cli: &Cli) -> Result<SaeId, Box<dyn Error>> {
+    let target_sae_id = match &cli.target_sae_id {
+        Some(target_sae_id) => Some(target_sae_id.clone()),
+        None => Profile::selected(cli)?.target_sae_id,
+    };
+    Ok(required(target_sae_id, "target-sae-id")?)
+}
+
+impl Connection {
+    /// Settings given on the command line or in environment variables take precedence over
+    /// the selected profile.
+    pub fn resolve(cli: &Cli) -> Result<Connection, Box<dyn Error>> {
+        let mut profile = Profile::selected(cli)?;
+        let credentials = credentials(cli, &mut profile)?;
+        let server_ca = cli.server_ca.clone().or(profile.server_ca);
+        let server_ca_dir = cli.server_ca_dir.clone().or(profile.server_ca_dir);
+        // A CA directory can replace the CA file
+        required(server_ca.as_ref().or(server_ca_dir.as_ref()), "server-ca")?;
+        let pin_spki = if cli.pin_spki.is_empty() {
+            profile.pin_spki
+        } else {
+            cli.pin_spki.clone()
+        };
+        Ok(Connection {
+            host: required(cli.host.clone().or(profile.host), "host")?,
+            port: cli.port.or(profile.port).unwrap_or(DEFAULT_PORT),
+            credentials,
+            server_ca,
+            server_ca_dir,
+            pin_spki,
+            server_name: cli.server_name.clone().or(profile.server_name),
+            min_tls_version: cli.min_tls_version.or(profile.min_tls_version),
+            target_sae_id: required(
+                cli.target_sae_id.clone().or(profile.target_sae_id),
+                "target-sae-id",
+            )?,
+            ledger: cli.ledger.clone().or(profile.ledger),
+            audit_log: cli.audit_log.clone().or(profile.audit_log),
+            audit_key_file: cli.audit_key_file.clone().or(profile.audit_key_file),
+            key_cache: cli.key_cache.clone().or(profile.key_cache),
+        })
+    }
+}
@@ -0,0 +1,100 @@
+use crate::config::Credentials;
+use crate::read_file;
+use etsi014_client::{ClientIdentity, ETSI014ClientBuilder, SecretVec};
+use std::error::Error;
+use std::io;
+use std::io::BufRead;
+
+const PASSPHRASE_ENV: &str = "ETSI014_KEY_PASSPHRASE";
+
+/// Identity in the client certificate and the passphrase that was read to parse it.
+type IdentityAndPassphrase = (ClientIdentity, Option<SecretVec<u8>>);
+
+/// Read the first line from `fd`, which stays open as it is owned by the caller of this
+/// program.
+#[cfg(unix)]
+fn read_fd(fd: i32) -> io::Result<Vec<u8>> {
+    use std::fs::File;
+    use std::mem::ManuallyDrop;
+    use std::os::fd::FromRawFd;
+
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let mut line = Vec::new();
+    io::BufReader::new(&*file).read_until(b'\n', &mut line)?;
+    Ok(line)
+}
+
+#[cfg(not(unix))]
+fn read_fd(_fd: i32) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--key-passphrase-fd is only supported on Unix",
+    ))
+}
+
+/// Passphrase of the private key or PKCS#12 bundle, from `fd`, ETSI014_KEY_PASSPHRASE or
+/// a prompt, in that order.
+pub fn key_passphrase(fd: Option<i32>) -> io::Result<SecretVec<u8>> {
+    let mut passphrase = match fd {
+        Some(fd) => read_fd(fd)?,
+        None => match std::env::var(PASSPHRASE_ENV) {
+            Ok(passphrase) => passphrase.into_bytes(),
+            Err(_) => rpassword::prompt_password("Private key passphrase: ")
+                .map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("Error reading passphrase, set {PASSPHRASE_ENV}: {e}"),
+                    )
+                })?
+                .into_bytes(),
+        },
+    };
+    let len = passphrase
+        .strip_suffix(b"\n")
+        .map_or(passphrase.len(), |line| {
+            line.strip_suffix(b"\r").unwrap_or(line).len()
+        });
+    let secret = SecretVec::new(len, |s| s.copy_from_slice(&passphrase[..len]));
+    passphrase.fill(0);
+    Ok(secret)
+}
+
+/// Set the client certificate and key. Unless `passphrase` was already read, it is only
+/// asked for if the key is encrypted.
+pub fn set_identity(
+    builder: &mut ETSI014ClientBuilder,
+    credentials: &Credentials,
+    passphrase: Option<&[u8]>,
+    passphrase_fd: Option<i32>,
+) -> Result<(), Box<dyn Error>> {
+    match credentials {
+        Credentials::CertKey { cert, key } => {
+            builder.identity_pem(&read_file(cert)?, &read_file(key)?)
+        }
+        Credentials::Pkcs12(pkcs12) => builder.identity_pkcs12(&read_file(pkcs12)?),
+    };
+    match passphrase {
+        Some(passphrase) => builder.key_passphrase(passphrase),
+        None => builder.key_passphrase_callback(move || key_passphrase(passphrase_fd)),
+    };
+    Ok(())
+}
+
+/// Identity in the client certificate, and the passphrase if it was needed to decrypt the
+/// certificate in a PKCS#12 bundle.
+pub fn client_identity(
+    credentials: &Credentials,
+    passphrase_fd: Option<i32>,
+) -> Result<IdentityAndPassphrase, Box<dyn Error>> {
+    match credentials {
+        Credentials::CertKey { cert, .. } => {
+            Ok((ClientIdentity::from_pem(&read_file(cert)?)?, None))
+        }
+        Credentials::Pkcs12(pkcs12) => {
+            let passphrase = key_passphrase(passphrase_fd)?;
+            let identity =
+                ClientIdentity::from_pkcs12(&read_file(pkcs12)?, &passphrase.borrow())?;
+            Ok((identity, Some(passphrase)))
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use etsi014_client::metrics;
+use etsi014_client::metrics::prometheus::{Registry, TextEncoder};
+use etsi014_client::{ETSI014Client, SaeId};
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use tokio::net::TcpListener;
+use tokio::task::LocalSet;
+
+struct Exporter {
+    client: ETSI014Client,
+    target_sae_ids: Vec<SaeId>,
+    registry: Registry,
+}
+
+impl Exporter {
+    /// Refresh the status gauges and encode all metrics. Status errors are counted in the
+    /// metrics instead of failing the scrape.
+    async fn metrics(&self) -> Result<String, Box<dyn Error>> {
+        for target_sae_id in &self.target_sae_ids {
+            if let Err(e) = self.client.get_status(target_sae_id).await {
+                eprintln!("Error getting status for {target_sae_id}: {e}");
+            }
+        }
+        Ok(TextEncoder::new().encode_to_string(&self.registry.gather())?)
+    }
+
+    async fn handle(&self, request: Request<Incoming>) -> Response<Full<Bytes>> {
+        let response = |code, content_type, body: String| {
+            Response::builder()
+                .status(code)
+                .header(CONTENT_TYPE, content_type)
+                .body(Full::new(Bytes::from(body)))
+                .expect("Error building response")
+        };
+        if request.method() != Method::GET || request.uri().path() != "/metrics" {
+            return response(StatusCode::NOT_FOUND, "text/plain", "Not found\n".into());
+        }
+        match self.metrics().await {
+            Ok(body) => response(
+                StatusCode::OK,
+                "text/plain; version=0.0.4; charset=utf-8",
+                body,
+            ),
+            Err(e) => response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "text/plain",
+                format!("{e}\n"),
+            ),
+        }
+    }
+}
+
+/// Serve `/metrics` on `listen` until the process is stopped.
+pub async fn serve(
+    client: ETSI014Client,
+    target_sae_ids: Vec<SaeId>,
+    listen: SocketAddr,
+) -> Result<(), Box<dyn Error>> {
+    let registry = Registry::new();
+    metrics::register(&registry)?;
+    let exporter = Rc::new(Exporter {
+        client,
+        target_sae_ids,
+        registry,
+    });
+    let listener = TcpListener::bind(listen).await?;
+    eprintln!("Serving metrics on http://{listen}/metrics");
+    // Client errors are not Send, so connections are served on the current thread
+    LocalSet::new()
+        .run_until(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        eprintln!("Error accepting connection: {e}");
+                        continue;
+                    }
+                };
+                let exporter = exporter.clone();
+                tokio::task::spawn_local(async move {
+                    let service = service_fn(|request| {
+                        let exporter = exporter.clone();
+                        async move { Ok::<_, Infallible>(exporter.handle(request).await) }
+                    });
+                    if let Err(e) = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                    {
+                        eprintln!("Error serving connection: {e}");
+                    }
+                });
+            }
+        })
+        .await
+}
@@ -0,0 +1,137 @@
+use crate::cli::{LedgerCommands, LedgerEventArg};
+use crate::output::Format;
+use etsi014_client::{KeyLedger, LedgerEntry, LedgerEvent};
+use std::error::Error;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+fn event_name(event: LedgerEvent) -> &'static str {
+    match event {
+        LedgerEvent::Retrieved => "retrieved",
+        LedgerEvent::Used => "used",
+    }
+}
+
+fn time(entry: &LedgerEntry) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(entry.time))
+        .to_string()
+}
+
+fn print_entry(
+    out: &mut impl Write,
+    entry: &LedgerEntry,
+    format: Format,
+) -> io::Result<()> {
+    if format == Format::Json {
+        serde_json::to_writer(&mut *out, entry)?;
+        return writeln!(out);
+    }
+    writeln!(
+        out,
+        "{}\t{}\t{}\t{}\t{}\t{}",
+        time(entry),
+        event_name(entry.event),
+        entry.key_id,
+        entry.target_sae_id.as_deref().unwrap_or("-"),
+        entry.purpose.as_deref().unwrap_or("-"),
+        entry.consumer.as_deref().unwrap_or("-"),
+    )
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn export_csv(out: &mut impl Write, entries: &[LedgerEntry]) -> io::Result<()> {
+    writeln!(out, "time,event,key_ID,target_SAE_ID,purpose,consumer")?;
+    for entry in entries {
+        let fields = [
+            time(entry),
+            event_name(entry.event).to_owned(),
+            csv_field(&entry.key_id),
+            csv_field(entry.target_sae_id.as_deref().unwrap_or_default()),
+            csv_field(entry.purpose.as_deref().unwrap_or_default()),
+            csv_field(entry.consumer.as_deref().unwrap_or_default()),
+        ];
+        writeln!(out, "{}", fields.join(","))?;
+    }
+    Ok(())
+}
+
+/// Run a ledger subcommand, which does not need a connection to the KME.
+pub fn run(
+    ledger: &KeyLedger,
+    command: &LedgerCommands,
+    format: Format,
+) -> Result<(), Box<dyn Error>> {
+    let mut out = io::stdout().lock();
+    match command {
+        LedgerCommands::Query {
+            key_id,
+            event,
+            purpose,
+        } => {
+            let event = event.map(|e| match e {
+                LedgerEventArg::Retrieved => LedgerEvent::Retrieved,
+                LedgerEventArg::Used => LedgerEvent::Used,
+            });
+            for entry in ledger.entries()? {
+                if key_id.as_ref().is_some_and(|id| *id != entry.key_id)
+                    || event.is_some_and(|e| e != entry.event)
+                    || purpose.is_some() && *purpose != entry.purpose
+                {
+                    continue;
+                }
+                print_entry(&mut out, &entry, format)?;
+            }
+        }
+        LedgerCommands::Export { csv } => {
+            let entries = ledger.entries()?;
+            if *csv {
+                export_csv(&mut out, &entries)?;
+            } else {
+                serde_json::to_writer_pretty(&mut out, &entries)?;
+                writeln!(out)?;
+            }
+        }
+        LedgerCommands::Use { ids, purpose } => {
+            for id in ids {
+                ledger.record_use(id, purpose)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Record that every key is used for `purpose` before it is handed out.
+pub fn record_use(
+    ledger: Option<&KeyLedger>,
+    keys: &[(etsi014_client::KeyId, etsi014_client::SecretVec<u8>)],
+    purpose: &str,
+) -> Result<(), Box<dyn Error>> {
+    let ledger = ledger.ok_or("--purpose requires a key ledger, set it with --ledger")?;
+    for (id, _) in keys {
+        ledger.record_use(id, purpose)?;
+    }
+    Ok(())
+}
+
+/// Open the ledger at `path` with the consumer and reuse settings of the command line.
+pub fn open(
+    path: &Path,
+    consumer: Option<&str>,
+    allow_reuse: bool,
+) -> Result<KeyLedger, Box<dyn Error>> {
+    let mut ledger = KeyLedger::open(path)?;
+    if let Some(consumer) = consumer {
+        ledger.consumer(consumer);
+    }
+    ledger.allow_reuse(allow_reuse);
+    Ok(ledger)
+}
@@ -0,0 +1,27 @@
+use crate::cli::LogFormat;
+use std::io;
+use std::io::IsTerminal;
+use tracing_subscriber::EnvFilter;
+
+/// Log to stderr, keeping stdout for keys and status. Without `-v` only warnings are
+/// logged. `-v` only applies to this program and the client library, as dependencies might
+/// log buffers containing key material at trace level.
+pub fn init(verbose: u8, format: LogFormat) {
+    let level = match verbose {
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(format!("warn,etsi014_client={level},etsi014_cli={level}"))
+    });
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
@@ -1,99 +1,294 @@
+mod cache;
 mod cli;
+mod client;
+mod config;
+mod credentials;
+mod exporter;
+mod ledger;
+mod logging;
+mod output;
+mod watch;
+mod whoami;
 
-use crate::cli::Cli;
-use crate::cli::Commands::{GetKeys, GetKeysByIds, Status};
+use crate::cli::Commands::{
+    Audit, Cache, Exporter, GetKeys, GetKeysByIds, Ledger, Status, Whoami,
+};
+use crate::cli::{AuditCommands, CacheCommands, Cli, KeyOutput};
+use crate::client::Client;
+use crate::config::{
+    Connection, agent_socket_path, audit_key_file_path, audit_log_path,
+    client_credentials, key_cache_path, ledger_path, target_sae_id,
+};
+use crate::output::{
+    Format, print_key_files, print_keys, write_key_file, write_key_files,
+};
+use crate::watch::KeysBelowThreshold;
 use clap::Parser;
-use etsi014_client::{ETSI014Client, Error, SecretVec};
-use std::io;
-use std::io::Write;
+#[cfg(unix)]
+use etsi014_client::agent::AgentClient;
+use etsi014_client::{AuditLog, AuditVerification, ETSI014ClientBuilder, KeyLedger};
+use etsi014_client::{KeyId, SaeId, SecretVec};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
 use std::process::exit;
+use std::sync::Arc;
+
+/// Client, target SAE and ledger to record the use of keys in.
+type Connected = (Client, SaeId, Option<Arc<KeyLedger>>);
+
+const MISSING_KEY_CACHE: &str = "Missing --key-cache, set it on the command line, with ETSI014_KEY_CACHE or in a profile";
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     if let Err(e) = cli().await {
         eprintln!("{e}");
-        exit(1)
+        exit(if e.is::<KeysBelowThreshold>() { 2 } else { 1 })
     }
 }
 
-fn print_keys(keys: Vec<(String, SecretVec<u8>)>) {
-    let keys_hex = keys
-        .iter()
-        .map(|(id, key)| {
-            let key_hex = SecretVec::new(key.len() * 2, |s| {
-                hex::encode_to_slice(key.borrow().as_ref(), s).unwrap()
-            });
-            (id, key_hex)
-        })
-        .collect::<Vec<_>>();
-    keys_hex.iter().for_each(|(id, key_hex)| {
-        print!("{id}=");
-        io::stdout().write_all(key_hex.borrow().as_ref()).unwrap();
-        println!();
-    })
+fn read_file(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    fs::read(path).map_err(|e| format!("Error reading {}: {e}", path.display()).into())
 }
 
-async fn cli() -> Result<(), Error> {
+async fn cli() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
-    let client =
-        ETSI014Client::new(&cli.host, cli.port, &cli.cert, &cli.key, &cli.server_ca)?;
+    logging::init(cli.verbose, cli.log_format);
+    if let Ledger { command } = &cli.command {
+        let path = ledger_path(&cli)?.ok_or(
+            "Missing --ledger, set it on the command line, with ETSI014_LEDGER or in a profile",
+        )?;
+        let ledger = ledger::open(&path, cli.consumer.as_deref(), cli.allow_key_reuse)?;
+        return ledger::run(&ledger, command, cli.format);
+    }
+    if let Audit {
+        command:
+            AuditCommands::Verify {
+                file,
+                last_hash,
+                syslog,
+            },
+    } = &cli.command
+    {
+        let path = match file {
+            Some(file) => file.clone(),
+            None => audit_log_path(&cli)?.ok_or("Missing audit log, give a FILE")?,
+        };
+        let mut verification = AuditVerification::new();
+        verification.multiple_chains(*syslog);
+        if let Some(key_file) = audit_key_file_path(&cli)? {
+            verification.key_file(&key_file)?;
+        }
+        if let Some(last_hash) = last_hash {
+            verification.last_hash(last_hash);
+        }
+        let summary = verification.verify(&path)?;
+        println!(
+            "{}: {} records in {} chains, last hash {}",
+            path.display(),
+            summary.records,
+            summary.chains,
+            summary.last_hash.as_deref().unwrap_or("-")
+        );
+        return Ok(());
+    }
+    if let Whoami { warn_before, check } = &cli.command {
+        let (identity, passphrase) = credentials::client_identity(
+            &client_credentials(&cli)?,
+            cli.key_passphrase_fd,
+        )?;
+        whoami::print_identity(&identity, cli.format)?;
+        whoami::check_validity(&identity, *warn_before)?;
+        if *check {
+            let passphrase = passphrase.as_ref().map(SecretVec::borrow);
+            let (client, target_sae_id, _) = connect(&cli, passphrase.as_deref())?;
+            let status = client
+                .direct("whoami --check")?
+                .get_status(&target_sae_id)
+                .await?;
+            eprintln!("The KME identifies this client as {}", status.source_sae_id);
+        }
+        return Ok(());
+    }
+    if let Cache { command } = &cli.command {
+        if !matches!(command, CacheCommands::Fill { .. }) {
+            let dir = key_cache_path(&cli)?.ok_or(MISSING_KEY_CACHE)?;
+            let key_cache = cache::open(&cli, &dir)?;
+            return cache::run(&key_cache, None, None, command, cli.format).await;
+        }
+    }
+    let (mut client, target_sae_id, ledger) = match agent_socket_path(&cli)? {
+        Some(socket) => connect_agent(&cli, &socket).await?,
+        None => connect(&cli, None)?,
+    };
     match cli.command {
-        Status => {
-            let s = client.get_status(&cli.target_sae_id).await?;
-            println!(
-                "source_KME_ID={}\n\
-                target_KME_ID={}\n\
-                source_SAE_ID={}\n\
-                target_SAE_ID={}\n\
-                key_size={}\n\
-                stored_key_count={}\n\
-                max_key_count={}\n\
-                max_key_per_request={}\n\
-                max_key_size={}\n\
-                min_key_size={}\n\
-                max_SAE_ID_count={}",
-                s.source_kme_id,
-                s.target_kme_id,
-                s.source_sae_id,
-                s.target_sae_id,
-                s.key_size,
-                s.stored_key_count,
-                s.max_key_count,
-                s.max_key_per_request,
-                s.max_key_size,
-                s.min_key_size,
-                s.max_sae_id_count,
-            );
-            Ok(())
+        Status { watch } => {
+            watch::status(&mut client, &target_sae_id, &watch, cli.format).await
+        }
+        Exporter {
+            listen,
+            mut status_sae_ids,
+        } => {
+            status_sae_ids.insert(0, target_sae_id);
+            exporter::serve(client.direct("exporter")?, status_sae_ids, listen).await
+        }
+        Cache { command } => {
+            let client = client.direct("cache fill")?;
+            let key_cache = client.key_cache().ok_or(MISSING_KEY_CACHE)?;
+            cache::run(
+                key_cache,
+                Some(&client),
+                Some(&target_sae_id),
+                &command,
+                cli.format,
+            )
+            .await
+        }
+        Ledger { .. } | Audit { .. } | Whoami { .. } => {
+            unreachable!(
+                "Ledger, audit and whoami commands are handled before connecting"
+            )
         }
         GetKeys {
             key_size_bits,
             allowed_sae_ids,
             amount,
+            purpose,
+            output,
         } => {
+            check_key_output(&output)?;
             let kl = client
-                .get_keys(
-                    key_size_bits,
-                    &cli.target_sae_id,
-                    &allowed_sae_ids
-                        .iter()
-                        .map(|a| a.as_ref())
-                        .collect::<Vec<_>>(),
-                    amount,
-                )
+                .get_keys(key_size_bits, &target_sae_id, &allowed_sae_ids, amount)
                 .await?;
-            print_keys(kl);
-            Ok(())
+            if let Some(purpose) = &purpose {
+                ledger::record_use(ledger.as_deref(), &kl, purpose)?;
+            }
+            output_keys(&kl, &output, cli.format, cli.fd)
         }
-        GetKeysByIds { ids } => {
-            let kl = client
-                .get_keys_by_ids(
-                    &cli.target_sae_id,
-                    &ids.iter().map(|a| a.as_ref()).collect::<Vec<_>>(),
-                )
-                .await?;
-            print_keys(kl);
-            Ok(())
+        GetKeysByIds {
+            ids,
+            purpose,
+            output,
+        } => {
+            check_key_output(&output)?;
+            let kl = client.get_keys_by_ids(&target_sae_id, &ids).await?;
+            if let Some(purpose) = &purpose {
+                ledger::record_use(ledger.as_deref(), &kl, purpose)?;
+            }
+            output_keys(&kl, &output, cli.format, cli.fd)
         }
     }
 }
+
+/// Connect to the KME with the credentials and files given on the command line, in the
+/// environment or in the profile. `key_passphrase` is the passphrase if it was already read.
+fn connect(
+    cli: &Cli,
+    key_passphrase: Option<&[u8]>,
+) -> Result<Connected, Box<dyn Error>> {
+    let c = Connection::resolve(cli)?;
+    let mut builder = ETSI014ClientBuilder::new(&c.host, c.port);
+    credentials::set_identity(
+        &mut builder,
+        &c.credentials,
+        key_passphrase,
+        cli.key_passphrase_fd,
+    )?;
+    builder
+        .server_name(c.server_name.as_deref())
+        .min_tls_version(c.min_tls_version.map(Into::into))
+        .verbose_diagnostics(cli.verbose_diagnostics);
+    if let Some(path) = &c.server_ca {
+        builder.server_ca_pem(&read_file(path)?);
+    }
+    if let Some(dir) = c.server_ca_dir {
+        builder.add_server_ca_dir(dir);
+    }
+    for pin in &c.pin_spki {
+        builder.add_spki_pin(pin);
+    }
+    if cli.verify_sae_id || matches!(cli.command, Whoami { check: true, .. }) {
+        builder.verify_source_sae_id(None);
+    }
+    if let Some(path) = &c.ledger {
+        let ledger = ledger::open(path, cli.consumer.as_deref(), cli.allow_key_reuse)?;
+        builder.key_ledger(Arc::new(ledger));
+    }
+    let audit_log = match &c.audit_log {
+        Some(path) => Some(AuditLog::file(path)?),
+        None if cli.audit_syslog => Some(syslog_audit_log()?),
+        None => None,
+    };
+    if let Some(audit_log) = audit_log {
+        let audit_log = match &c.audit_key_file {
+            Some(key_file) => audit_log.with_key_file(key_file)?,
+            None => audit_log,
+        };
+        builder.audit_log(Arc::new(audit_log));
+    }
+    if let Some(dir) = &c.key_cache {
+        builder.key_cache(Arc::new(cache::open(cli, dir)?));
+    }
+    let client = builder.build()?;
+    let ledger = client.key_ledger().cloned();
+    Ok((Client::Direct(Box::new(client)), c.target_sae_id, ledger))
+}
+
+/// Connect to `etsi014-agent`, which holds the credentials. The ledger only records the
+/// use of keys, their retrieval is up to the agent.
+#[cfg(unix)]
+async fn connect_agent(cli: &Cli, socket: &Path) -> Result<Connected, Box<dyn Error>> {
+    let agent = AgentClient::connect(socket).await?;
+    let ledger = match ledger_path(cli)? {
+        Some(path) => Some(Arc::new(ledger::open(
+            &path,
+            cli.consumer.as_deref(),
+            cli.allow_key_reuse,
+        )?)),
+        None => None,
+    };
+    Ok((Client::Agent(agent), target_sae_id(cli)?, ledger))
+}
+
+#[cfg(not(unix))]
+async fn connect_agent(_cli: &Cli, _socket: &Path) -> Result<Connected, Box<dyn Error>> {
+    Err("--agent-socket is only supported on Unix".into())
+}
+
+#[cfg(unix)]
+fn syslog_audit_log() -> Result<AuditLog, Box<dyn Error>> {
+    Ok(AuditLog::syslog())
+}
+
+#[cfg(not(unix))]
+fn syslog_audit_log() -> Result<AuditLog, Box<dyn Error>> {
+    Err("--audit-syslog is only supported on Unix".into())
+}
+
+/// Fail before requesting keys instead of after, as the KME does not hand them out again.
+fn check_key_output(output: &KeyOutput) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = &output.output_file {
+        if path.exists() {
+            return Err(format!("Output file {} already exists", path.display()).into());
+        }
+    }
+    Ok(())
+}
+
+fn output_keys(
+    keys: &[(KeyId, SecretVec<u8>)],
+    output: &KeyOutput,
+    format: Format,
+    fd: i32,
+) -> Result<(), Box<dyn Error>> {
+    let written = if let Some(dir) = &output.output_dir {
+        write_key_files(keys, dir, output.encoding)?
+    } else if let Some(path) = &output.output_file {
+        write_key_file(keys, path, output.encoding)?
+    } else {
+        print_keys(keys, format, fd)?;
+        return Ok(());
+    };
+    print_key_files(&written, output.ids_only)?;
+    Ok(())
+}
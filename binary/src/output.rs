@@ -0,0 +1,385 @@
+use base64ct::{Base64, Encoding};
+use clap::ValueEnum;
+use etsi014_client::{KeyId, SecretVec, Status};
+use std::ffi::OsString;
+use std::fs;
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// ID=hex per key, key=value for status
+    Hex,
+    /// ID=base64 per key, key=value for status
+    Base64,
+    /// ETSI GS QKD 014 JSON
+    Json,
+    /// Key bytes to --fd and key IDs per line to stdout, or to stderr if --fd is 1
+    Raw,
+    /// Shell variable assignments
+    Env,
+}
+
+/// Encoding of keys written to files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KeyEncoding {
+    Raw,
+    Hex,
+    Base64,
+}
+
+fn hex_key(key: &SecretVec<u8>) -> SecretVec<u8> {
+    SecretVec::new(key.len() * 2, |s| {
+        hex::encode_to_slice(&*key.borrow(), s).unwrap()
+    })
+}
+
+fn base64_key(key: &SecretVec<u8>) -> SecretVec<u8> {
+    SecretVec::new(Base64::encoded_len(&key.borrow()), |s| {
+        Base64::encode(&key.borrow(), s).unwrap();
+    })
+}
+
+#[cfg(unix)]
+fn raw_output(fd: i32) -> Box<dyn Write> {
+    use std::fs::File;
+    use std::mem::ManuallyDrop;
+    use std::os::fd::FromRawFd;
+
+    struct Fd(ManuallyDrop<File>);
+    impl Write for Fd {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+    // Not closed, as the file descriptor is owned by the caller of this program
+    Box::new(Fd(ManuallyDrop::new(unsafe { File::from_raw_fd(fd) })))
+}
+
+#[cfg(not(unix))]
+fn raw_output(_fd: i32) -> Box<dyn Write> {
+    Box::new(io::stdout())
+}
+
+pub fn print_keys(
+    keys: &[(KeyId, SecretVec<u8>)],
+    format: Format,
+    fd: i32,
+) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    match format {
+        Format::Hex | Format::Base64 => {
+            for (id, key) in keys {
+                let encoded = match format {
+                    Format::Hex => hex_key(key),
+                    _ => base64_key(key),
+                };
+                write!(out, "{id}=")?;
+                out.write_all(&encoded.borrow())?;
+                writeln!(out)?;
+            }
+        }
+        Format::Json => {
+            write!(out, "{{\"keys\":[")?;
+            for (i, (id, key)) in keys.iter().enumerate() {
+                let separator = if i == 0 { "" } else { "," };
+                let id = serde_json::to_string(id)?;
+                write!(out, "{separator}{{\"key_ID\":{id},\"key\":\"")?;
+                out.write_all(&base64_key(key).borrow())?;
+                write!(out, "\"}}")?;
+            }
+            writeln!(out, "]}}")?;
+        }
+        Format::Raw => {
+            let mut raw = raw_output(fd);
+            for (_, key) in keys {
+                raw.write_all(&key.borrow())?;
+            }
+            raw.flush()?;
+            let mut ids: Box<dyn Write> = if fd == 1 {
+                Box::new(io::stderr())
+            } else {
+                Box::new(out)
+            };
+            for (id, _) in keys {
+                writeln!(ids, "{id}")?;
+            }
+            return ids.flush();
+        }
+        Format::Env => {
+            writeln!(out, "ETSI014_KEY_COUNT={}", keys.len())?;
+            for (i, (id, key)) in keys.iter().enumerate() {
+                writeln!(out, "ETSI014_KEY_ID_{i}={}", shell_quote(id))?;
+                write!(out, "ETSI014_KEY_{i}=")?;
+                out.write_all(&hex_key(key).borrow())?;
+                writeln!(out)?;
+            }
+        }
+    }
+    out.flush()
+}
+
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+pub fn print_status(s: &Status, format: Format) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    let strings = [
+        ("source_KME_ID", s.source_kme_id.as_str()),
+        ("target_KME_ID", s.target_kme_id.as_str()),
+        ("source_SAE_ID", s.source_sae_id.as_str()),
+        ("target_SAE_ID", s.target_sae_id.as_str()),
+    ];
+    let numbers = [
+        ("key_size", s.key_size),
+        ("stored_key_count", s.stored_key_count),
+        ("max_key_count", s.max_key_count),
+        ("max_key_per_request", s.max_key_per_request),
+        ("max_key_size", s.max_key_size),
+        ("min_key_size", s.min_key_size),
+        ("max_SAE_ID_count", s.max_sae_id_count),
+    ];
+    match format {
+        Format::Hex | Format::Base64 | Format::Raw => {
+            for (name, value) in strings {
+                writeln!(out, "{name}={value}")?;
+            }
+            for (name, value) in numbers {
+                writeln!(out, "{name}={value}")?;
+            }
+        }
+        Format::Json => {
+            serde_json::to_writer(&mut out, s)?;
+            writeln!(out)?;
+        }
+        Format::Env => {
+            for (name, value) in strings {
+                let name = name.to_uppercase();
+                writeln!(out, "ETSI014_{name}={}", shell_quote(value))?;
+            }
+            for (name, value) in numbers {
+                let name = name.to_uppercase();
+                writeln!(out, "ETSI014_{name}={value}")?;
+            }
+        }
+    }
+    out.flush()
+}
+
+fn write_encoded(
+    file: &mut File,
+    key: &SecretVec<u8>,
+    encoding: KeyEncoding,
+) -> io::Result<()> {
+    match encoding {
+        KeyEncoding::Raw => file.write_all(&key.borrow()),
+        KeyEncoding::Hex => {
+            file.write_all(&hex_key(key).borrow())?;
+            file.write_all(b"\n")
+        }
+        KeyEncoding::Base64 => {
+            file.write_all(&base64_key(key).borrow())?;
+            file.write_all(b"\n")
+        }
+    }
+}
+
+/// Create a new file only readable and writable by the current user. Fails if the file
+/// already exists.
+fn create_key_file(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path).map_err(|e| {
+        io::Error::new(e.kind(), format!("Error creating {}: {e}", path.display()))
+    })
+}
+
+/// Make sure newly created files survive a crash by syncing the directory entry as well.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Hidden file next to `path` that is written first and linked to `path` once complete.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Fail if `path` exists, before anything is written.
+fn check_not_exists(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Error creating {}: file exists", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Link the complete temporary file to `path`, failing instead of replacing a file
+/// created since [`check_not_exists`], and remove the temporary file.
+fn link_into_place(temp_path: &Path, path: &Path) -> io::Result<()> {
+    fs::hard_link(temp_path, path).map_err(|e| {
+        io::Error::new(e.kind(), format!("Error creating {}: {e}", path.display()))
+    })?;
+    fs::remove_file(temp_path)
+}
+
+fn join_ids<'a>(ids: impl Iterator<Item = &'a KeyId>) -> String {
+    ids.map(KeyId::as_str).collect::<Vec<_>>().join(", ")
+}
+
+/// The keys were consumed from the KME, so name the keys that were written and the ones
+/// that were lost.
+fn write_error(
+    e: io::Error,
+    keys: &[(KeyId, SecretVec<u8>)],
+    written: &[(KeyId, PathBuf)],
+) -> io::Error {
+    let lost = join_ids(keys.iter().skip(written.len()).map(|(id, _)| id));
+    let message = if written.is_empty() {
+        format!("{e}. No keys were written, lost keys: {lost}")
+    } else {
+        let written = join_ids(written.iter().map(|(id, _)| id));
+        format!("{e}. Written keys: {written}, lost keys: {lost}")
+    };
+    io::Error::new(e.kind(), message)
+}
+
+/// Write keys to new temporary files, removing them all if one fails.
+fn write_temp_files(
+    files: &[(&Path, Vec<&SecretVec<u8>>)],
+    encoding: KeyEncoding,
+) -> io::Result<Vec<PathBuf>> {
+    let mut temp_paths = Vec::with_capacity(files.len());
+    let result = files.iter().try_for_each(|(path, keys)| {
+        let temp_path = temp_path(path);
+        let mut file = create_key_file(&temp_path)?;
+        temp_paths.push(temp_path);
+        for key in keys {
+            write_encoded(&mut file, key, encoding)?;
+        }
+        file.sync_all()
+    });
+    match result {
+        Ok(()) => Ok(temp_paths),
+        Err(e) => {
+            for temp_path in &temp_paths {
+                let _ = fs::remove_file(temp_path);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Write all keys to a single new file.
+pub fn write_key_file(
+    keys: &[(KeyId, SecretVec<u8>)],
+    path: &Path,
+    encoding: KeyEncoding,
+) -> io::Result<Vec<(KeyId, PathBuf)>> {
+    check_not_exists(path).map_err(|e| write_error(e, keys, &[]))?;
+    let files = [(path, keys.iter().map(|(_, key)| key).collect())];
+    let temp_paths =
+        write_temp_files(&files, encoding).map_err(|e| write_error(e, keys, &[]))?;
+    link_into_place(&temp_paths[0], path).map_err(|e| {
+        let _ = fs::remove_file(&temp_paths[0]);
+        write_error(e, keys, &[])
+    })?;
+    sync_dir(path.parent().unwrap_or(Path::new(".")))?;
+    Ok(keys
+        .iter()
+        .map(|(id, _)| (id.clone(), path.to_path_buf()))
+        .collect())
+}
+
+/// Write every key to a new file named by its key ID in `dir`, which is created with
+/// permissions for the current user only if it does not exist. The keys are written to
+/// temporary files first and linked into place when all are written, so a failed write
+/// leaves no partial set of key files behind.
+pub fn write_key_files(
+    keys: &[(KeyId, SecretVec<u8>)],
+    dir: &Path,
+    encoding: KeyEncoding,
+) -> io::Result<Vec<(KeyId, PathBuf)>> {
+    // Key IDs come from the KME, do not let them escape the output directory
+    if let Some((id, _)) = keys.iter().find(|(id, _)| {
+        id.is_empty() || id == "." || id == ".." || id.contains(['/', '\\', '\0'])
+    }) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Key ID can not be used as file name: {id:?}"),
+        ));
+    }
+    let mut dir_builder = DirBuilder::new();
+    dir_builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        dir_builder.mode(0o700);
+    }
+    dir_builder
+        .create(dir)
+        .map_err(|e| write_error(e, keys, &[]))?;
+    let paths: Vec<_> = keys.iter().map(|(id, _)| dir.join(id.as_str())).collect();
+    for path in &paths {
+        check_not_exists(path).map_err(|e| write_error(e, keys, &[]))?;
+    }
+    let files: Vec<_> = paths
+        .iter()
+        .zip(keys)
+        .map(|(path, (_, key))| (path.as_path(), vec![key]))
+        .collect();
+    let temp_paths =
+        write_temp_files(&files, encoding).map_err(|e| write_error(e, keys, &[]))?;
+    let mut written = Vec::with_capacity(keys.len());
+    for (((id, _), path), temp_path) in keys.iter().zip(paths).zip(&temp_paths) {
+        if let Err(e) = link_into_place(temp_path, &path) {
+            for temp_path in &temp_paths[written.len()..] {
+                let _ = fs::remove_file(temp_path);
+            }
+            return Err(write_error(e, keys, &written));
+        }
+        written.push((id.clone(), path));
+    }
+    sync_dir(dir)?;
+    Ok(written)
+}
+
+/// Print which file each key was written to, or only the key IDs.
+pub fn print_key_files(written: &[(KeyId, PathBuf)], ids_only: bool) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    for (id, path) in written {
+        if ids_only {
+            writeln!(out, "{id}")?;
+        } else {
+            writeln!(out, "{id}={}", path.display())?;
+        }
+    }
+    out.flush()
+}
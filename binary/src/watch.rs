@@ -0,0 +1,212 @@
+use crate::cli::WatchOptions;
+use crate::client::Client;
+use crate::output::{Format, print_status};
+use etsi014_client::{SaeId, Status};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Write;
+use std::time::{Instant, SystemTime};
+use tokio::process::Command;
+use tokio::time::{MissedTickBehavior, interval};
+
+/// Returned with `--exit-on-alert` when the stored key count dropped below `--min-keys`.
+#[derive(Debug)]
+pub struct KeysBelowThreshold {
+    pub stored_key_count: u32,
+    pub min_keys: u32,
+}
+
+impl Display for KeysBelowThreshold {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Stored key count {} is below {}",
+            self.stored_key_count, self.min_keys
+        )
+    }
+}
+
+impl Error for KeysBelowThreshold {}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    at: Instant,
+    stored_key_count: u32,
+}
+
+impl Sample {
+    /// Change of the stored key count per second since `earlier`. Negative if keys are
+    /// consumed faster than they are replenished.
+    fn rate_since(&self, earlier: &Sample) -> Option<f64> {
+        let seconds = (self.at - earlier.at).as_secs_f64();
+        (seconds > 0.0).then(|| {
+            (self.stored_key_count as f64 - earlier.stored_key_count as f64) / seconds
+        })
+    }
+}
+
+fn alert_command(command: &str, status: &Status, min_keys: u32) -> Command {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    cmd.arg(command)
+        .env("ETSI014_TARGET_SAE_ID", status.target_sae_id.as_str())
+        .env(
+            "ETSI014_STORED_KEY_COUNT",
+            status.stored_key_count.to_string(),
+        )
+        .env("ETSI014_MAX_KEY_COUNT", status.max_key_count.to_string())
+        .env("ETSI014_MIN_KEYS", min_keys.to_string());
+    cmd
+}
+
+async fn run_alert_command(command: &str, status: &Status, min_keys: u32) {
+    match alert_command(command, status, min_keys).status().await {
+        Ok(exit_status) if !exit_status.success() => {
+            eprintln!("Alert command failed: {exit_status}")
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Error running alert command: {e}"),
+    }
+}
+
+/// Raise the alert for a low stored key count. Does not wait for the alert command unless
+/// the program is about to exit.
+async fn alert(
+    options: &WatchOptions,
+    status: &Status,
+    min_keys: u32,
+) -> Result<(), KeysBelowThreshold> {
+    let below = KeysBelowThreshold {
+        stored_key_count: status.stored_key_count,
+        min_keys,
+    };
+    if let Some(command) = &options.alert_command {
+        if options.exit_on_alert || !options.watch {
+            run_alert_command(command, status, min_keys).await;
+        } else {
+            let command = command.clone();
+            let status = status.clone();
+            tokio::spawn(
+                async move { run_alert_command(&command, &status, min_keys).await },
+            );
+        }
+    }
+    if options.exit_on_alert {
+        return Err(below);
+    }
+    eprintln!("{below}");
+    Ok(())
+}
+
+fn print_sample(
+    format: Format,
+    status: &Status,
+    rate: Option<f64>,
+    average_rate: Option<f64>,
+    below_threshold: bool,
+) -> io::Result<()> {
+    let time = humantime::format_rfc3339_seconds(SystemTime::now());
+    let mut out = io::stdout().lock();
+    if format == Format::Json {
+        let line = serde_json::json!({
+            "time": time.to_string(),
+            "target_SAE_ID": status.target_sae_id,
+            "stored_key_count": status.stored_key_count,
+            "max_key_count": status.max_key_count,
+            "rate": rate,
+            "average_rate": average_rate,
+            "below_threshold": below_threshold,
+        });
+        writeln!(out, "{line}")?;
+    } else {
+        let percentage = if status.max_key_count == 0 {
+            0.0
+        } else {
+            status.stored_key_count as f64 * 100.0 / status.max_key_count as f64
+        };
+        let format_rate =
+            |rate: Option<f64>| rate.map_or("-".to_string(), |r| format!("{r:+.2}/s"));
+        writeln!(
+            out,
+            "{time} stored_key_count={}/{} ({percentage:.1}%) rate={} average_rate={}{}",
+            status.stored_key_count,
+            status.max_key_count,
+            format_rate(rate),
+            format_rate(average_rate),
+            if below_threshold { " ALERT" } else { "" },
+        )?;
+    }
+    out.flush()
+}
+
+fn print_poll_error(format: Format, e: &etsi014_client::Error) -> io::Result<()> {
+    if format == Format::Json {
+        let time = humantime::format_rfc3339_seconds(SystemTime::now());
+        let line = serde_json::json!({"time": time.to_string(), "error": e.to_string()});
+        let mut out = io::stdout().lock();
+        writeln!(out, "{line}")?;
+        out.flush()?;
+    }
+    eprintln!("{e}");
+    Ok(())
+}
+
+/// Print the status once, or keep polling it with `--watch`. Errors while watching are
+/// reported and do not stop polling.
+pub async fn status(
+    client: &mut Client,
+    target_sae_id: &SaeId,
+    options: &WatchOptions,
+    format: Format,
+) -> Result<(), Box<dyn Error>> {
+    if !options.watch {
+        let s = client.get_status(target_sae_id).await?;
+        print_status(&s, format)?;
+        if let Some(min_keys) = options.min_keys {
+            if s.stored_key_count < min_keys {
+                alert(options, &s, min_keys).await?;
+            }
+        }
+        return Ok(());
+    }
+
+    let mut ticks = interval(options.interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut first: Option<Sample> = None;
+    let mut previous: Option<Sample> = None;
+    let mut alerting = false;
+    loop {
+        ticks.tick().await;
+        let s = match client.get_status(target_sae_id).await {
+            Ok(s) => s,
+            Err(e) => {
+                print_poll_error(format, &e)?;
+                continue;
+            }
+        };
+        let sample = Sample {
+            at: Instant::now(),
+            stored_key_count: s.stored_key_count,
+        };
+        let rate = previous.and_then(|p| sample.rate_since(&p));
+        let average_rate = first.and_then(|f| sample.rate_since(&f));
+        let below_threshold =
+            options.min_keys.is_some_and(|min| s.stored_key_count < min);
+        print_sample(format, &s, rate, average_rate, below_threshold)?;
+        // Only alert when the count drops below the threshold, not on every poll
+        if below_threshold && !alerting {
+            alert(options, &s, options.min_keys.unwrap()).await?;
+        }
+        alerting = below_threshold;
+        first.get_or_insert(sample);
+        previous = Some(sample);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::output::{Format, shell_quote};
+use etsi014_client::ClientIdentity;
+use std::error::Error;
+use std::io;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn time(seconds: u64) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(seconds))
+        .to_string()
+}
+
+pub fn print_identity(identity: &ClientIdentity, format: Format) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    if format == Format::Json {
+        serde_json::to_writer(&mut out, identity)?;
+        writeln!(out)?;
+        return out.flush();
+    }
+    let fields = [
+        ("subject", identity.subject.clone()),
+        (
+            "common_name",
+            identity.common_name.clone().unwrap_or_default(),
+        ),
+        ("subject_alt_names", identity.subject_alt_names.join(",")),
+        ("issuer", identity.issuer.clone()),
+        ("serial", identity.serial.clone()),
+        ("not_before", time(identity.not_before)),
+        ("not_after", time(identity.not_after)),
+        ("sha256_fingerprint", identity.sha256_fingerprint.clone()),
+    ];
+    for (name, value) in fields {
+        if format == Format::Env {
+            let name = name.to_uppercase();
+            writeln!(out, "ETSI014_CERT_{name}={}", shell_quote(&value))?;
+        } else {
+            writeln!(out, "{name}={value}")?;
+        }
+    }
+    out.flush()
+}
+
+/// Fail if the certificate expired, and warn if it is not valid yet or expires within
+/// `warn_before`.
+pub fn check_validity(
+    identity: &ClientIdentity,
+    warn_before: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let not_after = time(identity.not_after);
+    match identity.expires_in() {
+        None => {
+            return Err(format!("The client certificate expired at {not_after}").into());
+        }
+        Some(remaining) if remaining < warn_before => {
+            let days = remaining.as_secs() / (24 * 60 * 60);
+            eprintln!(
+                "Warning: the client certificate expires in {days} days, at {not_after}"
+            );
+        }
+        Some(_) => {}
+    }
+    if UNIX_EPOCH + Duration::from_secs(identity.not_before) > SystemTime::now() {
+        eprintln!(
+            "Warning: the client certificate is not valid before {}",
+            time(identity.not_before)
+        );
+    }
+    Ok(())
+}
@@ -1,14 +1,17 @@
 #include "config.h"
 
 #include <assert.h>
+#include <stdbool.h>
 #include <etsi014-client/etsi014-client.h>
 #include <stdio.h>
 #include <string.h>
 
 #define KEY_SIZE_BYTES KEY_SIZE_BITS / 8
+#define SUBKEY_SIZE_BYTES 16
 
 int main(void)
 {
+    printf("etsi014-client %s\n", e14_version());
     const E14_Client* client = NULL;
     const char* error_str = NULL;
     if (e14_new_etsi014_client(
@@ -55,16 +58,28 @@ int main(void)
         assert(strcmp(keys1[i].uuid, keys2[i].uuid) == 0);
         assert(keys1[i].key_size == KEY_SIZE_BYTES);
         assert(keys2[i].key_size == KEY_SIZE_BYTES);
-        const E14_KeyBytesBorrow* borrow1;
-        const uint8_t* key_bytes1;
-        e14_unprotect_qkd_key_bytes(keys1[i].key_bytes_protected, &borrow1, &key_bytes1);
-        const E14_KeyBytesBorrow* borrow2;
-        const uint8_t* key_bytes2;
-        e14_unprotect_qkd_key_bytes(keys2[i].key_bytes_protected, &borrow2, &key_bytes2);
-        // Warning: constant time compare should be used on keys instead of memcmp
-        assert(memcmp(key_bytes1, key_bytes2, KEY_SIZE_BYTES) == 0);
-        e14_protect_qkd_key_bytes(&borrow1, &key_bytes1);
-        e14_protect_qkd_key_bytes(&borrow2, &key_bytes2);
+        assert(e14_compare_qkd_keys(
+            keys1[i].key_bytes_protected, keys2[i].key_bytes_protected));
+        // Both sides derive the same subkey from their copy of the key
+        const E14_KeyBytesProtected* subkeys[2] = { NULL, NULL };
+        const E14_KeyBytesProtected* sources[2] = {
+            keys1[i].key_bytes_protected, keys2[i].key_bytes_protected
+        };
+        const char info[] = "example";
+        for (int j = 0; j < 2; j++) {
+            if (e14_derive_qkd_key(sources[j], NULL, 0, (const uint8_t*)info,
+                    strlen(info), SUBKEY_SIZE_BYTES, &subkeys[j], &error_str)) {
+                printf("Failed to derive key: %s\n", error_str);
+                e14_free_error_str(&error_str);
+                return 1;
+            }
+        }
+        bool subkeys_equal = e14_compare_qkd_keys(subkeys[0], subkeys[1]);
+        assert(subkeys_equal);
+        printf("Derived %d byte subkey %d, equal on both sides: %s\n",
+            SUBKEY_SIZE_BYTES, i, subkeys_equal ? "yes" : "no");
+        e14_free_qkd_key_bytes(&subkeys[0]);
+        e14_free_qkd_key_bytes(&subkeys[1]);
         e14_free_qkd_key_bytes(&keys1[i].key_bytes_protected);
         e14_free_qkd_key_bytes(&keys2[i].key_bytes_protected);
     }
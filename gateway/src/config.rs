@@ -0,0 +1,163 @@
+use etsi014_client::{CredentialFormat, ETSI014ClientBuilder, KeyQuota};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Endpoint of the ETSI GS QKD 014 API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Status,
+    EncKeys,
+    DecKeys,
+}
+
+impl Operation {
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Status => "status",
+            Operation::EncKeys => "enc_keys",
+            Operation::DecKeys => "dec_keys",
+        }
+    }
+}
+
+/// KME that requests are forwarded to.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Upstream {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub server_ca: PathBuf,
+}
+
+fn default_port() -> u16 {
+    443
+}
+
+/// Amount of keys an SAE may get for each target SAE within a period.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    pub keys: u64,
+    #[serde(deserialize_with = "duration")]
+    pub period: Duration,
+}
+
+impl Quota {
+    /// Token bucket holding `keys` keys, refilled over `period`.
+    pub fn key_quota(&self) -> KeyQuota {
+        let rate = self.keys as f64 / self.period.as_secs_f64();
+        let mut quota = KeyQuota::new();
+        quota.keys_per_second(rate, u32::try_from(self.keys).unwrap_or(u32::MAX));
+        quota
+    }
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    humantime::parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+/// An SAE allowed to use the gateway, and the credentials used for it upstream.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sae {
+    pub sae_id: String,
+    /// Common name of the client certificate of the SAE, its SAE ID if not given.
+    pub certificate_cn: Option<String>,
+    /// Name of the upstream in `upstreams`.
+    pub upstream: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// SAEs this SAE may get status and keys for. All SAEs if not given.
+    pub target_sae_ids: Option<Vec<String>>,
+    /// All operations if not given.
+    pub operations: Option<Vec<Operation>>,
+    pub max_keys_per_request: Option<u32>,
+    pub quota: Option<Quota>,
+}
+
+impl Sae {
+    pub fn certificate_cn(&self) -> &str {
+        self.certificate_cn.as_deref().unwrap_or(&self.sae_id)
+    }
+
+    pub fn permits(&self, operation: Operation, target_sae_id: &str) -> bool {
+        let operation_allowed = self
+            .operations
+            .as_ref()
+            .is_none_or(|operations| operations.contains(&operation));
+        let target_allowed = self
+            .target_sae_ids
+            .as_ref()
+            .is_none_or(|ids| ids.iter().any(|id| id == target_sae_id));
+        operation_allowed && target_allowed
+    }
+
+    /// Builder of the client forwarding requests to `upstream`, with the certificate and
+    /// quota of the SAE.
+    pub fn client_builder(&self, upstream: &Upstream) -> ETSI014ClientBuilder {
+        let mut builder = ETSI014ClientBuilder::new(&upstream.host, upstream.port);
+        builder
+            .identity_files(CredentialFormat::Pem, self.cert.clone(), self.key.clone())
+            .server_ca_file(CredentialFormat::Pem, upstream.server_ca.clone());
+        if let Some(quota) = &self.quota {
+            builder.key_quota(quota.key_quota());
+        }
+        builder
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayConfig {
+    pub listen: SocketAddr,
+    /// Server certificate and key presented to SAEs.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA that issued the client certificates of the SAEs.
+    pub client_ca: PathBuf,
+    pub upstreams: BTreeMap<String, Upstream>,
+    #[serde(rename = "sae")]
+    pub saes: Vec<Sae>,
+}
+
+impl GatewayConfig {
+    /// Load the configuration at `path`. Relative paths are relative to its directory.
+    pub fn load(path: &Path) -> Result<GatewayConfig, Box<dyn Error>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading config {}: {e}", path.display()))?;
+        let mut config: GatewayConfig = toml::from_str(&content)
+            .map_err(|e| format!("Error parsing config {}: {e}", path.display()))?;
+        if let Some(dir) = path.parent() {
+            let files = [&mut config.cert, &mut config.key, &mut config.client_ca]
+                .into_iter()
+                .chain(config.upstreams.values_mut().map(|u| &mut u.server_ca))
+                .chain(
+                    config
+                        .saes
+                        .iter_mut()
+                        .flat_map(|sae| [&mut sae.cert, &mut sae.key]),
+                );
+            for file in files {
+                *file = dir.join(&*file);
+            }
+        }
+        for sae in &config.saes {
+            if !config.upstreams.contains_key(&sae.upstream) {
+                return Err(format!(
+                    "Unknown upstream '{}' for SAE {}",
+                    sae.upstream, sae.sae_id
+                )
+                .into());
+            }
+        }
+        Ok(config)
+    }
+}
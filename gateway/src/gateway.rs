@@ -0,0 +1,846 @@
+use crate::config::{Operation, Quota, Sae};
+use base64ct::{Base64, Encoding};
+use etsi014_client::{ETSI014Client, Error, KeyId, SaeId, SecretVec};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Body, Bytes};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Method, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{info, warn};
+use zeroize::Zeroizing;
+
+const PATH_PREFIX: &str = "/api/v1/keys/";
+/// Largest request body accepted from SAEs.
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// Error returned to the SAE as an ETSI GS QKD 014 error body.
+#[derive(Debug)]
+struct ApiError {
+    code: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn bad_request(message: impl Into<String>) -> ApiError {
+        ApiError {
+            code: StatusCode::BAD_REQUEST,
+            message: message.into(),
+        }
+    }
+
+    fn unauthorized(message: impl Into<String>) -> ApiError {
+        ApiError {
+            code: StatusCode::UNAUTHORIZED,
+            message: message.into(),
+        }
+    }
+
+    fn unavailable(message: impl Into<String>) -> ApiError {
+        ApiError {
+            code: StatusCode::SERVICE_UNAVAILABLE,
+            message: message.into(),
+        }
+    }
+}
+
+/// Body of requests from SAEs, [`hyper::body::Incoming`] when served.
+pub trait RequestBody:
+    Body<Data = Bytes, Error: std::error::Error + Send + Sync + 'static>
+{
+}
+
+impl<B> RequestBody for B where
+    B: Body<Data = Bytes, Error: std::error::Error + Send + Sync + 'static>
+{
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody<'a> {
+    message: &'a str,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct KeyRequest {
+    number: Option<u32>,
+    size: Option<u32>,
+    #[serde(rename = "additional_slave_SAE_IDs", default)]
+    additional_slave_sae_ids: Vec<SaeId>,
+    #[serde(default)]
+    extension_mandatory: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyIdRequest {
+    #[serde(rename = "key_ID")]
+    key_id: KeyId,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyIdsRequest {
+    #[serde(rename = "key_IDs")]
+    key_ids: Vec<KeyIdRequest>,
+}
+
+#[derive(Debug, Serialize)]
+struct KeyAndId {
+    #[serde(rename = "key_ID")]
+    key_id: KeyId,
+    #[serde(serialize_with = "serialize_zeroizing")]
+    key: Zeroizing<String>,
+}
+
+fn serialize_zeroizing<S: Serializer>(
+    value: &Zeroizing<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(value)
+}
+
+#[derive(Debug, Serialize)]
+struct KeyContainer {
+    keys: Vec<KeyAndId>,
+}
+
+/// An SAE with its upstream client, which applies the quota of the SAE.
+pub struct SaeClient {
+    pub config: Sae,
+    pub client: Arc<ETSI014Client>,
+}
+
+impl SaeClient {
+    pub fn new(config: Sae, client: Arc<ETSI014Client>) -> SaeClient {
+        SaeClient { config, client }
+    }
+}
+
+/// Forwards requests of SAEs, identified by their client certificate, to their upstream
+/// KME.
+pub struct Gateway {
+    /// SAEs by the common name of their client certificate.
+    saes: HashMap<String, SaeClient>,
+}
+
+/// Response with a JSON body, which is zeroized when hyper has sent it.
+fn json_response(code: StatusCode, body: Zeroizing<Vec<u8>>) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from_owner(body)))
+        .expect("Error building response")
+}
+
+fn upstream_error(e: Error) -> ApiError {
+    // The error may describe the upstream, so SAEs only get a generic message
+    warn!(kind = ?e.kind, "Upstream request failed: {e}");
+    ApiError::unavailable("Error forwarding the request to the KME")
+}
+
+fn key_container(keys: Vec<(KeyId, SecretVec<u8>)>) -> Zeroizing<Vec<u8>> {
+    let container = KeyContainer {
+        keys: keys
+            .into_iter()
+            .map(|(key_id, key)| KeyAndId {
+                key_id,
+                key: Zeroizing::new(Base64::encode_string(&key.borrow())),
+            })
+            .collect(),
+    };
+    // Growing the buffer would leave copies of the keys behind, so it is allocated for the
+    // longest possible serialization: key IDs escaped as \uXXXX, and the JSON syntax
+    let len = container
+        .keys
+        .iter()
+        .map(|k| 6 * k.key_id.len() + k.key.len() + 32)
+        .sum::<usize>()
+        + 16;
+    let mut body = Zeroizing::new(Vec::with_capacity(len));
+    serde_json::to_writer(&mut *body, &container).expect("Error serializing keys");
+    body
+}
+
+fn json_body_of<T: Serialize>(value: &T) -> Zeroizing<Vec<u8>> {
+    Zeroizing::new(serde_json::to_vec(value).expect("Error serializing response"))
+}
+
+fn query_params<B>(request: &Request<B>) -> Vec<(String, String)> {
+    url::form_urlencoded::parse(request.uri().query().unwrap_or_default().as_bytes())
+        .into_owned()
+        .collect()
+}
+
+fn query_number(
+    params: &[(String, String)],
+    name: &str,
+) -> Result<Option<u32>, ApiError> {
+    params
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| {
+            value
+                .parse()
+                .map_err(|_| ApiError::bad_request(format!("Invalid {name}: {value}")))
+        })
+        .transpose()
+}
+
+async fn json_body<T: for<'de> Deserialize<'de>, B: RequestBody>(
+    request: Request<B>,
+) -> Result<T, ApiError> {
+    let body = Limited::new(request.into_body(), MAX_BODY_LEN)
+        .collect()
+        .await
+        .map_err(|e| ApiError::bad_request(format!("Error reading request body: {e}")))?
+        .to_bytes();
+    serde_json::from_slice(&body)
+        .map_err(|e| ApiError::bad_request(format!("Invalid request body: {e}")))
+}
+
+impl Gateway {
+    pub fn new(saes: Vec<SaeClient>) -> Gateway {
+        Gateway {
+            saes: saes
+                .into_iter()
+                .map(|sae| (sae.config.certificate_cn().to_owned(), sae))
+                .collect(),
+        }
+    }
+
+    /// Handle a request of the SAE with client certificate common name `certificate_cn`.
+    pub async fn handle<B: RequestBody>(
+        &self,
+        certificate_cn: Option<&str>,
+        request: Request<B>,
+    ) -> Response<Full<Bytes>> {
+        match self.route(certificate_cn, request).await {
+            Ok(response) => response,
+            Err(e) => {
+                let body = json_body_of(&ErrorBody {
+                    message: &e.message,
+                });
+                json_response(e.code, body)
+            }
+        }
+    }
+
+    async fn route<B: RequestBody>(
+        &self,
+        certificate_cn: Option<&str>,
+        request: Request<B>,
+    ) -> Result<Response<Full<Bytes>>, ApiError> {
+        let sae = certificate_cn
+            .and_then(|cn| self.saes.get(cn))
+            .ok_or_else(|| ApiError::unauthorized("Unknown client certificate"))?;
+        let path = request.uri().path();
+        let (sae_id, endpoint) = path
+            .strip_prefix(PATH_PREFIX)
+            .and_then(|rest| rest.split_once('/'))
+            .ok_or_else(|| ApiError::bad_request(format!("Unknown path {path}")))?;
+        let sae_id = percent_decode_str(sae_id)
+            .decode_utf8()
+            .map_err(|_| ApiError::bad_request("Invalid SAE ID"))
+            .and_then(|id| {
+                SaeId::new(id).map_err(|e| ApiError::bad_request(e.to_string()))
+            })?;
+        let operation = match endpoint {
+            "status" => Operation::Status,
+            "enc_keys" => Operation::EncKeys,
+            "dec_keys" => Operation::DecKeys,
+            _ => return Err(ApiError::bad_request(format!("Unknown path {path}"))),
+        };
+        let method = request.method();
+        if method != Method::GET
+            && (operation == Operation::Status || method != Method::POST)
+        {
+            return Err(ApiError::bad_request(format!(
+                "Method {method} is not supported for {}",
+                operation.name()
+            )));
+        }
+        let sae_name = &sae.config.sae_id;
+        if !sae.config.permits(operation, &sae_id) {
+            warn!(
+                sae_id = sae_name,
+                operation = operation.name(),
+                target_sae_id = sae_id.as_str(),
+                "Refused request"
+            );
+            return Err(ApiError::unauthorized(format!(
+                "{sae_name} is not allowed to request {} for {sae_id}",
+                operation.name()
+            )));
+        }
+        let response = match operation {
+            Operation::Status => {
+                let status = sae
+                    .client
+                    .get_status(&sae_id)
+                    .await
+                    .map_err(upstream_error)?;
+                json_body_of(&status)
+            }
+            Operation::EncKeys => self.enc_keys(sae, &sae_id, request).await?,
+            Operation::DecKeys => self.dec_keys(sae, &sae_id, request).await?,
+        };
+        info!(
+            sae_id = sae_name,
+            operation = operation.name(),
+            target_sae_id = sae_id.as_str(),
+            "Forwarded request"
+        );
+        Ok(json_response(StatusCode::OK, response))
+    }
+
+    fn check_amount(sae: &SaeClient, amount: u32) -> Result<(), ApiError> {
+        if amount == 0 {
+            return Err(ApiError::bad_request("Number of keys must be at least 1"));
+        }
+        match sae.config.max_keys_per_request {
+            Some(max) if amount > max => Err(ApiError::bad_request(format!(
+                "Number of keys exceeds the maximum of {max} per request"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Forward a keys request. Requests refused by the quota of the SAE are the only
+    /// errors that are described to the SAE.
+    async fn forward_keys(
+        sae: &SaeClient,
+        keys: impl Future<Output = Result<Vec<(KeyId, SecretVec<u8>)>, Error>>,
+    ) -> Result<Zeroizing<Vec<u8>>, ApiError> {
+        match (keys.await, &sae.config.quota) {
+            (Ok(keys), _) => Ok(key_container(keys)),
+            (Err(e), Some(Quota { keys, period }))
+                if format!("{:?}", e.kind) == "QuotaExceeded" =>
+            {
+                Err(ApiError::unavailable(format!(
+                    "Key quota of {keys} keys per {} exceeded",
+                    humantime::format_duration(*period)
+                )))
+            }
+            (Err(e), _) => Err(upstream_error(e)),
+        }
+    }
+
+    async fn enc_keys<B: RequestBody>(
+        &self,
+        sae: &SaeClient,
+        slave_sae_id: &SaeId,
+        request: Request<B>,
+    ) -> Result<Zeroizing<Vec<u8>>, ApiError> {
+        let key_request = if request.method() == Method::POST {
+            json_body::<KeyRequest, _>(request).await?
+        } else {
+            let params = query_params(&request);
+            KeyRequest {
+                number: query_number(&params, "number")?,
+                size: query_number(&params, "size")?,
+                ..Default::default()
+            }
+        };
+        if !key_request.extension_mandatory.is_empty() {
+            return Err(ApiError::bad_request(
+                "Mandatory extensions are not supported",
+            ));
+        }
+        for id in &key_request.additional_slave_sae_ids {
+            if !sae.config.permits(Operation::EncKeys, id) {
+                return Err(ApiError::unauthorized(format!(
+                    "{} is not allowed to request keys for {id}",
+                    sae.config.sae_id
+                )));
+            }
+        }
+        let amount = key_request.number.unwrap_or(1);
+        Self::check_amount(sae, amount)?;
+        let size = match key_request.size {
+            Some(size) => size,
+            // The default key size of the KME
+            None => {
+                sae.client
+                    .get_status(slave_sae_id)
+                    .await
+                    .map_err(upstream_error)?
+                    .key_size
+            }
+        };
+        let additional = &key_request.additional_slave_sae_ids;
+        let keys = sae.client.get_keys(size, slave_sae_id, additional, amount);
+        Self::forward_keys(sae, keys).await
+    }
+
+    async fn dec_keys<B: RequestBody>(
+        &self,
+        sae: &SaeClient,
+        master_sae_id: &SaeId,
+        request: Request<B>,
+    ) -> Result<Zeroizing<Vec<u8>>, ApiError> {
+        let key_ids = if request.method() == Method::POST {
+            json_body::<KeyIdsRequest, _>(request)
+                .await?
+                .key_ids
+                .into_iter()
+                .map(|k| k.key_id)
+                .collect()
+        } else {
+            query_params(&request)
+                .into_iter()
+                .filter(|(key, _)| key == "key_ID")
+                .map(|(_, value)| {
+                    KeyId::new(value).map_err(|e| ApiError::bad_request(e.to_string()))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        let amount = u32::try_from(key_ids.len())
+            .map_err(|_| ApiError::bad_request("Too many key IDs"))?;
+        Self::check_amount(sae, amount)?;
+        let keys = sae.client.get_keys_by_ids(master_sae_id, &key_ids);
+        Self::forward_keys(sae, keys).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Upstream;
+    use etsi014_mock_kme::{MockKme, testdata};
+    use std::time::Duration;
+
+    fn sae_config(sae_id: &str, upstream_sae: &str) -> Sae {
+        Sae {
+            sae_id: sae_id.to_owned(),
+            certificate_cn: None,
+            upstream: "kme".to_owned(),
+            cert: testdata(&format!("{upstream_sae}.crt")),
+            key: testdata(&format!("{upstream_sae}.key")),
+            target_sae_ids: None,
+            operations: None,
+            max_keys_per_request: None,
+            quota: None,
+        }
+    }
+
+    fn sae_client(kme: &MockKme, config: Sae) -> SaeClient {
+        let upstream = Upstream {
+            host: "localhost".to_owned(),
+            port: kme.port(),
+            server_ca: testdata("ca.crt"),
+        };
+        let client = config.client_builder(&upstream).build().unwrap();
+        SaeClient::new(config, Arc::new(client))
+    }
+
+    /// Gateway forwarding the requests of SAEs `client-1` and `client-2` with their own
+    /// certificates.
+    fn gateway(kme: &MockKme, configure: impl Fn(&mut Sae)) -> Gateway {
+        let saes = ["client-1", "client-2"]
+            .into_iter()
+            .map(|id| {
+                let mut config = sae_config(id, id);
+                configure(&mut config);
+                sae_client(kme, config)
+            })
+            .collect();
+        Gateway::new(saes)
+    }
+
+    /// Status and JSON body of the response to a request of the SAE with certificate
+    /// `cn`.
+    async fn send(
+        gateway: &Gateway,
+        cn: Option<&str>,
+        method: Method,
+        uri: &str,
+        body: &str,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Full::new(Bytes::from(body.to_owned())))
+            .unwrap();
+        let response = gateway.handle(cn, request).await;
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    /// Message of an error body, which must have nothing but the message.
+    fn message(body: &Value) -> &str {
+        let object = body.as_object().unwrap();
+        assert_eq!(object.len(), 1, "{body}");
+        object["message"].as_str().unwrap()
+    }
+
+    fn keys(body: &Value) -> Vec<(String, Vec<u8>)> {
+        body["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|k| {
+                let key = Base64::decode_vec(k["key"].as_str().unwrap()).unwrap();
+                (k["key_ID"].as_str().unwrap().to_owned(), key)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn unknown_client_certificates_are_refused() {
+        let kme = MockKme::start();
+        let gateway = gateway(&kme, |_| {});
+        for cn in [None, Some("client-3"), Some("")] {
+            let (status, body) = send(
+                &gateway,
+                cn,
+                Method::GET,
+                "/api/v1/keys/client-2/status",
+                "",
+            )
+            .await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(message(&body), "Unknown client certificate");
+        }
+        assert!(kme.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn certificate_cn_maps_to_the_sae() {
+        let kme = MockKme::start();
+        let mut config = sae_config("sae-a", "client-1");
+        config.certificate_cn = Some("client-3".to_owned());
+        let gateway = Gateway::new(vec![sae_client(&kme, config)]);
+        let uri = "/api/v1/keys/client-2/status";
+        let (status, body) = send(&gateway, Some("client-3"), Method::GET, uri, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["master_SAE_ID"], "client-1");
+        assert_eq!(body["slave_SAE_ID"], "client-2");
+        // The SAE ID is not a certificate name
+        let (status, _) = send(&gateway, Some("sae-a"), Method::GET, uri, "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = send(&gateway, Some("client-1"), Method::GET, uri, "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let requests = kme.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].sae_id, "client-1");
+    }
+
+    #[tokio::test]
+    async fn unauthorized_targets_and_operations_are_refused() {
+        let kme = MockKme::start();
+        let gateway = gateway(&kme, |sae| {
+            sae.target_sae_ids = Some(vec!["client-2".to_owned()]);
+            sae.operations = Some(vec![Operation::Status, Operation::EncKeys]);
+        });
+        let cn = Some("client-1");
+        let (status, body) = send(
+            &gateway,
+            cn,
+            Method::GET,
+            "/api/v1/keys/client-3/status",
+            "",
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            message(&body),
+            "client-1 is not allowed to request status for client-3"
+        );
+        let (status, body) = send(
+            &gateway,
+            cn,
+            Method::GET,
+            "/api/v1/keys/client-2/dec_keys",
+            "",
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            message(&body),
+            "client-1 is not allowed to request dec_keys for client-2"
+        );
+        let (status, body) = send(
+            &gateway,
+            cn,
+            Method::POST,
+            "/api/v1/keys/client-2/enc_keys",
+            r#"{"number": 1, "additional_slave_SAE_IDs": ["client-3"]}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            message(&body),
+            "client-1 is not allowed to request keys for client-3"
+        );
+        // Percent-encoding does not get around the target list
+        let (status, _) = send(
+            &gateway,
+            cn,
+            Method::GET,
+            "/api/v1/keys/client%2D3/status",
+            "",
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(kme.requests().is_empty());
+
+        let (status, _) = send(
+            &gateway,
+            cn,
+            Method::GET,
+            "/api/v1/keys/client-2/status",
+            "",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn invalid_requests_get_error_bodies() {
+        let kme = MockKme::start();
+        let gateway = gateway(&kme, |sae| sae.max_keys_per_request = Some(10));
+        let cn = Some("client-1");
+        let cases = [
+            (
+                Method::GET,
+                "/api/v1/keys/client-2/other",
+                "",
+                "Unknown path",
+            ),
+            (
+                Method::GET,
+                "/api/v2/keys/client-2/status",
+                "",
+                "Unknown path",
+            ),
+            (
+                Method::POST,
+                "/api/v1/keys/client-2/status",
+                "",
+                "Method POST is not supported for status",
+            ),
+            (
+                Method::PUT,
+                "/api/v1/keys/client-2/enc_keys",
+                "",
+                "Method PUT is not supported for enc_keys",
+            ),
+            (
+                Method::GET,
+                "/api/v1/keys/client-2/enc_keys?number=x",
+                "",
+                "Invalid number: x",
+            ),
+            (
+                Method::GET,
+                "/api/v1/keys/client-2/enc_keys?number=0",
+                "",
+                "Number of keys must be at least 1",
+            ),
+            (
+                Method::GET,
+                "/api/v1/keys/client-2/enc_keys?number=11",
+                "",
+                "Number of keys exceeds the maximum of 10 per request",
+            ),
+            (
+                Method::POST,
+                "/api/v1/keys/client-2/enc_keys",
+                "{",
+                "Invalid request body",
+            ),
+            (
+                Method::POST,
+                "/api/v1/keys/client-2/enc_keys",
+                r#"{"extension_mandatory": [{"x": 1}]}"#,
+                "Mandatory extensions are not supported",
+            ),
+            (
+                Method::GET,
+                "/api/v1/keys/client-2/dec_keys",
+                "",
+                "Number of keys must be at least 1",
+            ),
+        ];
+        for (method, uri, body, expected) in cases {
+            let (status, body) = send(&gateway, cn, method, uri, body).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}");
+            assert!(message(&body).starts_with(expected), "{uri}: {body}");
+        }
+        assert!(kme.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn upstream_errors_are_not_passed_on() {
+        let kme = MockKme::start();
+        let gateway = gateway(&kme, |_| {});
+        kme.fail_next(StatusCode::INTERNAL_SERVER_ERROR, "Internal detail");
+        let (status, body) = send(
+            &gateway,
+            Some("client-1"),
+            Method::GET,
+            "/api/v1/keys/client-2/status",
+            "",
+        )
+        .await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(message(&body), "Error forwarding the request to the KME");
+        assert_eq!(kme.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn enc_keys_are_forwarded_from_get_and_post() {
+        let kme = MockKme::start();
+        let gateway = gateway(&kme, |_| {});
+        let cn = Some("client-1");
+        let uri = "/api/v1/keys/client-2/enc_keys";
+        let (status, body) = send(
+            &gateway,
+            cn,
+            Method::GET,
+            &format!("{uri}?number=2&size=512"),
+            "",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let got = keys(&body);
+        assert_eq!(got.len(), 2);
+        assert!(got.iter().all(|(_, key)| key.len() == 64));
+
+        let request = r#"{"number": 3, "size": 128}"#;
+        let (status, body) = send(&gateway, cn, Method::POST, uri, request).await;
+        assert_eq!(status, StatusCode::OK);
+        let got = keys(&body);
+        assert_eq!(got.len(), 3);
+        assert!(got.iter().all(|(_, key)| key.len() == 16));
+
+        // Without a size, the default key size of the KME is requested
+        let (status, body) = send(&gateway, cn, Method::GET, uri, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(keys(&body)[0].1.len(), 32);
+
+        let requests = kme.requests();
+        let bodies: Vec<_> = requests
+            .iter()
+            .filter(|r| r.endpoint == "enc_keys")
+            .map(|r| {
+                assert_eq!(r.method, Method::POST);
+                assert_eq!(r.target_sae_id, "client-2");
+                let body: Value = serde_json::from_slice(&r.body).unwrap();
+                (body["number"].clone(), body["size"].clone())
+            })
+            .collect();
+        assert_eq!(
+            bodies,
+            [
+                (2.into(), 512.into()),
+                (3.into(), 128.into()),
+                (1.into(), 256.into())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn dec_keys_are_forwarded_from_get_and_post() {
+        let kme = MockKme::start();
+        let gateway = gateway(&kme, |_| {});
+        let (_, body) = send(
+            &gateway,
+            Some("client-1"),
+            Method::GET,
+            "/api/v1/keys/client-2/enc_keys?number=4",
+            "",
+        )
+        .await;
+        let sent = keys(&body);
+
+        let uri = "/api/v1/keys/client-1/dec_keys";
+        let query = format!("{uri}?key_ID={}&key_ID={}", sent[0].0, sent[1].0);
+        let cn = Some("client-2");
+        let (status, body) = send(&gateway, cn, Method::GET, &query, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(keys(&body), sent[..2]);
+
+        let request = serde_json::json!({
+            "key_IDs": [{"key_ID": sent[2].0}, {"key_ID": sent[3].0}]
+        });
+        let (status, body) =
+            send(&gateway, cn, Method::POST, uri, &request.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(keys(&body), sent[2..]);
+
+        let requests: Vec<_> = kme
+            .requests()
+            .into_iter()
+            .filter(|r| r.endpoint == "dec_keys")
+            .collect();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.method == Method::POST
+            && r.sae_id == "client-2"
+            && r.target_sae_id == "client-1"));
+        assert_eq!(kme.pending_keys(), 0);
+    }
+
+    fn with_quota(sae: &mut Sae) {
+        sae.quota = Some(Quota {
+            keys: 4,
+            period: Duration::from_secs(3600),
+        });
+    }
+
+    #[tokio::test]
+    async fn quota_is_refunded_when_the_request_fails() {
+        let kme = MockKme::start();
+        let gateway = gateway(&kme, with_quota);
+        let cn = Some("client-1");
+        let uri = "/api/v1/keys/client-2/enc_keys?number=4&size=256";
+        kme.fail_next(StatusCode::SERVICE_UNAVAILABLE, "No keys");
+        let (status, _) = send(&gateway, cn, Method::GET, uri, "").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let (status, body) = send(&gateway, cn, Method::GET, uri, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(keys(&body).len(), 4);
+
+        let requests = kme.requests().len();
+        let (status, body) = send(&gateway, cn, Method::GET, uri, "").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(message(&body), "Key quota of 4 keys per 1h exceeded");
+        assert_eq!(kme.requests().len(), requests);
+
+        // dec_keys requests do not count against the quota
+        let dec_uri = "/api/v1/keys/client-2/dec_keys?key_ID=unknown";
+        let (status, body) = send(&gateway, cn, Method::GET, dec_uri, "").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(message(&body), "Error forwarding the request to the KME");
+        assert_eq!(kme.requests().len(), requests + 1);
+    }
+
+    #[tokio::test]
+    async fn quota_is_refunded_for_keys_not_received() {
+        let kme = MockKme::start();
+        kme.set_max_keys_per_response(1);
+        let gateway = gateway(&kme, with_quota);
+        let cn = Some("client-1");
+        let uri = "/api/v1/keys/client-2/enc_keys?size=256&number=";
+        let (status, body) =
+            send(&gateway, cn, Method::GET, &format!("{uri}4"), "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(keys(&body).len(), 1);
+        // Only possible if 3 keys were refunded
+        let (status, body) =
+            send(&gateway, cn, Method::GET, &format!("{uri}3"), "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(keys(&body).len(), 1);
+
+        let (status, body) =
+            send(&gateway, cn, Method::GET, &format!("{uri}3"), "").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(message(&body), "Key quota of 4 keys per 1h exceeded");
+        let (status, _) = send(&gateway, cn, Method::GET, &format!("{uri}2"), "").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
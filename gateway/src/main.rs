@@ -0,0 +1,298 @@
+mod config;
+mod gateway;
+
+use crate::config::GatewayConfig;
+use crate::gateway::{Gateway, SaeClient};
+use clap::Parser;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::rc::Rc;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::task::LocalSet;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::RootCertStore;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tracing::{debug, info, warn};
+use tracing_subscriber::EnvFilter;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Serve the ETSI GS QKD 014 API to SAEs and forward their requests to upstream KMEs.
+#[derive(Debug, Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    #[arg(
+        long,
+        env = "ETSI014_GATEWAY_CONFIG",
+        value_name = "FILE",
+        default_value = "/etc/etsi014-gateway/config.toml"
+    )]
+    config: PathBuf,
+    #[arg(short, long, action = clap::ArgAction::Count, help = "Log more, -vv for debug")]
+    verbose: u8,
+}
+
+fn tls_acceptor(config: &GatewayConfig) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let pem_error = |path: &Path, e| format!("Error reading {}: {e}", path.display());
+    let certs = CertificateDer::pem_file_iter(&config.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(&config.cert, e))?;
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .map_err(|e| pem_error(&config.key, e))?;
+    let mut roots = RootCertStore::empty();
+    for ca in CertificateDer::pem_file_iter(&config.client_ca)
+        .map_err(|e| pem_error(&config.client_ca, e))?
+    {
+        roots.add(ca.map_err(|e| pem_error(&config.client_ca, e))?)?;
+    }
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+    let server_config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Common name of the client certificate, which identifies the SAE.
+fn certificate_cn(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(str::to_owned)
+}
+
+async fn serve_connection(
+    gateway: Rc<Gateway>,
+    acceptor: TlsAcceptor,
+    stream: TcpStream,
+) -> Result<(), Box<dyn Error>> {
+    let stream = acceptor.accept(stream).await?;
+    let cn = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(certificate_cn);
+    debug!(certificate_cn = cn, "Accepted connection");
+    let cn = Rc::new(cn);
+    let service = service_fn(|request| {
+        let gateway = gateway.clone();
+        let cn = cn.clone();
+        async move { Ok::<_, Infallible>(gateway.handle(cn.as_deref(), request).await) }
+    });
+    http1::Builder::new()
+        .serve_connection(TokioIo::new(stream), service)
+        .await?;
+    Ok(())
+}
+
+async fn serve(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let mut config = GatewayConfig::load(&cli.config)?;
+    let acceptor = tls_acceptor(&config)?;
+    let mut saes = Vec::new();
+    let mut clients = Vec::new();
+    let mut watchers = Vec::new();
+    for sae in config.saes.drain(..) {
+        let upstream = &config.upstreams[&sae.upstream];
+        let client = sae.client_builder(upstream).build()?;
+        let client = Arc::new(client);
+        // Renewed certificates are picked up without restarting
+        watchers.push(client.watch_credentials()?);
+        clients.push(client.clone());
+        saes.push(SaeClient::new(sae, client));
+    }
+    let gateway = Rc::new(Gateway::new(saes));
+    let listener = TcpListener::bind(config.listen)
+        .await
+        .map_err(|e| format!("Error binding {}: {e}", config.listen))?;
+    info!(listen = %config.listen, "Listening");
+    // Client errors are not Send, so connections are served on the current thread
+    let local = LocalSet::new();
+    let accept = local.run_until(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!(error = %e, "Error accepting connection");
+                    continue;
+                }
+            };
+            let gateway = gateway.clone();
+            let acceptor = acceptor.clone();
+            tokio::task::spawn_local(async move {
+                if let Err(e) = serve_connection(gateway, acceptor, stream).await {
+                    warn!(%peer, "Error serving connection: {e}");
+                }
+            });
+        }
+    });
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::pin!(accept);
+    loop {
+        tokio::select! {
+            _ = &mut accept => break,
+            _ = tokio::signal::ctrl_c() => {
+                info!("Stopping");
+                break;
+            }
+            _ = terminate.recv() => {
+                info!("Stopping");
+                break;
+            }
+            _ = hangup.recv() => {
+                for client in &clients {
+                    if let Err(e) = client.reload_credentials() {
+                        warn!("Error reloading credentials: {e}");
+                    }
+                }
+            }
+        }
+    }
+    drop(watchers);
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let cli = Cli::parse();
+    let level = match cli.verbose {
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
+    };
+    // Dependencies might log buffers containing key material at trace level
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(format!(
+            "warn,etsi014_client={level},etsi014_gateway={level}"
+        ))
+    });
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+    if let Err(e) = serve(cli).await {
+        eprintln!("{e}");
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Sae, Upstream};
+    use etsi014_client::{CredentialFormat, ETSI014ClientBuilder, SaeId};
+    use etsi014_mock_kme::{MockKme, testdata};
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+
+    fn config(kme: &MockKme) -> GatewayConfig {
+        let upstream = Upstream {
+            host: "localhost".to_owned(),
+            port: kme.port(),
+            server_ca: testdata("ca.crt"),
+        };
+        GatewayConfig {
+            listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+            cert: testdata("kme.crt"),
+            key: testdata("kme.key"),
+            client_ca: testdata("ca.crt"),
+            upstreams: BTreeMap::from([("kme".to_owned(), upstream)]),
+            saes: vec![Sae {
+                sae_id: "sae-a".to_owned(),
+                certificate_cn: Some("client-3".to_owned()),
+                upstream: "kme".to_owned(),
+                cert: testdata("client-1.crt"),
+                key: testdata("client-1.key"),
+                target_sae_ids: None,
+                operations: None,
+                max_keys_per_request: None,
+                quota: None,
+            }],
+        }
+    }
+
+    /// Serve `config` on the current `LocalSet`, returning the address listened on.
+    async fn start(mut config: GatewayConfig) -> SocketAddr {
+        let acceptor = tls_acceptor(&config).unwrap();
+        let saes = config
+            .saes
+            .drain(..)
+            .map(|sae| {
+                let upstream = &config.upstreams[&sae.upstream];
+                let client = sae.client_builder(upstream).build().unwrap();
+                SaeClient::new(sae, Arc::new(client))
+            })
+            .collect();
+        let gateway = Rc::new(Gateway::new(saes));
+        let listener = TcpListener::bind(config.listen).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task::spawn_local(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let gateway = gateway.clone();
+                let acceptor = acceptor.clone();
+                tokio::task::spawn_local(serve_connection(gateway, acceptor, stream));
+            }
+        });
+        addr
+    }
+
+    fn client(addr: SocketAddr, sae: &str) -> etsi014_client::ETSI014Client {
+        ETSI014ClientBuilder::new("localhost", addr.port())
+            .identity_files(
+                CredentialFormat::Pem,
+                testdata(&format!("{sae}.crt")),
+                testdata(&format!("{sae}.key")),
+            )
+            .server_ca_file(CredentialFormat::Pem, testdata("ca.crt"))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn certificate_cn_is_read_from_the_subject() {
+        for (file, cn) in [("client-3.crt", "client-3"), ("kme.crt", "kme-1")] {
+            let cert = CertificateDer::from_pem_file(testdata(file)).unwrap();
+            assert_eq!(certificate_cn(&cert).as_deref(), Some(cn));
+        }
+        assert_eq!(
+            certificate_cn(&CertificateDer::from(vec![0x30, 0x03])),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn client_certificate_selects_the_sae() {
+        let kme = MockKme::start();
+        let local = LocalSet::new();
+        local
+            .run_until(async {
+                let addr = start(config(&kme)).await;
+                let target = SaeId::new("client-2").unwrap();
+                let status = client(addr, "client-3").get_status(&target).await.unwrap();
+                // Forwarded with the upstream credentials of the SAE
+                assert_eq!(status.source_sae_id, "client-1");
+                assert_eq!(status.target_sae_id, "client-2");
+                let requests = kme.requests();
+                assert_eq!(requests.len(), 1);
+                assert_eq!(requests[0].sae_id, "client-1");
+
+                let e = client(addr, "client-2")
+                    .get_status(&target)
+                    .await
+                    .unwrap_err();
+                assert_eq!(format!("{:?}", e.kind), "InvalidResponse");
+                assert!(e.to_string().contains("Unknown client certificate"), "{e}");
+                assert_eq!(kme.requests().len(), 1);
+            })
+            .await;
+    }
+}
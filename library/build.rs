@@ -1,25 +1,230 @@
 extern crate cbindgen;
 
 use cbindgen::Config;
+use std::collections::BTreeMap;
 use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const HEADER_PATH: &str = "./c/etsi014-client.h";
+const LIB_NAME: &str = "libetsi014_client.so";
+
+struct Version {
+    full: String,
+    major: u32,
+    minor: u32,
+    patch: u32,
+}
+
+impl Version {
+    fn from_env() -> Self {
+        let number = |name| env::var(name).unwrap().parse().unwrap();
+        Version {
+            full: env::var("CARGO_PKG_VERSION").unwrap(),
+            major: number("CARGO_PKG_VERSION_MAJOR"),
+            minor: number("CARGO_PKG_VERSION_MINOR"),
+            patch: number("CARGO_PKG_VERSION_PATCH"),
+        }
+    }
+
+    /// Versions before 1.0.0 may break the ABI in every minor version.
+    fn abi(&self) -> String {
+        abi_version(self.major, self.minor)
+    }
+
+    fn soname(&self) -> String {
+        format!("{LIB_NAME}.{}", self.abi())
+    }
+}
+
+fn abi_version(major: u32, minor: u32) -> String {
+    if major == 0 {
+        format!("0.{minor}")
+    } else {
+        major.to_string()
+    }
+}
 
 fn main() {
+    let version = Version::from_env();
+    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap();
+    let elf_targets = ["linux", "android", "freebsd", "netbsd", "openbsd"];
+    if elf_targets.contains(&target_os.as_str()) {
+        println!(
+            "cargo:rustc-cdylib-link-arg=-Wl,-soname,{}",
+            version.soname()
+        );
+    }
+    write_package_files(&version);
+    println!("cargo:rerun-if-env-changed=E14_NO_BINDINGS");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=c/cbindgen.toml");
     if env::var("E14_NO_BINDINGS").is_ok() {
         return;
     }
     let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
 
-    cbindgen::Builder::new()
+    let bindings = cbindgen::Builder::new()
         .with_crate(crate_dir)
         .with_config(Config::from_file("./c/cbindgen.toml").unwrap())
         .generate()
-        .map_or_else(
-            |error| match error {
-                cbindgen::Error::ParseSyntaxError { .. } => {}
-                e => panic!("{:?}", e),
-            },
-            |bindings| {
-                bindings.write_to_file("./c/etsi014-client.h");
-            },
+        .unwrap_or_else(|e| {
+            panic!("Error generating C bindings, set E14_NO_BINDINGS=1 to skip: {e:?}")
+        });
+    let mut header = Vec::new();
+    bindings.write(&mut header);
+    let header = String::from_utf8(header).expect("Generated header is not UTF-8");
+    check_version_defines(&header, &version);
+    if let Ok(previous_header) = fs::read_to_string(HEADER_PATH) {
+        check_abi_compatibility(&previous_header, &header, &version);
+    }
+    bindings.write_to_file(HEADER_PATH);
+}
+
+/// Write pkg-config and CMake package files to `OUT_DIR`, from where `c/install.sh` installs
+/// them. The pkg-config prefix can be set with `E14_INSTALL_PREFIX`.
+fn write_package_files(version: &Version) {
+    println!("cargo:rerun-if-env-changed=E14_INSTALL_PREFIX");
+    let prefix = env::var("E14_INSTALL_PREFIX").unwrap_or("/usr/local".to_string());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let substitute = |template: &str| {
+        template
+            .replace("@PREFIX@", &prefix)
+            .replace("@VERSION@", &version.full)
+            .replace("@VERSION_MAJOR@", &version.major.to_string())
+            .replace("@VERSION_MINOR@", &version.minor.to_string())
+            .replace("@SONAME@", &version.soname())
+    };
+    let files = [
+        ("etsi014-client.pc.in", "etsi014-client.pc"),
+        (
+            "etsi014-client-config.cmake.in",
+            "etsi014-client-config.cmake",
+        ),
+        (
+            "etsi014-client-config-version.cmake.in",
+            "etsi014-client-config-version.cmake",
+        ),
+    ];
+    for (template_name, file_name) in files {
+        let template_path = Path::new("./c").join(template_name);
+        println!("cargo:rerun-if-changed={}", template_path.display());
+        let template = fs::read_to_string(&template_path).unwrap();
+        fs::write(out_dir.join(file_name), substitute(&template)).unwrap();
+    }
+}
+
+fn find_define(header: &str, name: &str) -> Option<u32> {
+    header.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("#define"), Some(n), Some(value)) if n == name => value.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+fn check_version_defines(header: &str, version: &Version) {
+    let defines = (
+        find_define(header, "E14_VERSION_MAJOR"),
+        find_define(header, "E14_VERSION_MINOR"),
+        find_define(header, "E14_VERSION_PATCH"),
+    );
+    let expected = (
+        Some(version.major),
+        Some(version.minor),
+        Some(version.patch),
+    );
+    if defines != expected {
+        panic!(
+            "E14_VERSION_* constants in src/c.rs do not match version {}",
+            version.full
         );
+    }
+}
+
+/// Split a header into its declarations, keyed by the declared name, with comments removed
+/// and whitespace normalized.
+fn declarations(header: &str) -> BTreeMap<String, String> {
+    let mut without_comments = String::new();
+    let mut rest = header;
+    while let Some(start) = rest.find("/*") {
+        without_comments.push_str(&rest[..start]);
+        rest = rest[start..]
+            .find("*/")
+            .map_or("", |end| &rest[start + end + 2..]);
+    }
+    without_comments.push_str(rest);
+
+    let mut declarations = BTreeMap::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    for line in without_comments.lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            // Version defines change with every release
+            if line.starts_with("#define") && !line.contains("E14_VERSION_") {
+                let name = line.split_whitespace().nth(1).unwrap_or_default();
+                declarations.insert(name.to_string(), line.to_string());
+            }
+            continue;
+        }
+        for c in line.chars() {
+            current.push(c);
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                ';' if depth == 0 => {
+                    let declaration =
+                        current.split_whitespace().collect::<Vec<_>>().join(" ");
+                    // Name after the body of a struct or enum, or before the arguments
+                    // of a function
+                    let head = match declaration.rfind('}') {
+                        Some(end) => &declaration[end + 1..],
+                        None => declaration.split('(').next().unwrap_or_default(),
+                    };
+                    let name = head
+                        .trim_end_matches(';')
+                        .split_whitespace()
+                        .last()
+                        .unwrap_or_default()
+                        .trim_start_matches('*')
+                        .to_string();
+                    declarations.insert(name, declaration);
+                    current.clear();
+                }
+                _ => {}
+            }
+        }
+        current.push(' ');
+    }
+    declarations
+}
+
+/// Fail the build if a declaration of the previous header was removed or changed, unless the
+/// version in Cargo.toml was bumped to a new ABI version.
+fn check_abi_compatibility(previous_header: &str, header: &str, version: &Version) {
+    let previous_abi = match (
+        find_define(previous_header, "E14_VERSION_MAJOR"),
+        find_define(previous_header, "E14_VERSION_MINOR"),
+    ) {
+        (Some(major), Some(minor)) => abi_version(major, minor),
+        // Header from before versioning
+        _ => return,
+    };
+    if previous_abi != version.abi() {
+        return;
+    }
+    let current = declarations(header);
+    let incompatible = declarations(previous_header)
+        .into_iter()
+        .filter(|(name, declaration)| current.get(name) != Some(declaration))
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    if !incompatible.is_empty() {
+        panic!(
+            "C ABI changed for {incompatible:?} without changing ABI version {previous_abi}. \
+            Bump the version in Cargo.toml and the E14_VERSION_* constants in src/c.rs."
+        );
+    }
 }
@@ -9,6 +9,49 @@
 
 #define KEY_UUID_LENGTH 37
 
+/**
+ * Version of the header, checked against Cargo.toml by build.rs. Compare with
+ * [`e14_version`] to detect a mismatch with the loaded library.
+ */
+#define E14_VERSION_MAJOR 0
+
+#define E14_VERSION_MINOR 2
+
+#define E14_VERSION_PATCH 0
+
+typedef enum E14_CredentialFormat {
+    E14_CredentialFormat_Pem,
+    /**
+     * DER encoded certificate and private key.
+     */
+    E14_CredentialFormat_Der,
+} E14_CredentialFormat;
+
+typedef enum E14_TlsVersion {
+    /**
+     * The default of the TLS backend.
+     */
+    E14_TlsVersion_Default,
+    E14_TlsVersion_Tls12,
+    E14_TlsVersion_Tls13,
+} E14_TlsVersion;
+
+typedef enum E14_HttpVersion {
+    /**
+     * Negotiate HTTP/1.1 or HTTP/2 using ALPN.
+     */
+    E14_HttpVersion_Auto,
+    E14_HttpVersion_Http1Only,
+    E14_HttpVersion_Http2PriorKnowledge,
+} E14_HttpVersion;
+
+/**
+ * Options for creating a client with [`e14_new_etsi014_client_from_options`], with
+ * credentials from memory or files. Created by [`e14_new_client_options`] and freed with
+ * [`e14_free_client_options`].
+ */
+typedef struct E14_ClientOptions E14_ClientOptions;
+
 typedef struct E14_Client E14_Client;
 
 typedef struct E14_KeyBytesBorrow E14_KeyBytesBorrow;
@@ -35,6 +78,12 @@ typedef struct E14_QKD_Key {
     const struct E14_KeyBytesProtected *key_bytes_protected;
 } E14_QKD_Key;
 
+/**
+ * Version of the loaded library, e.g. "0.2.0". The string is statically allocated and must
+ * not be freed.
+ */
+const char *e14_version(void);
+
 /**
  * If this function returns a 0, the caller must call [`e14_free_etsi014_client`]. Otherwise,
  * the caller must call [`e14_free_error_str`].
@@ -47,6 +96,176 @@ int e14_new_etsi014_client(const char *host,
                            const struct E14_Client **etsi014_client,
                            const char **error_str);
 
+/**
+ * Like [`e14_new_etsi014_client`], with a PEM key that may be encrypted. If `key_path` is
+ * NULL, `cert_path` is a PKCS#12 bundle containing the client certificate and key.
+ * `passphrase` is the passphrase of the encrypted key or bundle, NULL if it is not
+ * encrypted. If this function returns a 0, the caller must call
+ * [`e14_free_etsi014_client`]. Otherwise, the caller must call [`e14_free_error_str`].
+ */
+int e14_new_etsi014_client_with_passphrase(const char *host,
+                                           uint16_t port,
+                                           const char *cert_path,
+                                           const char *key_path,
+                                           const char *server_ca_path,
+                                           const char *passphrase,
+                                           const struct E14_Client **etsi014_client,
+                                           const char **error_str);
+
+/**
+ * Create options for [`e14_new_etsi014_client_from_options`]. A client identity and server CA
+ * must be set before creating a client. If this function returns a 0, the caller must call
+ * [`e14_free_client_options`]. Otherwise, the caller must call [`e14_free_error_str`].
+ */
+int e14_new_client_options(const char *host,
+                           uint16_t port,
+                           struct E14_ClientOptions **options,
+                           const char **error_str);
+
+/**
+ * Set the client certificate and PKCS#8, encrypted PKCS#8, PKCS#1 RSA or SEC1 EC private
+ * key from memory. The private key is copied into protected memory, so the caller may
+ * overwrite `key` after this call. If this function returns a 1, the caller must call
+ * [`e14_free_error_str`].
+ */
+int e14_client_options_set_identity(struct E14_ClientOptions *options,
+                                    enum E14_CredentialFormat format,
+                                    const uint8_t *cert,
+                                    size_t cert_len,
+                                    const uint8_t *key,
+                                    size_t key_len,
+                                    const char **error_str);
+
+/**
+ * Set the client certificate, chain and private key from a PKCS#12 bundle in memory, instead
+ * of [`e14_client_options_set_identity`]. The bundle is copied into protected memory. If
+ * this function returns a 1, the caller must call [`e14_free_error_str`].
+ */
+int e14_client_options_set_pkcs12(struct E14_ClientOptions *options,
+                                  const uint8_t *pkcs12,
+                                  size_t pkcs12_len,
+                                  const char **error_str);
+
+/**
+ * Set the passphrase of an encrypted private key or PKCS#12 bundle. The passphrase is copied
+ * into protected memory, so the caller may overwrite it after this call. If this function
+ * returns a 1, the caller must call [`e14_free_error_str`].
+ */
+int e14_client_options_set_key_passphrase(struct E14_ClientOptions *options,
+                                          const uint8_t *passphrase,
+                                          size_t passphrase_len,
+                                          const char **error_str);
+
+/**
+ * Set the CA certificate used to verify the KME from memory. If this function returns a 1,
+ * the caller must call [`e14_free_error_str`].
+ */
+int e14_client_options_set_server_ca(struct E14_ClientOptions *options,
+                                     enum E14_CredentialFormat format,
+                                     const uint8_t *cert,
+                                     size_t cert_len,
+                                     const char **error_str);
+
+/**
+ * Trust another CA certificate or PEM bundle to verify the KME, in addition to the CA set
+ * with [`e14_client_options_set_server_ca`]. If this function returns a 1, the caller must
+ * call [`e14_free_error_str`].
+ */
+int e14_client_options_add_server_ca(struct E14_ClientOptions *options,
+                                     enum E14_CredentialFormat format,
+                                     const uint8_t *cert,
+                                     size_t cert_len,
+                                     const char **error_str);
+
+/**
+ * Trust the PEM files ending in .pem, .crt or .cer in `dir`, which is read when the client
+ * is created. If this function returns a 1, the caller must call [`e14_free_error_str`].
+ */
+int e14_client_options_add_server_ca_dir(struct E14_ClientOptions *options,
+                                         const char *dir,
+                                         const char **error_str);
+
+/**
+ * Only accept a KME whose certificate contains one of the pinned public keys. `pin` is
+ * "sha256//" followed by the base64 SHA-256 hash of the SubjectPublicKeyInfo, and is
+ * checked when the client is created. If this function returns a 1, the caller must call
+ * [`e14_free_error_str`].
+ */
+int e14_client_options_add_spki_pin(struct E14_ClientOptions *options,
+                                    const char *pin,
+                                    const char **error_str);
+
+/**
+ * Verify the certificate of the KME against `server_name` instead of the host, while still
+ * connecting to the host. It must be a DNS name, creating the client fails for IP addresses
+ * other than the host. NULL verifies against the host again. If this function returns a
+ * 1, the caller must call [`e14_free_error_str`].
+ */
+int e14_client_options_set_server_name(struct E14_ClientOptions *options,
+                                       const char *server_name,
+                                       const char **error_str);
+
+void e14_client_options_set_min_tls_version(struct E14_ClientOptions *options,
+                                            enum E14_TlsVersion min_tls_version);
+
+/**
+ * Timeouts in milliseconds, 0 disables a timeout. `request_timeout_ms` covers the complete
+ * request, from connecting until the response body is read.
+ */
+void e14_client_options_set_timeouts(struct E14_ClientOptions *options,
+                                     uint64_t connect_timeout_ms,
+                                     uint64_t request_timeout_ms);
+
+/**
+ * Retry requests that failed to connect up to `max_retries` times, waiting `retry_delay_ms`
+ * between attempts.
+ */
+void e14_client_options_set_retries(struct E14_ClientOptions *options,
+                                    uint32_t max_retries,
+                                    uint64_t retry_delay_ms);
+
+/**
+ * Path in front of `/{SAE_ID}/{endpoint}`, `api/v1/keys` by default. If this function
+ * returns a 1, the caller must call [`e14_free_error_str`].
+ */
+int e14_client_options_set_path_prefix(struct E14_ClientOptions *options,
+                                       const char *path_prefix,
+                                       const char **error_str);
+
+void e14_client_options_set_http_version(struct E14_ClientOptions *options,
+                                         enum E14_HttpVersion http_version);
+
+/**
+ * Include requests and response bodies, which may contain keys, in error strings. Only
+ * meant for debugging, disabled by default.
+ */
+void e14_client_options_set_verbose_diagnostics(struct E14_ClientOptions *options,
+                                                bool enabled);
+
+/**
+ * The options are not consumed and can be reused. If this function returns a 0, the caller
+ * must call [`e14_free_etsi014_client`]. Otherwise, the caller must call
+ * [`e14_free_error_str`].
+ */
+int e14_new_etsi014_client_from_options(const struct E14_ClientOptions *options,
+                                        const struct E14_Client **etsi014_client,
+                                        const char **error_str);
+
+/**
+ * Will overwrite the private key and deallocate memory.
+ */
+void e14_free_client_options(struct E14_ClientOptions **options);
+
+/**
+ * Read the client certificate, key and server CA files again and use them for new
+ * requests, e.g. after a certificate was renewed. Requests in progress are not interrupted.
+ * Credentials set from memory stay the same. If reading or parsing fails, the client keeps
+ * its credentials. If this function returns a 1, the caller must call
+ * [`e14_free_error_str`].
+ */
+int e14_reload_credentials(const struct E14_Client *client,
+                           const char **error_str);
+
 /**
  * If this function returns a 1, the caller must call [`e14_free_error_str`].
  */
@@ -102,6 +321,50 @@ void e14_protect_qkd_key_bytes(const struct E14_KeyBytesBorrow **borrow,
  */
 void e14_free_qkd_key_bytes(const struct E14_KeyBytesProtected **key_bytes_protected);
 
+/**
+ * Derive a subkey of `derived_len` bytes from a qkd key using HKDF-SHA256, without exposing
+ * either key. Keys passed to this function and the other key operations below must not be
+ * unprotected at the same time. If this function returns a 0, the caller must call
+ * [`e14_free_qkd_key_bytes`] on `derived`. Otherwise, the caller must call
+ * [`e14_free_error_str`].
+ */
+int e14_derive_qkd_key(const struct E14_KeyBytesProtected *key_bytes_protected,
+                       const uint8_t *salt,
+                       size_t salt_len,
+                       const uint8_t *info,
+                       size_t info_len,
+                       size_t derived_len,
+                       const struct E14_KeyBytesProtected **derived,
+                       const char **error_str);
+
+/**
+ * Combine two keys of equal length with XOR. If this function returns a 0, the caller must
+ * call [`e14_free_qkd_key_bytes`] on `combined`. Otherwise, the caller must call
+ * [`e14_free_error_str`].
+ */
+int e14_xor_qkd_keys(const struct E14_KeyBytesProtected *a,
+                     const struct E14_KeyBytesProtected *b,
+                     const struct E14_KeyBytesProtected **combined,
+                     const char **error_str);
+
+/**
+ * Compare two keys in constant time. Returns false if either key is a null pointer or the
+ * keys differ in length.
+ */
+bool e14_compare_qkd_keys(const struct E14_KeyBytesProtected *a,
+                          const struct E14_KeyBytesProtected *b);
+
+/**
+ * Copy a qkd key into `buffer`, which must be at least as large as the key. The caller is
+ * responsible for protecting `buffer`, e.g. by allocating it with `sodium_malloc` or locking
+ * it with `mlock`, and for overwriting it after use. If this function returns a 1, the
+ * caller must call [`e14_free_error_str`].
+ */
+int e14_copy_qkd_key_bytes(const struct E14_KeyBytesProtected *key_bytes_protected,
+                           uint8_t *buffer,
+                           size_t buffer_len,
+                           const char **error_str);
+
 void e14_free_error_str(const char **error_str);
 
 void e14_free_etsi014_client(const struct E14_Client **client);
@@ -0,0 +1,34 @@
+#!/bin/sh
+# Install the shared library, header, pkg-config and CMake package files after running
+# `cargo build --release --lib`.
+#
+# Usage: library/c/install.sh [PREFIX]
+# PREFIX defaults to /usr/local. DESTDIR, CARGO_TARGET_DIR and PROFILE (default release) are
+# respected.
+set -eu
+
+prefix="${1:-/usr/local}"
+root="$(cd "$(dirname "$0")/../.." && pwd)"
+target="${CARGO_TARGET_DIR:-$root/target}/${PROFILE:-release}"
+# The build script writes the package files to its OUT_DIR, take those of the last build
+pc="$(ls -t "$target"/build/etsi014-client-[0-9a-f]*/out/etsi014-client.pc 2>/dev/null \
+    | head -n 1)"
+if [ -z "$pc" ]; then
+    echo "No package files in $target, run cargo build --lib first" >&2
+    exit 1
+fi
+out="$(dirname "$pc")"
+version="$(sed -n 's/^Version: //p' "$pc")"
+soname="$(sed -n 's/^ *IMPORTED_SONAME "\(.*\)"$/\1/p' "$out/etsi014-client-config.cmake")"
+libdir="${DESTDIR:-}$prefix/lib"
+includedir="${DESTDIR:-}$prefix/include/etsi014-client"
+
+install -Dm755 "$target/libetsi014_client.so" "$libdir/libetsi014_client.so.$version"
+ln -sf "libetsi014_client.so.$version" "$libdir/$soname"
+ln -sf "$soname" "$libdir/libetsi014_client.so"
+install -Dm644 "$root/library/c/etsi014-client.h" "$includedir/etsi014-client.h"
+mkdir -p "$libdir/pkgconfig" "$libdir/cmake/etsi014-client"
+sed "s|^prefix=.*|prefix=$prefix|" "$pc" \
+    > "$libdir/pkgconfig/etsi014-client.pc"
+install -m644 "$out/etsi014-client-config.cmake" \
+    "$out/etsi014-client-config-version.cmake" "$libdir/cmake/etsi014-client/"
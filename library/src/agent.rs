@@ -0,0 +1,479 @@
+//! Protocol of `etsi014-agent`, which holds the client credentials and serves keys to local
+//! applications over a Unix domain socket, and a client for it.
+//!
+//! Every request is a JSON object on one line. The response starts with a JSON object on
+//! one line. For keys, it lists the key IDs and lengths, and is followed by the key bytes,
+//! which are never encoded as JSON, so they can be read directly into protected memory.
+
+use crate::error::ErrorType::AgentError;
+use crate::json::status_response::StatusResponse;
+use crate::{ETSI014Client, Error, KeyId, SaeId, Status};
+use secrets::SecretVec;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+/// Longest request line accepted.
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+/// Longest response line accepted, without key bytes.
+const MAX_RESPONSE_LEN: u64 = 1024 * 1024;
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum AgentRequest {
+    Status {
+        #[serde(rename = "target_SAE_ID")]
+        target_sae_id: SaeId,
+    },
+    GetKeys {
+        #[serde(rename = "target_SAE_ID")]
+        target_sae_id: SaeId,
+        key_size_bits: u32,
+        #[serde(rename = "additional_target_SAE_IDs", default)]
+        additional_target_sae_ids: Vec<SaeId>,
+        amount: u32,
+    },
+    GetKeysByIds {
+        #[serde(rename = "target_SAE_ID")]
+        target_sae_id: SaeId,
+        #[serde(rename = "key_IDs")]
+        key_ids: Vec<KeyId>,
+    },
+}
+
+impl AgentRequest {
+    pub fn operation(&self) -> &'static str {
+        match self {
+            AgentRequest::Status { .. } => "status",
+            AgentRequest::GetKeys { .. } => "get_keys",
+            AgentRequest::GetKeysByIds { .. } => "get_keys_by_ids",
+        }
+    }
+
+    pub fn target_sae_id(&self) -> &SaeId {
+        match self {
+            AgentRequest::Status { target_sae_id }
+            | AgentRequest::GetKeys { target_sae_id, .. }
+            | AgentRequest::GetKeysByIds { target_sae_id, .. } => target_sae_id,
+        }
+    }
+
+    /// Execute the request with `client`. Priority requests may use the reserve of the
+    /// [`KeyQuota`](crate::KeyQuota) of the client.
+    pub async fn execute(
+        &self,
+        client: &ETSI014Client,
+        priority: bool,
+    ) -> Result<AgentReply, Error> {
+        match self {
+            AgentRequest::Status { target_sae_id } => client
+                .get_status(target_sae_id)
+                .await
+                .map(AgentReply::Status),
+            AgentRequest::GetKeys {
+                target_sae_id,
+                key_size_bits,
+                additional_target_sae_ids,
+                amount,
+            } => {
+                let keys = if priority {
+                    client
+                        .get_priority_keys(
+                            *key_size_bits,
+                            target_sae_id,
+                            additional_target_sae_ids,
+                            *amount,
+                        )
+                        .await
+                } else {
+                    client
+                        .get_keys(
+                            *key_size_bits,
+                            target_sae_id,
+                            additional_target_sae_ids,
+                            *amount,
+                        )
+                        .await
+                };
+                keys.map(AgentReply::Keys)
+            }
+            AgentRequest::GetKeysByIds {
+                target_sae_id,
+                key_ids,
+            } => client
+                .get_keys_by_ids(target_sae_id, key_ids)
+                .await
+                .map(AgentReply::Keys),
+        }
+    }
+}
+
+/// Successful result of an [`AgentRequest`].
+#[derive(Debug)]
+pub enum AgentReply {
+    Status(Status),
+    Keys(Vec<(KeyId, SecretVec<u8>)>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyHeader {
+    #[serde(rename = "key_ID")]
+    key_id: KeyId,
+    length: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+enum ResponseHeader {
+    Status(StatusResponse),
+    Keys { keys: Vec<KeyHeader> },
+    Error { kind: String, message: String },
+}
+
+/// Error sent for a request the agent does not allow.
+pub fn refused(msg: String) -> Error {
+    Error::new(msg, AgentError, None)
+}
+
+fn agent_error(msg: String, e: impl std::error::Error + 'static) -> Error {
+    Error::new(msg, AgentError, Some(Box::new(e)))
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_len: u64,
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(max_len)
+        .read_until(b'\n', &mut line)
+        .await
+        .map_err(|e| agent_error("Error reading from agent socket".to_string(), e))?;
+    match line.last() {
+        None => Ok(None),
+        Some(b'\n') => Ok(Some(line)),
+        Some(_) => Err(Error::new(
+            "Incomplete or too long message on agent socket".to_string(),
+            AgentError,
+            None,
+        )),
+    }
+}
+
+/// Read the next request, or `None` when the peer closed the connection.
+pub async fn read_request<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<AgentRequest>, Error> {
+    let Some(line) = read_line(reader, MAX_REQUEST_LEN).await? else {
+        return Ok(None);
+    };
+    serde_json::from_slice(&line)
+        .map(Some)
+        .map_err(|e| agent_error("Invalid agent request".to_string(), e))
+}
+
+/// Write the response to a request. Errors are sent with their type and message.
+pub async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    result: &Result<AgentReply, Error>,
+) -> Result<(), Error> {
+    let header = match result {
+        Ok(AgentReply::Status(status)) => ResponseHeader::Status(status.clone().into()),
+        Ok(AgentReply::Keys(keys)) => ResponseHeader::Keys {
+            keys: keys
+                .iter()
+                .map(|(key_id, key)| KeyHeader {
+                    key_id: key_id.clone(),
+                    length: key.len(),
+                })
+                .collect(),
+        },
+        Err(e) => ResponseHeader::Error {
+            kind: format!("{:?}", e.kind),
+            message: e.msg.clone(),
+        },
+    };
+    let mut line = serde_json::to_vec(&header).expect("Error serializing agent response");
+    line.push(b'\n');
+    let write_error = |e| agent_error("Error writing to agent socket".to_string(), e);
+    writer.write_all(&line).await.map_err(write_error)?;
+    if let Ok(AgentReply::Keys(keys)) = result {
+        for (_, key) in keys {
+            writer.write_all(&key.borrow()).await.map_err(write_error)?;
+        }
+    }
+    writer.flush().await.map_err(write_error)
+}
+
+/// Client of `etsi014-agent`, with the same operations as [`ETSI014Client`]. The agent
+/// decides which operations and target SAEs are allowed for the user of the process.
+#[derive(Debug)]
+pub struct AgentClient {
+    stream: UnixStream,
+}
+
+impl AgentClient {
+    pub async fn connect(socket: &Path) -> Result<AgentClient, Error> {
+        let stream = UnixStream::connect(socket).await.map_err(|e| {
+            agent_error(
+                format!("Error connecting to agent at {}", socket.display()),
+                e,
+            )
+        })?;
+        Ok(AgentClient { stream })
+    }
+
+    async fn request(&mut self, request: &AgentRequest) -> Result<ResponseHeader, Error> {
+        let mut line =
+            serde_json::to_vec(request).expect("Error serializing agent request");
+        line.push(b'\n');
+        let io_error = |e| agent_error("Error communicating with agent".to_string(), e);
+        self.stream.write_all(&line).await.map_err(io_error)?;
+        // Read byte by byte, as buffering could copy key bytes following the line into
+        // unprotected memory
+        let mut line = Vec::new();
+        while line.last() != Some(&b'\n') {
+            if line.len() as u64 >= MAX_RESPONSE_LEN {
+                return Err(Error::new(
+                    "Agent response too long".to_string(),
+                    AgentError,
+                    None,
+                ));
+            }
+            line.push(self.stream.read_u8().await.map_err(io_error)?);
+        }
+        match serde_json::from_slice(&line)
+            .map_err(|e| agent_error("Invalid agent response".to_string(), e))?
+        {
+            ResponseHeader::Error { kind, message } => Err(Error::new(
+                format!("Agent refused or failed the request ({kind}): {message}"),
+                AgentError,
+                None,
+            )),
+            header => Ok(header),
+        }
+    }
+
+    async fn read_keys(
+        &mut self,
+        header: ResponseHeader,
+    ) -> Result<Vec<(KeyId, SecretVec<u8>)>, Error> {
+        let ResponseHeader::Keys { keys } = header else {
+            return Err(Error::new(
+                "Unexpected agent response, expected keys".to_string(),
+                AgentError,
+                None,
+            ));
+        };
+        let mut result = Vec::with_capacity(keys.len());
+        for KeyHeader { key_id, length } in keys {
+            let mut key = SecretVec::zero(length);
+            self.stream
+                .read_exact(&mut key.borrow_mut())
+                .await
+                .map_err(|e| {
+                    agent_error("Error reading keys from agent".to_string(), e)
+                })?;
+            result.push((key_id, key));
+        }
+        Ok(result)
+    }
+
+    pub async fn get_status(&mut self, target_sae_id: &SaeId) -> Result<Status, Error> {
+        let request = AgentRequest::Status {
+            target_sae_id: target_sae_id.clone(),
+        };
+        match self.request(&request).await? {
+            ResponseHeader::Status(status) => Ok(status.into()),
+            _ => Err(Error::new(
+                "Unexpected agent response, expected status".to_string(),
+                AgentError,
+                None,
+            )),
+        }
+    }
+
+    pub async fn get_keys(
+        &mut self,
+        key_size_bits: u32,
+        target_sae_id: &SaeId,
+        additional_target_sae_ids: &[SaeId],
+        amount_of_keys: u32,
+    ) -> Result<Vec<(KeyId, SecretVec<u8>)>, Error> {
+        let request = AgentRequest::GetKeys {
+            target_sae_id: target_sae_id.clone(),
+            key_size_bits,
+            additional_target_sae_ids: additional_target_sae_ids.to_vec(),
+            amount: amount_of_keys,
+        };
+        let header = self.request(&request).await?;
+        self.read_keys(header).await
+    }
+
+    pub async fn get_keys_by_ids(
+        &mut self,
+        target_sae_id: &SaeId,
+        key_ids: &[KeyId],
+    ) -> Result<Vec<(KeyId, SecretVec<u8>)>, Error> {
+        let request = AgentRequest::GetKeysByIds {
+            target_sae_id: target_sae_id.clone(),
+            key_ids: key_ids.to_vec(),
+        };
+        let header = self.request(&request).await?;
+        self.read_keys(header).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KmeId;
+    use tokio::io::BufReader;
+
+    fn sae_id(id: &str) -> SaeId {
+        SaeId::new(id).unwrap()
+    }
+
+    fn status() -> Status {
+        Status {
+            source_kme_id: KmeId::new("kme-1").unwrap(),
+            target_kme_id: KmeId::new("kme-2").unwrap(),
+            source_sae_id: sae_id("client-1"),
+            target_sae_id: sae_id("client-2"),
+            key_size: 256,
+            stored_key_count: 25000,
+            max_key_count: 100000,
+            max_key_per_request: 128,
+            max_key_size: 1024,
+            min_key_size: 64,
+            max_sae_id_count: 0,
+        }
+    }
+
+    fn keys() -> Vec<(KeyId, SecretVec<u8>)> {
+        // Keys end with a newline and a '{' to catch readers that look for line ends in
+        // key bytes
+        [
+            ("key-1", b"0123456789abcdef\n{".as_slice()),
+            ("key-2", &[0xff; 64]),
+        ]
+        .into_iter()
+        .map(|(id, key)| {
+            let key = SecretVec::new(key.len(), |s| s.copy_from_slice(key));
+            (KeyId::new(id).unwrap(), key)
+        })
+        .collect()
+    }
+
+    /// Answer requests like the agent until the client disconnects, returning them.
+    async fn serve(stream: UnixStream) -> Vec<AgentRequest> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut requests = Vec::new();
+        while let Some(request) = read_request(&mut reader).await.unwrap() {
+            let result = match &request {
+                AgentRequest::Status { .. } => Ok(AgentReply::Status(status())),
+                AgentRequest::GetKeys { .. } => Ok(AgentReply::Keys(keys())),
+                AgentRequest::GetKeysByIds { .. } => {
+                    Err(refused("User 1000 is not allowed".to_string()))
+                }
+            };
+            write_response(&mut writer, &result).await.unwrap();
+            requests.push(request);
+        }
+        requests
+    }
+
+    #[test]
+    fn requests_and_responses_round_trip() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        // Errors are not Send, so the agent runs on the same thread
+        let local = tokio::task::LocalSet::new();
+        local.block_on(&runtime, async {
+            let (client_stream, agent_stream) = UnixStream::pair().unwrap();
+            let agent = tokio::task::spawn_local(serve(agent_stream));
+            let mut client = AgentClient {
+                stream: client_stream,
+            };
+
+            assert_eq!(
+                client.get_status(&sae_id("client-2")).await.unwrap(),
+                status()
+            );
+            let received = client
+                .get_keys(256, &sae_id("client-2"), &[sae_id("client-3")], 2)
+                .await
+                .unwrap();
+            let expected = keys();
+            assert_eq!(received.len(), expected.len());
+            for ((id, key), (expected_id, expected_key)) in received.iter().zip(&expected)
+            {
+                assert_eq!(id, expected_id);
+                assert_eq!(*key.borrow(), *expected_key.borrow());
+            }
+            let key_ids = [KeyId::new("key-1").unwrap()];
+            let e = client
+                .get_keys_by_ids(&sae_id("client-2"), &key_ids)
+                .await
+                .unwrap_err();
+            assert!(matches!(e.kind, AgentError));
+            assert!(
+                e.msg.contains("(AgentError): User 1000 is not allowed"),
+                "{}",
+                e.msg
+            );
+            // The connection is still usable after keys and errors
+            assert_eq!(
+                client.get_status(&sae_id("client-2")).await.unwrap(),
+                status()
+            );
+            drop(client);
+
+            let requests = agent.await.unwrap();
+            assert_eq!(
+                requests,
+                [
+                    AgentRequest::Status {
+                        target_sae_id: sae_id("client-2")
+                    },
+                    AgentRequest::GetKeys {
+                        target_sae_id: sae_id("client-2"),
+                        key_size_bits: 256,
+                        additional_target_sae_ids: vec![sae_id("client-3")],
+                        amount: 2,
+                    },
+                    AgentRequest::GetKeysByIds {
+                        target_sae_id: sae_id("client-2"),
+                        key_ids: key_ids.to_vec(),
+                    },
+                    AgentRequest::Status {
+                        target_sae_id: sae_id("client-2")
+                    },
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn truncated_and_oversized_requests_are_rejected() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut truncated = &b"{\"operation\":\"status\""[..];
+            let e = read_request(&mut truncated).await.unwrap_err();
+            assert!(e.msg.contains("Incomplete or too long"), "{}", e.msg);
+            let oversized = vec![b' '; MAX_REQUEST_LEN as usize + 1];
+            let e = read_request(&mut oversized.as_slice()).await.unwrap_err();
+            assert!(e.msg.contains("Incomplete or too long"), "{}", e.msg);
+            let mut invalid = &b"{\"operation\":\"delete_keys\"}\n"[..];
+            let e = read_request(&mut invalid).await.unwrap_err();
+            assert_eq!(e.msg, "Invalid agent request");
+            assert!(read_request(&mut &b""[..]).await.unwrap().is_none());
+        });
+    }
+}
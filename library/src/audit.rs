@@ -0,0 +1,616 @@
+use crate::Error;
+use crate::error::ErrorType::AuditError;
+use crate::utils::{FileLock, read_or_create_secret, read_secret};
+use hmac::{Hmac, Mac};
+use secrets::SecretVec;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `prev_hash` of the first record of a chain.
+pub const GENESIS_HASH: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+/// Length of the keys created by [`AuditLog::with_key_file`].
+const KEY_LEN: usize = 32;
+
+/// Outcome of an audited operation.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Error,
+}
+
+/// Record of one operation. Never contains key material.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Position in the chain, starting at 0.
+    pub seq: u64,
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    /// Common name of the client certificate.
+    #[serde(rename = "local_SAE_ID", skip_serializing_if = "Option::is_none")]
+    pub local_sae_id: Option<String>,
+    /// `status`, `enc_keys` or `dec_keys`.
+    pub operation: String,
+    #[serde(rename = "target_SAE_ID")]
+    pub target_sae_id: String,
+    /// Key IDs requested with `dec_keys`.
+    #[serde(
+        rename = "requested_key_IDs",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub requested_key_ids: Vec<String>,
+    /// Key IDs received.
+    #[serde(rename = "key_IDs", default, skip_serializing_if = "Vec::is_empty")]
+    pub key_ids: Vec<String>,
+    /// Sizes of the received keys in bits, in the order of `key_ids`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub key_sizes: Vec<u32>,
+    pub outcome: AuditOutcome,
+    /// Error type if the operation failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Hash of the previous record, [`GENESIS_HASH`] for the first record.
+    pub prev_hash: String,
+    /// SHA-256 of the record serialized without this field, or HMAC-SHA256 if the log is
+    /// authenticated with a key.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+impl AuditRecord {
+    fn unhashed_json(&self) -> Vec<u8> {
+        let unhashed = AuditRecord {
+            hash: String::new(),
+            ..self.clone()
+        };
+        serde_json::to_vec(&unhashed).expect("Error serializing audit record")
+    }
+
+    /// Hex encoded SHA-256 of the record without `hash`.
+    pub fn compute_hash(&self) -> String {
+        hex(&Sha256::digest(self.unhashed_json()))
+    }
+
+    /// Hex encoded HMAC-SHA256 of the record without `hash`, for logs authenticated with
+    /// `key`.
+    pub fn compute_mac(&self, key: &[u8]) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(&self.unhashed_json());
+        hex(&mac.finalize().into_bytes())
+    }
+
+    fn compute(&self, key: Option<&[u8]>) -> String {
+        match key {
+            Some(key) => self.compute_mac(key),
+            None => self.compute_hash(),
+        }
+    }
+}
+
+/// Operation passed to [`AuditLog::record`].
+pub(crate) struct AuditEvent<'a> {
+    pub(crate) local_sae_id: Option<&'a str>,
+    pub(crate) operation: &'a str,
+    pub(crate) target_sae_id: &'a str,
+    pub(crate) requested_key_ids: &'a [&'a str],
+    /// Key IDs and sizes in bytes.
+    pub(crate) keys: Vec<(&'a str, usize)>,
+    pub(crate) error: Option<&'a Error>,
+}
+
+enum Sink {
+    File {
+        path: PathBuf,
+        file: File,
+        /// Bytes of the file that have been read to find the end of the chain.
+        offset: u64,
+    },
+    #[cfg(unix)]
+    Syslog,
+}
+
+struct ChainState {
+    sink: Sink,
+    next_seq: u64,
+    prev_hash: String,
+    key: Option<SecretVec<u8>>,
+}
+
+/// Tamper-evident log of client operations. Every record contains the hash of the previous
+/// record, so records cannot be changed, removed or reordered without breaking the chain,
+/// see [`verify_audit_log`]. Records are written as JSON lines to a file shared by all
+/// processes, or to syslog, where every process starts its own chain.
+///
+/// Anyone who can write the log can also replace it with a new chain. Authenticate the
+/// records with a key kept outside the log with [`with_key`](Self::with_key), or store the
+/// last hash elsewhere, see [`AuditVerification::last_hash`].
+pub struct AuditLog {
+    state: Mutex<ChainState>,
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sink = match &self.state.lock().unwrap_or_else(|e| e.into_inner()).sink {
+            Sink::File { path, .. } => path.display().to_string(),
+            #[cfg(unix)]
+            Sink::Syslog => "syslog".to_owned(),
+        };
+        f.debug_struct("AuditLog").field("sink", &sink).finish()
+    }
+}
+
+fn audit_error(msg: String, e: std::io::Error) -> Error {
+    Error::new(msg, AuditError, Some(Box::new(e)))
+}
+
+fn parse_record(path: &Path, line: &[u8], location: &str) -> Result<AuditRecord, Error> {
+    serde_json::from_slice(line).map_err(|e| {
+        Error::new(
+            format!("Invalid {location} in audit log {}", path.display()),
+            AuditError,
+            Some(Box::new(e)),
+        )
+    })
+}
+
+impl ChainState {
+    /// Continue the chain after records appended by other processes.
+    fn refresh(&mut self) -> Result<(), Error> {
+        let Sink::File { path, file, offset } = &mut self.sink else {
+            return Ok(());
+        };
+        let read_error = |e| audit_error(format!("Error reading {}", path.display()), e);
+        file.seek(SeekFrom::Start(*offset)).map_err(read_error)?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).map_err(read_error)?;
+        let complete = content
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .map_or(0, |i| i + 1);
+        if let Some(line) = content[..complete]
+            .split(|b| *b == b'\n')
+            .rfind(|line| !line.is_empty())
+        {
+            let record = parse_record(path, line, "last record")?;
+            self.next_seq = record.seq + 1;
+            self.prev_hash = record.hash;
+        }
+        *offset += complete as u64;
+        Ok(())
+    }
+
+    fn write(&mut self, record: &AuditRecord) -> Result<(), Error> {
+        let mut line =
+            serde_json::to_vec(record).expect("Error serializing audit record");
+        match &mut self.sink {
+            Sink::File { path, file, offset } => {
+                line.push(b'\n');
+                let write_error =
+                    |e| audit_error(format!("Error writing {}", path.display()), e);
+                file.write_all(&line).map_err(write_error)?;
+                file.sync_data().map_err(write_error)?;
+                *offset += line.len() as u64;
+            }
+            #[cfg(unix)]
+            Sink::Syslog => {
+                let message = std::ffi::CString::new(line)
+                    .expect("Serialized JSON contains no NUL bytes");
+                unsafe {
+                    libc::syslog(
+                        libc::LOG_AUTHPRIV | libc::LOG_INFO,
+                        c"%s".as_ptr(),
+                        message.as_ptr(),
+                    )
+                };
+            }
+        }
+        self.next_seq = record.seq + 1;
+        self.prev_hash = record.hash.clone();
+        Ok(())
+    }
+}
+
+impl AuditLog {
+    fn new(sink: Sink) -> AuditLog {
+        AuditLog {
+            state: Mutex::new(ChainState {
+                sink,
+                next_seq: 0,
+                prev_hash: GENESIS_HASH.to_owned(),
+                key: None,
+            }),
+        }
+    }
+
+    /// Authenticate records with HMAC-SHA256 under `key` instead of hashing them, so that
+    /// records can only be written by holders of the key. Verify them with
+    /// [`AuditVerification::key`].
+    pub fn with_key(mut self, key: SecretVec<u8>) -> AuditLog {
+        self.state.get_mut().unwrap_or_else(|e| e.into_inner()).key = Some(key);
+        self
+    }
+
+    /// Like [`with_key`](Self::with_key), with the key read from the file at `path`, which
+    /// is created with 32 random bytes if it does not exist.
+    pub fn with_key_file(self, path: &Path) -> Result<AuditLog, Error> {
+        let key = read_or_create_secret(path, KEY_LEN, audit_error)?;
+        Ok(self.with_key(key))
+    }
+
+    /// Append records to the file at `path`, creating it if it does not exist.
+    pub fn file(path: &Path) -> Result<AuditLog, Error> {
+        let mut options = OpenOptions::new();
+        options.read(true).append(true).create(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options
+            .open(path)
+            .map_err(|e| audit_error(format!("Error opening {}", path.display()), e))?;
+        Ok(AuditLog::new(Sink::File {
+            path: path.to_owned(),
+            file,
+            offset: 0,
+        }))
+    }
+
+    /// Send records to syslog with the `authpriv` facility.
+    #[cfg(unix)]
+    pub fn syslog() -> AuditLog {
+        unsafe {
+            libc::openlog(
+                c"etsi014-client".as_ptr(),
+                libc::LOG_PID,
+                libc::LOG_AUTHPRIV,
+            )
+        };
+        AuditLog::new(Sink::Syslog)
+    }
+
+    pub(crate) fn record(&self, event: AuditEvent) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let lock = match &state.sink {
+            Sink::File { path, file, .. } => Some(
+                file.try_clone()
+                    .and_then(FileLock::exclusive)
+                    .map_err(|e| {
+                        audit_error(format!("Error locking {}", path.display()), e)
+                    })?,
+            ),
+            #[cfg(unix)]
+            Sink::Syslog => None,
+        };
+        state.refresh()?;
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut record = AuditRecord {
+            seq: state.next_seq,
+            time,
+            local_sae_id: event.local_sae_id.map(str::to_owned),
+            operation: event.operation.to_owned(),
+            target_sae_id: event.target_sae_id.to_owned(),
+            requested_key_ids: event
+                .requested_key_ids
+                .iter()
+                .map(|id| id.to_string())
+                .collect(),
+            key_ids: event.keys.iter().map(|(id, _)| id.to_string()).collect(),
+            key_sizes: event.keys.iter().map(|(_, len)| *len as u32 * 8).collect(),
+            outcome: match event.error {
+                None => AuditOutcome::Success,
+                Some(_) => AuditOutcome::Error,
+            },
+            error: event.error.map(|e| format!("{:?}", e.kind)),
+            prev_hash: state.prev_hash.clone(),
+            hash: String::new(),
+        };
+        let key = state.key.as_ref().map(SecretVec::borrow);
+        record.hash = record.compute(key.as_deref());
+        drop(key);
+        state.write(&record)?;
+        drop(lock);
+        Ok(())
+    }
+}
+
+/// Result of [`verify_audit_log`].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AuditSummary {
+    pub records: u64,
+    /// Chains in the log. Files written by [`AuditLog::file`] contain one chain, records
+    /// collected from syslog one per process.
+    pub chains: u64,
+    /// Hash of the last record, which can be stored elsewhere to detect truncation later.
+    pub last_hash: Option<String>,
+}
+
+/// How an audit log is verified. By default, a log must contain a single chain of hashed
+/// records, like a file written by [`AuditLog::file`].
+#[derive(Debug, Default)]
+pub struct AuditVerification {
+    key: Option<SecretVec<u8>>,
+    last_hash: Option<String>,
+    multiple_chains: bool,
+}
+
+impl AuditVerification {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Key the records are authenticated with, see [`AuditLog::with_key`].
+    pub fn key(&mut self, key: SecretVec<u8>) -> &mut Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Like [`key`](Self::key), with the key read from the file at `path`.
+    pub fn key_file(&mut self, path: &Path) -> Result<&mut Self, Error> {
+        self.key = Some(read_secret(path, KEY_LEN, audit_error)?);
+        Ok(self)
+    }
+
+    /// Require a record with this hash, e.g. the [`AuditSummary::last_hash`] of an earlier
+    /// verification that was stored outside the log. Detects that records after it were
+    /// removed, and without a key, that the log was rewritten up to it.
+    pub fn last_hash(&mut self, hash: &str) -> &mut Self {
+        self.last_hash = Some(hash.to_ascii_lowercase());
+        self
+    }
+
+    /// Accept logs with several chains, like records collected from syslog, where every
+    /// process starts its own chain. Otherwise, a new chain in the middle of the log is
+    /// refused, as it could replace everything before it.
+    pub fn multiple_chains(&mut self, allowed: bool) -> &mut Self {
+        self.multiple_chains = allowed;
+        self
+    }
+
+    /// Verify the chain of the audit log at `path`, failing at the first record that was
+    /// changed, removed or inserted. A record with sequence number 0 and [`GENESIS_HASH`]
+    /// starts a chain.
+    pub fn verify(&self, path: &Path) -> Result<AuditSummary, Error> {
+        let file = File::open(path)
+            .map_err(|e| audit_error(format!("Error opening {}", path.display()), e))?;
+        let key = self.key.as_ref().map(SecretVec::borrow);
+        let mut summary = AuditSummary {
+            records: 0,
+            chains: 0,
+            last_hash: None,
+        };
+        let mut expected_seq = 0;
+        let mut found_last_hash = false;
+        for (index, line) in BufReader::new(file).split(b'\n').enumerate() {
+            let line_number = index + 1;
+            let line = line.map_err(|e| {
+                audit_error(format!("Error reading {}", path.display()), e)
+            })?;
+            if line.is_empty() {
+                continue;
+            }
+            let record =
+                parse_record(path, &line, &format!("record at line {line_number}"))?;
+            let broken = |reason: &str| {
+                Err(Error::new(
+                    format!(
+                        "Audit log {} broken at line {line_number}: {reason}",
+                        path.display()
+                    ),
+                    AuditError,
+                    None,
+                ))
+            };
+            if record.hash != record.compute(key.as_deref()) {
+                return broken(match key {
+                    Some(_) => "record was modified, or authenticated with another key",
+                    None => "record was modified, or the log is authenticated with a key",
+                });
+            }
+            if record.seq == 0 && record.prev_hash == GENESIS_HASH {
+                if summary.records > 0 && !self.multiple_chains {
+                    return broken("new chain in the middle of the log");
+                }
+                summary.chains += 1;
+            } else if summary.last_hash.as_ref() != Some(&record.prev_hash) {
+                return broken("previous record is missing or was modified");
+            } else if record.seq != expected_seq {
+                return broken("unexpected sequence number");
+            }
+            expected_seq = record.seq + 1;
+            summary.records += 1;
+            found_last_hash |= self.last_hash.as_ref() == Some(&record.hash);
+            summary.last_hash = Some(record.hash);
+        }
+        if let (Some(last_hash), false) = (&self.last_hash, found_last_hash) {
+            return Err(Error::new(
+                format!(
+                    "Audit log {} has no record with hash {last_hash}, records were \
+                     removed or the log was replaced",
+                    path.display()
+                ),
+                AuditError,
+                None,
+            ));
+        }
+        Ok(summary)
+    }
+}
+
+/// Verify the hash chain of the audit log at `path` with the default
+/// [`AuditVerification`].
+pub fn verify_audit_log(path: &Path) -> Result<AuditSummary, Error> {
+    AuditVerification::new().verify(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn temp_path(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir()
+            .join(format!("etsi014-audit-{}-{n}-{name}", std::process::id()))
+    }
+
+    fn key(byte: u8) -> SecretVec<u8> {
+        SecretVec::new(KEY_LEN, |key| key.fill(byte))
+    }
+
+    fn record(log: &AuditLog, operation: &str) {
+        let event = AuditEvent {
+            local_sae_id: Some("client-1"),
+            operation,
+            target_sae_id: "client-2",
+            requested_key_ids: &[],
+            keys: vec![("4b9b7a0c-3e2f-4c5d-9a1b-2c3d4e5f6a7b", 32)],
+            error: None,
+        };
+        log.record(event).unwrap();
+    }
+
+    fn write_log(path: &Path, key: Option<SecretVec<u8>>, records: usize) {
+        let mut log = AuditLog::file(path).unwrap();
+        if let Some(key) = key {
+            log = log.with_key(key);
+        }
+        for _ in 0..records {
+            record(&log, "enc_keys");
+        }
+    }
+
+    fn verify_error(verification: &AuditVerification, path: &Path) -> String {
+        verification.verify(path).unwrap_err().msg
+    }
+
+    #[test]
+    fn keyed_chain_verifies_only_with_the_key() {
+        let path = temp_path("keyed.log");
+        write_log(&path, Some(key(1)), 3);
+
+        let summary = AuditVerification::new().key(key(1)).verify(&path).unwrap();
+        assert_eq!(summary.records, 3);
+        assert_eq!(summary.chains, 1);
+        let msg = verify_error(&AuditVerification::new(), &path);
+        assert!(msg.contains("broken at line 1"), "{msg}");
+        let msg = verify_error(AuditVerification::new().key(key(2)), &path);
+        assert!(msg.contains("another key"), "{msg}");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rewritten_log_is_refused_with_the_key() {
+        let path = temp_path("rewritten.log");
+        write_log(&path, Some(key(1)), 2);
+        let content = fs::read_to_string(&path).unwrap();
+        let mut forged: AuditRecord =
+            serde_json::from_str(content.lines().nth(1).unwrap()).unwrap();
+        forged.target_sae_id = "client-3".to_owned();
+        // Without the key, the forger can only recompute the plain hash
+        forged.hash = forged.compute_hash();
+        let forged = serde_json::to_string(&forged).unwrap();
+        fs::write(
+            &path,
+            format!("{}\n{forged}\n", content.lines().next().unwrap()),
+        )
+        .unwrap();
+
+        let msg = verify_error(AuditVerification::new().key(key(1)), &path);
+        assert!(msg.contains("broken at line 2"), "{msg}");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn new_chain_in_the_middle_is_refused_in_files() {
+        let path = temp_path("restarted.log");
+        write_log(&path, None, 2);
+        let replaced = temp_path("replacement.log");
+        write_log(&replaced, None, 1);
+        let mut content = fs::read(&path).unwrap();
+        content.extend(fs::read(&replaced).unwrap());
+        fs::write(&path, content).unwrap();
+
+        let msg = verify_error(&AuditVerification::new(), &path);
+        assert!(msg.contains("new chain in the middle of the log"), "{msg}");
+        let summary = AuditVerification::new()
+            .multiple_chains(true)
+            .verify(&path)
+            .unwrap();
+        assert_eq!(summary.chains, 2);
+        fs::remove_file(path).unwrap();
+        fs::remove_file(replaced).unwrap();
+    }
+
+    #[test]
+    fn last_hash_detects_truncation_and_replacement() {
+        let path = temp_path("truncated.log");
+        write_log(&path, None, 3);
+        let last_hash = verify_audit_log(&path).unwrap().last_hash.unwrap();
+        AuditVerification::new()
+            .last_hash(&last_hash.to_ascii_uppercase())
+            .verify(&path)
+            .unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let truncated: Vec<&str> = content.lines().take(2).collect();
+        fs::write(&path, truncated.join("\n") + "\n").unwrap();
+        verify_audit_log(&path).unwrap();
+        let msg = verify_error(AuditVerification::new().last_hash(&last_hash), &path);
+        assert!(msg.contains("has no record with hash"), "{msg}");
+
+        // A replacement written in the same second must differ from the original
+        fs::remove_file(&path).unwrap();
+        let log = AuditLog::file(&path).unwrap();
+        for _ in 0..3 {
+            record(&log, "status");
+        }
+        let msg = verify_error(AuditVerification::new().last_hash(&last_hash), &path);
+        assert!(msg.contains("has no record with hash"), "{msg}");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn key_file_is_created_and_reused() {
+        let log_path = temp_path("key-file.log");
+        let key_path = temp_path("audit.key");
+        let log = AuditLog::file(&log_path)
+            .unwrap()
+            .with_key_file(&key_path)
+            .unwrap();
+        record(&log, "status");
+        drop(log);
+        assert_eq!(fs::read(&key_path).unwrap().len(), KEY_LEN);
+        let log = AuditLog::file(&log_path)
+            .unwrap()
+            .with_key_file(&key_path)
+            .unwrap();
+        record(&log, "dec_keys");
+
+        let summary = AuditVerification::new()
+            .key_file(&key_path)
+            .unwrap()
+            .verify(&log_path)
+            .unwrap();
+        assert_eq!(summary.records, 2);
+        fs::remove_file(log_path).unwrap();
+        fs::remove_file(key_path).unwrap();
+    }
+}
@@ -0,0 +1,732 @@
+use crate::error::ErrorType::{InvalidArgument, InvalidHost};
+use crate::etsi014_client::ClientState;
+use crate::pkcs12;
+use crate::private_key::pkcs8_der;
+use crate::quota::QuotaTracker;
+use crate::tls::{ServerNameResolver, SpkiPinLayer, parse_spki_pin};
+use crate::utils::{der_to_pem, read_file};
+use crate::{
+    AuditLog, ClientIdentity, ETSI014Client, Error, KeyCache, KeyLedger, KeyQuota, SaeId,
+};
+use reqwest::{Certificate, Client, Identity, Url};
+use secrets::SecretVec;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use std::{fmt, fs, io};
+use zeroize::Zeroize;
+
+/// Encoding of certificates and private keys passed to [`ETSI014ClientBuilder`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CredentialFormat {
+    Pem,
+    /// DER encoded certificate and private key.
+    Der,
+}
+
+/// HTTP protocol version used to talk to the KME.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum HttpVersion {
+    /// Negotiate HTTP/1.1 or HTTP/2 using ALPN.
+    #[default]
+    Auto,
+    Http1Only,
+    Http2PriorKnowledge,
+}
+
+/// TLS protocol version.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl From<TlsVersion> for reqwest::tls::Version {
+    fn from(version: TlsVersion) -> Self {
+        match version {
+            TlsVersion::Tls12 => reqwest::tls::Version::TLS_1_2,
+            TlsVersion::Tls13 => reqwest::tls::Version::TLS_1_3,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum IdentityData {
+    CertKey {
+        format: CredentialFormat,
+        cert: Vec<u8>,
+        key: SecretVec<u8>,
+    },
+    Pkcs12(SecretVec<u8>),
+    /// Read on every build and reload.
+    CertKeyFiles {
+        format: CredentialFormat,
+        cert: PathBuf,
+        key: PathBuf,
+    },
+    Pkcs12File(PathBuf),
+}
+
+#[derive(Clone)]
+enum PassphraseSource {
+    Passphrase(SecretVec<u8>),
+    Callback(Arc<dyn Fn() -> io::Result<SecretVec<u8>> + Send + Sync>),
+}
+
+#[derive(Clone)]
+enum CaData {
+    Memory {
+        format: CredentialFormat,
+        cert: Vec<u8>,
+    },
+    /// Read on every build and reload.
+    File {
+        format: CredentialFormat,
+        path: PathBuf,
+    },
+}
+
+/// Builder for [`ETSI014Client`] with credentials from memory or files.
+#[derive(Clone)]
+pub struct ETSI014ClientBuilder {
+    host: String,
+    port: u16,
+    identity: Option<IdentityData>,
+    key_passphrase: Option<PassphraseSource>,
+    server_ca: Vec<CaData>,
+    server_ca_dirs: Vec<PathBuf>,
+    spki_pins: Vec<String>,
+    server_name: Option<String>,
+    min_tls_version: Option<TlsVersion>,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    max_retries: u32,
+    retry_delay: Duration,
+    path_prefix: String,
+    http_version: HttpVersion,
+    verbose_diagnostics: bool,
+    ledger: Option<Arc<KeyLedger>>,
+    audit_log: Option<Arc<AuditLog>>,
+    key_cache: Option<Arc<KeyCache>>,
+    quota: Option<KeyQuota>,
+    verify_source_sae_id: bool,
+    expected_sae_id: Option<SaeId>,
+}
+
+impl fmt::Debug for ETSI014ClientBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ETSI014ClientBuilder")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ETSI014ClientBuilder {
+    pub const DEFAULT_PATH_PREFIX: &'static str = "api/v1/keys";
+
+    pub fn new(host: &str, port: u16) -> Self {
+        ETSI014ClientBuilder {
+            host: host.to_owned(),
+            port,
+            identity: None,
+            key_passphrase: None,
+            server_ca: Vec::new(),
+            server_ca_dirs: Vec::new(),
+            spki_pins: Vec::new(),
+            server_name: None,
+            min_tls_version: None,
+            connect_timeout: None,
+            timeout: None,
+            max_retries: 0,
+            retry_delay: Duration::from_secs(1),
+            path_prefix: Self::DEFAULT_PATH_PREFIX.to_owned(),
+            http_version: HttpVersion::Auto,
+            verbose_diagnostics: false,
+            ledger: None,
+            audit_log: None,
+            key_cache: None,
+            quota: None,
+            verify_source_sae_id: false,
+            expected_sae_id: None,
+        }
+    }
+
+    /// Client certificate and PKCS#8, encrypted PKCS#8, PKCS#1 RSA or SEC1 EC private key.
+    /// The private key is copied into protected memory. Encrypted keys need a
+    /// [passphrase](Self::key_passphrase).
+    pub fn identity(
+        &mut self,
+        format: CredentialFormat,
+        cert: &[u8],
+        key: &[u8],
+    ) -> &mut Self {
+        self.identity = Some(IdentityData::CertKey {
+            format,
+            cert: cert.to_vec(),
+            key: SecretVec::new(key.len(), |s| s.copy_from_slice(key)),
+        });
+        self
+    }
+
+    pub fn identity_pem(&mut self, cert: &[u8], key: &[u8]) -> &mut Self {
+        self.identity(CredentialFormat::Pem, cert, key)
+    }
+
+    pub fn identity_der(&mut self, cert: &[u8], key: &[u8]) -> &mut Self {
+        self.identity(CredentialFormat::Der, cert, key)
+    }
+
+    /// Client certificate and private key files, like [`Self::identity`]. The files are
+    /// read by [`Self::build`] and again by [`ETSI014Client::reload_credentials`], so
+    /// renewed certificates are picked up without creating a new client.
+    pub fn identity_files(
+        &mut self,
+        format: CredentialFormat,
+        cert: PathBuf,
+        key: PathBuf,
+    ) -> &mut Self {
+        self.identity = Some(IdentityData::CertKeyFiles { format, cert, key });
+        self
+    }
+
+    /// Client certificate, chain and private key from a PKCS#12 bundle instead of
+    /// [`Self::identity`]. The bundle is copied into protected memory. Bundles encrypted
+    /// with AES, the default since OpenSSL 3, and with the legacy 3DES or RC2 are supported.
+    pub fn identity_pkcs12(&mut self, pkcs12: &[u8]) -> &mut Self {
+        self.identity = Some(IdentityData::Pkcs12(SecretVec::new(pkcs12.len(), |s| {
+            s.copy_from_slice(pkcs12)
+        })));
+        self
+    }
+
+    /// PKCS#12 bundle file, like [`Self::identity_pkcs12`]. The file is read by
+    /// [`Self::build`] and again by [`ETSI014Client::reload_credentials`].
+    pub fn identity_pkcs12_file(&mut self, pkcs12: PathBuf) -> &mut Self {
+        self.identity = Some(IdentityData::Pkcs12File(pkcs12));
+        self
+    }
+
+    /// Passphrase of an encrypted private key or PKCS#12 bundle, copied into protected
+    /// memory.
+    pub fn key_passphrase(&mut self, passphrase: &[u8]) -> &mut Self {
+        self.key_passphrase = Some(PassphraseSource::Passphrase(SecretVec::new(
+            passphrase.len(),
+            |s| s.copy_from_slice(passphrase),
+        )));
+        self
+    }
+
+    /// Ask `callback` for the passphrase, e.g. by prompting the user. It is only called by
+    /// [`Self::build`] and when reloading credentials, if the private key is encrypted or a
+    /// PKCS#12 bundle is used.
+    pub fn key_passphrase_callback(
+        &mut self,
+        callback: impl Fn() -> io::Result<SecretVec<u8>> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.key_passphrase = Some(PassphraseSource::Callback(Arc::new(callback)));
+        self
+    }
+
+    /// CA certificate used to verify the KME, replacing the CA certificates added before. A
+    /// PEM file may contain a bundle of several certificates.
+    pub fn server_ca(&mut self, format: CredentialFormat, cert: &[u8]) -> &mut Self {
+        self.server_ca.clear();
+        self.add_server_ca(format, cert)
+    }
+
+    /// Trust another CA certificate or PEM bundle to verify the KME, e.g. during a rollover
+    /// to a new CA.
+    pub fn add_server_ca(&mut self, format: CredentialFormat, cert: &[u8]) -> &mut Self {
+        self.server_ca.push(CaData::Memory {
+            format,
+            cert: cert.to_vec(),
+        });
+        self
+    }
+
+    /// CA certificate file, replacing the CA certificates added before. The file is read by
+    /// [`Self::build`] and again by [`ETSI014Client::reload_credentials`].
+    pub fn server_ca_file(
+        &mut self,
+        format: CredentialFormat,
+        path: PathBuf,
+    ) -> &mut Self {
+        self.server_ca.clear();
+        self.add_server_ca_file(format, path)
+    }
+
+    /// Trust another CA certificate or PEM bundle file, read like [`Self::server_ca_file`].
+    pub fn add_server_ca_file(
+        &mut self,
+        format: CredentialFormat,
+        path: PathBuf,
+    ) -> &mut Self {
+        self.server_ca.push(CaData::File { format, path });
+        self
+    }
+
+    pub fn server_ca_pem(&mut self, cert: &[u8]) -> &mut Self {
+        self.server_ca(CredentialFormat::Pem, cert)
+    }
+
+    pub fn server_ca_der(&mut self, cert: &[u8]) -> &mut Self {
+        self.server_ca(CredentialFormat::Der, cert)
+    }
+
+    /// Trust the PEM certificates and bundles in `dir`, the files ending in `.pem`, `.crt`
+    /// or `.cer`. The directory is read by [`Self::build`] and when reloading credentials.
+    pub fn add_server_ca_dir(&mut self, dir: PathBuf) -> &mut Self {
+        self.server_ca_dirs.push(dir);
+        self
+    }
+
+    /// Only accept a KME whose certificate contains one of the pinned public keys, on top
+    /// of verifying the certificate. `pin` is the base64 SHA-256 hash of the DER encoded
+    /// SubjectPublicKeyInfo, optionally prefixed by `sha256//` like in curl.
+    pub fn add_spki_pin(&mut self, pin: &str) -> &mut Self {
+        self.spki_pins.push(pin.to_owned());
+        self
+    }
+
+    /// Verify the certificate of the KME against `server_name` instead of the host. The
+    /// client still connects to the host, which may be an IP address. `server_name` must be
+    /// a DNS name, [`build`](Self::build) fails for IP addresses other than the host.
+    pub fn server_name(&mut self, server_name: Option<&str>) -> &mut Self {
+        self.server_name = server_name.map(str::to_owned);
+        self
+    }
+
+    /// Refuse to connect with TLS versions older than `version`. `None` uses the default
+    /// of the TLS backend.
+    pub fn min_tls_version(&mut self, version: Option<TlsVersion>) -> &mut Self {
+        self.min_tls_version = version;
+        self
+    }
+
+    /// Timeout for establishing a connection, including the TLS handshake. `None` disables
+    /// the timeout, which is the default.
+    pub fn connect_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Timeout for a complete request, from connecting until the response body is read.
+    /// `None` disables the timeout, which is the default.
+    pub fn timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Retry requests that failed to connect up to `max_retries` times, waiting `delay`
+    /// between attempts. Requests that timed out are only retried for `status`, as the
+    /// KME might already have handed out keys for the other endpoints.
+    pub fn retries(&mut self, max_retries: u32, delay: Duration) -> &mut Self {
+        self.max_retries = max_retries;
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Path in front of `/{SAE_ID}/{endpoint}`, `api/v1/keys` by default.
+    pub fn path_prefix(&mut self, path_prefix: &str) -> &mut Self {
+        self.path_prefix = path_prefix.trim_matches('/').to_owned();
+        self
+    }
+
+    pub fn http_version(&mut self, http_version: HttpVersion) -> &mut Self {
+        self.http_version = http_version;
+        self
+    }
+
+    /// Include requests and response bodies in errors, for debugging in a lab. Response
+    /// bodies may contain keys, which then end up in unprotected memory and error messages.
+    /// Disabled by default.
+    pub fn verbose_diagnostics(&mut self, enabled: bool) -> &mut Self {
+        self.verbose_diagnostics = enabled;
+        self
+    }
+
+    /// Record every key received in `ledger` and refuse keys that were retrieved before.
+    pub fn key_ledger(&mut self, ledger: Arc<KeyLedger>) -> &mut Self {
+        self.ledger = Some(ledger);
+        self
+    }
+
+    /// Record every operation in `audit_log`. Records that cannot be written do not change the
+    /// result of the operation, as keys would be lost, but are logged as errors and counted
+    /// in the `errors_total` metric with kind `AuditError`.
+    pub fn audit_log(&mut self, audit_log: Arc<AuditLog>) -> &mut Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Take keys from `key_cache` when `get_keys` cannot connect to the KME. Fill the cache
+    /// with [`ETSI014Client::fill_key_cache`].
+    pub fn key_cache(&mut self, key_cache: Arc<KeyCache>) -> &mut Self {
+        self.key_cache = Some(key_cache);
+        self
+    }
+
+    /// Limit the keys requested with `get_keys` and `fill_key_cache` per target SAE.
+    pub fn key_quota(&mut self, quota: KeyQuota) -> &mut Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Check that the KME reports `expected`, or the common name of the client certificate
+    /// if `None`, as the source SAE ID in every status, and once with a status request
+    /// before the first keys are requested. Fails with `ErrorType::IdentityMismatch` if it
+    /// reports another SAE ID, which means the wrong certificate is used.
+    pub fn verify_source_sae_id(&mut self, expected: Option<SaeId>) -> &mut Self {
+        self.verify_source_sae_id = true;
+        self.expected_sae_id = expected;
+        self
+    }
+
+    /// The SAE ID the KME must report as the source SAE ID, if it is verified.
+    fn expected_sae_id(
+        &self,
+        identity: Option<&ClientIdentity>,
+    ) -> Result<Option<SaeId>, Error> {
+        if !self.verify_source_sae_id {
+            return Ok(None);
+        }
+        match (
+            &self.expected_sae_id,
+            identity.and_then(ClientIdentity::sae_id),
+        ) {
+            (Some(expected), _) => Ok(Some(expected.clone())),
+            (None, Some(common_name)) => Ok(Some(common_name)),
+            (None, None) => Err(Error::new(
+                "Cannot verify the source SAE ID, the client certificate has no common \
+                 name that is an SAE ID"
+                    .to_string(),
+                InvalidArgument,
+                None,
+            )),
+        }
+    }
+
+    fn base_url(&self) -> Result<Url, Error> {
+        if let Some(server_name) = &self.server_name {
+            // reqwest connects to IP addresses in the URL without resolving them, so the
+            // host would be ignored
+            let ip = server_name.trim_start_matches('[').trim_end_matches(']');
+            if ip.parse::<IpAddr>().is_ok() && *server_name != self.host {
+                return Err(Error::new(
+                    format!(
+                        "Server name {server_name} is an IP address, which cannot be \
+                         verified while connecting to {}, use it as the host instead",
+                        self.host
+                    ),
+                    InvalidArgument,
+                    None,
+                ));
+            }
+        }
+        let host = self.server_name.as_ref().unwrap_or(&self.host);
+        // Can not set host and port without parsing something first
+        let mut base_url =
+            Url::parse("https://localhost").expect("Error parsing hardcoded URL");
+        base_url
+            .set_scheme("https")
+            .expect("Error setting https as scheme");
+        base_url.set_host(Some(host)).map_err(|e| {
+            Error::new(
+                format!("Invalid host: {host}"),
+                InvalidHost,
+                Some(Box::new(e)),
+            )
+        })?;
+        base_url
+            .set_port(Some(self.port))
+            // Might fail if host invalid
+            .map_err(|_| {
+                Error::new(
+                    format!("Error setting port for host: '{host}"),
+                    InvalidHost,
+                    None,
+                )
+            })?;
+        Ok(base_url)
+    }
+
+    fn read_server_ca_dir(dir: &PathBuf) -> Result<Vec<CaData>, Error> {
+        let entries = fs::read_dir(dir).map_err(|e| {
+            Error::new(
+                format!("Error reading {dir:#?}"),
+                InvalidArgument,
+                Some(Box::new(e)),
+            )
+        })?;
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|e| {
+                    Error::new(
+                        format!("Error reading {dir:#?}"),
+                        InvalidArgument,
+                        Some(Box::new(e)),
+                    )
+                })?
+                .path();
+            let extension = path.extension().and_then(|e| e.to_str());
+            if matches!(extension, Some("pem" | "crt" | "cer")) && path.is_file() {
+                paths.push(path);
+            }
+        }
+        // Sorted, so errors are reported in a predictable order
+        paths.sort();
+        Ok(paths
+            .into_iter()
+            .map(|path| CaData::File {
+                format: CredentialFormat::Pem,
+                path,
+            })
+            .collect())
+    }
+
+    fn parse_server_ca(&self) -> Result<Vec<Certificate>, Error> {
+        let mut from_dirs = Vec::new();
+        for dir in &self.server_ca_dirs {
+            from_dirs.extend(Self::read_server_ca_dir(dir)?);
+        }
+        let mut certs = Vec::new();
+        for server_ca in self.server_ca.iter().chain(&from_dirs) {
+            let (format, cert) = match server_ca {
+                CaData::Memory { format, cert } => (*format, cert.clone()),
+                CaData::File { format, path } => (*format, read_file(path)?),
+            };
+            let parsed = match format {
+                CredentialFormat::Pem => Certificate::from_pem_bundle(&cert),
+                CredentialFormat::Der => {
+                    Certificate::from_der(&cert).map(|cert| vec![cert])
+                }
+            };
+            certs.extend(parsed.map_err(|e| {
+                Error::new(
+                    "Error parsing server CA".to_string(),
+                    InvalidArgument,
+                    Some(Box::new(e)),
+                )
+            })?);
+        }
+        if certs.is_empty() {
+            return Err(Error::new(
+                "No server CA configured".to_string(),
+                InvalidArgument,
+                None,
+            ));
+        }
+        Ok(certs)
+    }
+
+    fn passphrase(&self) -> Result<SecretVec<u8>, Error> {
+        match &self.key_passphrase {
+            Some(PassphraseSource::Passphrase(passphrase)) => {
+                let passphrase = passphrase.borrow();
+                Ok(SecretVec::new(passphrase.len(), |s| {
+                    s.copy_from_slice(&passphrase)
+                }))
+            }
+            Some(PassphraseSource::Callback(callback)) => callback().map_err(|e| {
+                Error::new(
+                    "Error reading key passphrase".to_string(),
+                    InvalidArgument,
+                    Some(Box::new(e)),
+                )
+            }),
+            None => Err(Error::new(
+                "The private key or PKCS#12 bundle is encrypted, but no passphrase is \
+                 configured"
+                    .to_string(),
+                InvalidArgument,
+                None,
+            )),
+        }
+    }
+
+    /// The certificate chain as PEM and the private key as PKCS#8 DER.
+    fn credentials(&self) -> Result<(Vec<u8>, SecretVec<u8>), Error> {
+        let identity = self.identity.as_ref().ok_or_else(|| {
+            Error::new(
+                "No client certificate and key configured".to_string(),
+                InvalidArgument,
+                None,
+            )
+        })?;
+        let cert_pem = |format: CredentialFormat, cert: Vec<u8>| match format {
+            CredentialFormat::Pem => cert,
+            CredentialFormat::Der => der_to_pem("CERTIFICATE", &cert).borrow().to_vec(),
+        };
+        match identity {
+            IdentityData::CertKey { format, cert, key } => {
+                let key = pkcs8_der(*format, &key.borrow(), &|| self.passphrase())?;
+                Ok((cert_pem(*format, cert.clone()), key))
+            }
+            IdentityData::Pkcs12(pkcs12) => {
+                pkcs12::parse(&pkcs12.borrow(), &self.passphrase()?.borrow())
+            }
+            IdentityData::CertKeyFiles { format, cert, key } => {
+                let cert = read_file(cert)?;
+                let mut key = read_file(key)?;
+                let result = pkcs8_der(*format, &key, &|| self.passphrase());
+                key.zeroize();
+                Ok((cert_pem(*format, cert), result?))
+            }
+            IdentityData::Pkcs12File(path) => {
+                let mut pkcs12 = read_file(path)?;
+                let result = pkcs12::parse(&pkcs12, &self.passphrase()?.borrow());
+                pkcs12.zeroize();
+                result
+            }
+        }
+    }
+
+    /// Files and directories read by [`Self::build`], which are watched by
+    /// [`ETSI014Client::watch_credentials`].
+    pub(crate) fn credential_paths(&self) -> Vec<PathBuf> {
+        let mut paths = match &self.identity {
+            Some(IdentityData::CertKeyFiles { cert, key, .. }) => {
+                vec![cert.clone(), key.clone()]
+            }
+            Some(IdentityData::Pkcs12File(path)) => vec![path.clone()],
+            _ => Vec::new(),
+        };
+        for server_ca in &self.server_ca {
+            if let CaData::File { path, .. } = server_ca {
+                paths.push(path.clone());
+            }
+        }
+        paths.extend(self.server_ca_dirs.iter().cloned());
+        paths
+    }
+
+    fn parse_identity(cert_pem: &[u8], key: &[u8]) -> Result<Identity, Error> {
+        let key_pem = der_to_pem("PRIVATE KEY", key);
+        let key_pem = key_pem.borrow();
+        #[cfg(feature = "rustls")]
+        let result = {
+            // rustls takes the certificates and key from one PEM file
+            let pem = SecretVec::new(cert_pem.len() + 1 + key_pem.len(), |pem| {
+                let (cert_dst, key_dst) = pem.split_at_mut(cert_pem.len() + 1);
+                cert_dst[..cert_pem.len()].copy_from_slice(cert_pem);
+                cert_dst[cert_pem.len()] = b'\n';
+                key_dst.copy_from_slice(&key_pem);
+            });
+            Identity::from_pem(&pem.borrow())
+        };
+        #[cfg(not(feature = "rustls"))]
+        let result = Identity::from_pkcs8_pem(cert_pem, &key_pem);
+        result.map_err(|e| {
+            Error::new(
+                "Error parsing client certificate or key".to_string(),
+                InvalidArgument,
+                Some(Box::new(e)),
+            )
+        })
+    }
+
+    pub fn build(&self) -> Result<ETSI014Client, Error> {
+        Ok(ETSI014Client {
+            state: RwLock::new(Arc::new(self.build_state()?)),
+            options: Mutex::new(self.clone()),
+            path_prefix: self.path_prefix.clone(),
+            max_retries: self.max_retries,
+            retry_delay: self.retry_delay,
+            verbose_diagnostics: self.verbose_diagnostics,
+            ledger: self.ledger.clone(),
+            audit_log: self.audit_log.clone(),
+            key_cache: self.key_cache.clone(),
+            quota: self.quota.clone().map(QuotaTracker::new),
+        })
+    }
+
+    /// The HTTP client with the current credentials, which is replaced when they are
+    /// reloaded.
+    pub(crate) fn build_state(&self) -> Result<ClientState, Error> {
+        let base_url = self.base_url()?;
+        let (cert_pem, key) = self.credentials()?;
+        let identity = ClientIdentity::from_pem(&cert_pem).ok();
+        let expected_sae_id = self.expected_sae_id(identity.as_ref())?;
+        #[cfg(feature = "rustls")]
+        let client_builder = Client::builder().tls_backend_rustls();
+        #[cfg(not(feature = "rustls"))]
+        let client_builder = Client::builder().tls_backend_native();
+        let mut client_builder = client_builder
+            .tls_certs_only(self.parse_server_ca()?)
+            .identity(Self::parse_identity(&cert_pem, &key.borrow())?);
+        if let Some(version) = self.min_tls_version {
+            client_builder = client_builder.tls_version_min(version.into());
+        }
+        if !self.spki_pins.is_empty() {
+            let pins = self
+                .spki_pins
+                .iter()
+                .map(|pin| parse_spki_pin(pin))
+                .collect::<Result<_, _>>()?;
+            client_builder = client_builder
+                .tls_info(true)
+                .connector_layer(SpkiPinLayer::new(pins));
+        }
+        if let Some(server_name) = &self.server_name {
+            client_builder = client_builder.dns_resolver(ServerNameResolver {
+                server_name: server_name.clone(),
+                host: self.host.clone(),
+            });
+        }
+        if let Some(timeout) = self.connect_timeout {
+            client_builder = client_builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            client_builder = client_builder.timeout(timeout);
+        }
+        client_builder = match self.http_version {
+            HttpVersion::Auto => client_builder,
+            HttpVersion::Http1Only => client_builder.http1_only(),
+            HttpVersion::Http2PriorKnowledge => client_builder.http2_prior_knowledge(),
+        };
+        let http_client = client_builder.build().map_err(|e| {
+            Error::new(
+                "Error creating http client".to_string(),
+                InvalidArgument,
+                Some(Box::new(e)),
+            )
+        })?;
+        Ok(ClientState {
+            http_client,
+            base_url,
+            identity,
+            expected_sae_id,
+            identity_verified: AtomicBool::new(false),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_error(host: &str, server_name: &str) -> Error {
+        ETSI014ClientBuilder::new(host, 443)
+            .server_name(Some(server_name))
+            .build()
+            .expect_err("Client was built without credentials")
+    }
+
+    #[test]
+    fn ip_address_server_name_is_refused() {
+        for server_name in ["192.0.2.10", "2001:db8::10", "[2001:db8::10]"] {
+            let e = build_error("kme.example.org", server_name);
+            assert!(matches!(e.kind, InvalidArgument), "{e:?}");
+            assert!(e.msg.contains("is an IP address"), "{}", e.msg);
+        }
+        // DNS names and the host itself get as far as the missing credentials
+        for (host, server_name) in [("192.0.2.10", "kme.example.org"), ("::1", "::1")] {
+            let e = build_error(host, server_name);
+            assert!(!e.msg.contains("is an IP address"), "{}", e.msg);
+        }
+    }
+}
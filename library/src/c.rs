@@ -1,13 +1,33 @@
 use crate::error::ErrorType::{InvalidArgument, InvalidHost, InvalidResponse};
-use crate::{ETSI014Client, Error};
+use crate::{
+    CredentialFormat, ETSI014Client, ETSI014ClientBuilder, Error, HttpVersion, KeyId,
+    SaeId, TlsVersion, derive_key, keys_equal, xor_keys,
+};
 use libc::{c_char, size_t};
 use secrets::SecretVec;
 use std::ffi::{CStr, CString, c_int};
 use std::future::Future;
 use std::path::PathBuf;
+use std::time::Duration;
 
 pub const KEY_UUID_LENGTH: usize = 37;
 
+/// Version of the header, checked against Cargo.toml by build.rs. Compare with
+/// [`e14_version`] to detect a mismatch with the loaded library.
+#[allow(dead_code)]
+pub const E14_VERSION_MAJOR: u32 = 0;
+#[allow(dead_code)]
+pub const E14_VERSION_MINOR: u32 = 2;
+#[allow(dead_code)]
+pub const E14_VERSION_PATCH: u32 = 0;
+
+/// Version of the loaded library, e.g. "0.2.0". The string is statically allocated and must
+/// not be freed.
+#[unsafe(no_mangle)]
+pub extern "C" fn e14_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
 #[repr(C)]
 pub struct CStatus {
     pub source_kme_id: [c_char; 255],
@@ -127,6 +147,477 @@ pub unsafe extern "C" fn e14_new_etsi014_client(
     }
 }
 
+/// Like [`e14_new_etsi014_client`], with a PEM key that may be encrypted. If `key_path` is
+/// NULL, `cert_path` is a PKCS#12 bundle containing the client certificate and key.
+/// `passphrase` is the passphrase of the encrypted key or bundle, NULL if it is not
+/// encrypted. If this function returns a 0, the caller must call
+/// [`e14_free_etsi014_client`]. Otherwise, the caller must call [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_new_etsi014_client_with_passphrase(
+    host: *const c_char,
+    port: u16,
+    cert_path: *const c_char,
+    key_path: *const c_char,
+    server_ca_path: *const c_char,
+    passphrase: *const c_char,
+    etsi014_client: *mut *const ETSI014Client,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = (|| {
+            let mut options =
+                ETSI014ClientBuilder::new(str_from_ptr(host, "host")?, port);
+            let cert = PathBuf::from(str_from_ptr(cert_path, "cert_path")?);
+            if key_path.is_null() {
+                options.identity_pkcs12_file(cert);
+            } else {
+                let key = PathBuf::from(str_from_ptr(key_path, "key_path")?);
+                options.identity_files(CredentialFormat::Pem, cert, key);
+            }
+            let server_ca_path = str_from_ptr(server_ca_path, "server_ca_path")?;
+            options.server_ca_file(CredentialFormat::Pem, PathBuf::from(server_ca_path));
+            if !passphrase.is_null() {
+                options.key_passphrase(CStr::from_ptr(passphrase).to_bytes());
+            }
+            *etsi014_client = Box::into_raw(Box::new(options.build()?));
+            Ok(())
+        })();
+        c_result(result, error_str)
+    }
+}
+
+#[repr(C)]
+pub enum CCredentialFormat {
+    Pem,
+    /// DER encoded certificate and private key.
+    Der,
+}
+
+impl From<CCredentialFormat> for CredentialFormat {
+    fn from(format: CCredentialFormat) -> Self {
+        match format {
+            CCredentialFormat::Pem => CredentialFormat::Pem,
+            CCredentialFormat::Der => CredentialFormat::Der,
+        }
+    }
+}
+
+#[repr(C)]
+pub enum CHttpVersion {
+    /// Negotiate HTTP/1.1 or HTTP/2 using ALPN.
+    Auto,
+    Http1Only,
+    Http2PriorKnowledge,
+}
+
+impl From<CHttpVersion> for HttpVersion {
+    fn from(version: CHttpVersion) -> Self {
+        match version {
+            CHttpVersion::Auto => HttpVersion::Auto,
+            CHttpVersion::Http1Only => HttpVersion::Http1Only,
+            CHttpVersion::Http2PriorKnowledge => HttpVersion::Http2PriorKnowledge,
+        }
+    }
+}
+
+#[repr(C)]
+pub enum CTlsVersion {
+    /// The default of the TLS backend.
+    Default,
+    Tls12,
+    Tls13,
+}
+
+impl From<CTlsVersion> for Option<TlsVersion> {
+    fn from(version: CTlsVersion) -> Self {
+        match version {
+            CTlsVersion::Default => None,
+            CTlsVersion::Tls12 => Some(TlsVersion::Tls12),
+            CTlsVersion::Tls13 => Some(TlsVersion::Tls13),
+        }
+    }
+}
+
+unsafe fn str_from_ptr<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, Error> {
+    if ptr.is_null() {
+        return Err(Error::new(
+            format!("{name} is a null pointer"),
+            InvalidArgument,
+            None,
+        ));
+    }
+    unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .map_err(|utf8error| {
+            Error::new(
+                format!("{name} is not valid UTF8"),
+                InvalidArgument,
+                Some(Box::new(utf8error)),
+            )
+        })
+}
+
+unsafe fn bytes_from_ptr<'a>(
+    ptr: *const u8,
+    len: size_t,
+    name: &str,
+) -> Result<&'a [u8], Error> {
+    if ptr.is_null() {
+        return Err(Error::new(
+            format!("{name} is a null pointer"),
+            InvalidArgument,
+            None,
+        ));
+    }
+    Ok(unsafe { std::slice::from_raw_parts(ptr, len) })
+}
+
+unsafe fn options_from_ptr<'a>(
+    options: *mut ClientOptions,
+) -> Result<&'a mut ETSI014ClientBuilder, Error> {
+    unsafe { options.as_mut() }
+        .map(|options| &mut options.0)
+        .ok_or_else(|| {
+            Error::new(
+                "Null pointer passed as client options".to_string(),
+                InvalidArgument,
+                None,
+            )
+        })
+}
+
+/// Returns 0 on success. Otherwise, stores the error in `error_str` and returns 1.
+unsafe fn c_result(result: Result<(), Error>, error_str: *mut *const c_char) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            unsafe { *error_str = create_error_cstr(e) };
+            1
+        }
+    }
+}
+
+/// Options for creating a client with [`e14_new_etsi014_client_from_options`], with
+/// credentials from memory or files. Created by [`e14_new_client_options`] and freed with
+/// [`e14_free_client_options`].
+pub struct ClientOptions(ETSI014ClientBuilder);
+
+/// Create options for [`e14_new_etsi014_client_from_options`]. A client identity and server CA
+/// must be set before creating a client. If this function returns a 0, the caller must call
+/// [`e14_free_client_options`]. Otherwise, the caller must call [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_new_client_options(
+    host: *const c_char,
+    port: u16,
+    options: *mut *mut ClientOptions,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = str_from_ptr(host, "host").map(|host| {
+            *options = Box::into_raw(Box::new(ClientOptions(ETSI014ClientBuilder::new(
+                host, port,
+            ))));
+        });
+        c_result(result, error_str)
+    }
+}
+
+/// Set the client certificate and PKCS#8, encrypted PKCS#8, PKCS#1 RSA or SEC1 EC private
+/// key from memory. The private key is copied into protected memory, so the caller may
+/// overwrite `key` after this call. If this function returns a 1, the caller must call
+/// [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_client_options_set_identity(
+    options: *mut ClientOptions,
+    format: CCredentialFormat,
+    cert: *const u8,
+    cert_len: size_t,
+    key: *const u8,
+    key_len: size_t,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = (|| {
+            let options = options_from_ptr(options)?;
+            let cert = bytes_from_ptr(cert, cert_len, "cert")?;
+            let key = bytes_from_ptr(key, key_len, "key")?;
+            options.identity(format.into(), cert, key);
+            Ok(())
+        })();
+        c_result(result, error_str)
+    }
+}
+
+/// Set the client certificate, chain and private key from a PKCS#12 bundle in memory, instead
+/// of [`e14_client_options_set_identity`]. The bundle is copied into protected memory. If
+/// this function returns a 1, the caller must call [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_client_options_set_pkcs12(
+    options: *mut ClientOptions,
+    pkcs12: *const u8,
+    pkcs12_len: size_t,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = (|| {
+            let options = options_from_ptr(options)?;
+            options.identity_pkcs12(bytes_from_ptr(pkcs12, pkcs12_len, "pkcs12")?);
+            Ok(())
+        })();
+        c_result(result, error_str)
+    }
+}
+
+/// Set the passphrase of an encrypted private key or PKCS#12 bundle. The passphrase is copied
+/// into protected memory, so the caller may overwrite it after this call. If this function
+/// returns a 1, the caller must call [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_client_options_set_key_passphrase(
+    options: *mut ClientOptions,
+    passphrase: *const u8,
+    passphrase_len: size_t,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = (|| {
+            let options = options_from_ptr(options)?;
+            options.key_passphrase(bytes_from_ptr(
+                passphrase,
+                passphrase_len,
+                "passphrase",
+            )?);
+            Ok(())
+        })();
+        c_result(result, error_str)
+    }
+}
+
+/// Set the CA certificate used to verify the KME from memory. If this function returns a 1,
+/// the caller must call [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_client_options_set_server_ca(
+    options: *mut ClientOptions,
+    format: CCredentialFormat,
+    cert: *const u8,
+    cert_len: size_t,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = (|| {
+            let options = options_from_ptr(options)?;
+            let cert = bytes_from_ptr(cert, cert_len, "cert")?;
+            options.server_ca(format.into(), cert);
+            Ok(())
+        })();
+        c_result(result, error_str)
+    }
+}
+
+/// Trust another CA certificate or PEM bundle to verify the KME, in addition to the CA set
+/// with [`e14_client_options_set_server_ca`]. If this function returns a 1, the caller must
+/// call [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_client_options_add_server_ca(
+    options: *mut ClientOptions,
+    format: CCredentialFormat,
+    cert: *const u8,
+    cert_len: size_t,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = (|| {
+            let options = options_from_ptr(options)?;
+            let cert = bytes_from_ptr(cert, cert_len, "cert")?;
+            options.add_server_ca(format.into(), cert);
+            Ok(())
+        })();
+        c_result(result, error_str)
+    }
+}
+
+/// Trust the PEM files ending in .pem, .crt or .cer in `dir`, which is read when the client
+/// is created. If this function returns a 1, the caller must call [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_client_options_add_server_ca_dir(
+    options: *mut ClientOptions,
+    dir: *const c_char,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = (|| {
+            let options = options_from_ptr(options)?;
+            options.add_server_ca_dir(PathBuf::from(str_from_ptr(dir, "dir")?));
+            Ok(())
+        })();
+        c_result(result, error_str)
+    }
+}
+
+/// Only accept a KME whose certificate contains one of the pinned public keys. `pin` is
+/// "sha256//" followed by the base64 SHA-256 hash of the SubjectPublicKeyInfo, and is
+/// checked when the client is created. If this function returns a 1, the caller must call
+/// [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_client_options_add_spki_pin(
+    options: *mut ClientOptions,
+    pin: *const c_char,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = (|| {
+            let options = options_from_ptr(options)?;
+            options.add_spki_pin(str_from_ptr(pin, "pin")?);
+            Ok(())
+        })();
+        c_result(result, error_str)
+    }
+}
+
+/// Verify the certificate of the KME against `server_name` instead of the host, while still
+/// connecting to the host. It must be a DNS name, creating the client fails for IP addresses
+/// other than the host. NULL verifies against the host again. If this function returns a
+/// 1, the caller must call [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_client_options_set_server_name(
+    options: *mut ClientOptions,
+    server_name: *const c_char,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = (|| {
+            let options = options_from_ptr(options)?;
+            let server_name = if server_name.is_null() {
+                None
+            } else {
+                Some(str_from_ptr(server_name, "server_name")?)
+            };
+            options.server_name(server_name);
+            Ok(())
+        })();
+        c_result(result, error_str)
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_client_options_set_min_tls_version(
+    options: *mut ClientOptions,
+    min_tls_version: CTlsVersion,
+) {
+    unsafe {
+        if let Some(ClientOptions(options)) = options.as_mut() {
+            options.min_tls_version(min_tls_version.into());
+        }
+    }
+}
+
+/// Timeouts in milliseconds, 0 disables a timeout. `request_timeout_ms` covers the complete
+/// request, from connecting until the response body is read.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_client_options_set_timeouts(
+    options: *mut ClientOptions,
+    connect_timeout_ms: u64,
+    request_timeout_ms: u64,
+) {
+    unsafe {
+        if let Some(ClientOptions(options)) = options.as_mut() {
+            let duration = |ms| (ms != 0).then(|| Duration::from_millis(ms));
+            options
+                .connect_timeout(duration(connect_timeout_ms))
+                .timeout(duration(request_timeout_ms));
+        }
+    }
+}
+
+/// Retry requests that failed to connect up to `max_retries` times, waiting `retry_delay_ms`
+/// between attempts.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_client_options_set_retries(
+    options: *mut ClientOptions,
+    max_retries: u32,
+    retry_delay_ms: u64,
+) {
+    unsafe {
+        if let Some(ClientOptions(options)) = options.as_mut() {
+            options.retries(max_retries, Duration::from_millis(retry_delay_ms));
+        }
+    }
+}
+
+/// Path in front of `/{SAE_ID}/{endpoint}`, `api/v1/keys` by default. If this function
+/// returns a 1, the caller must call [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_client_options_set_path_prefix(
+    options: *mut ClientOptions,
+    path_prefix: *const c_char,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = (|| {
+            let options = options_from_ptr(options)?;
+            options.path_prefix(str_from_ptr(path_prefix, "path_prefix")?);
+            Ok(())
+        })();
+        c_result(result, error_str)
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_client_options_set_http_version(
+    options: *mut ClientOptions,
+    http_version: CHttpVersion,
+) {
+    unsafe {
+        if let Some(ClientOptions(options)) = options.as_mut() {
+            options.http_version(http_version.into());
+        }
+    }
+}
+
+/// Include requests and response bodies, which may contain keys, in error strings. Only
+/// meant for debugging, disabled by default.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_client_options_set_verbose_diagnostics(
+    options: *mut ClientOptions,
+    enabled: bool,
+) {
+    unsafe {
+        if let Some(ClientOptions(options)) = options.as_mut() {
+            options.verbose_diagnostics(enabled);
+        }
+    }
+}
+
+/// The options are not consumed and can be reused. If this function returns a 0, the caller
+/// must call [`e14_free_etsi014_client`]. Otherwise, the caller must call
+/// [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_new_etsi014_client_from_options(
+    options: *const ClientOptions,
+    etsi014_client: *mut *const ETSI014Client,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = (|| {
+            let options = options_from_ptr(options as *mut ClientOptions)?;
+            *etsi014_client = Box::into_raw(Box::new(options.build()?));
+            Ok(())
+        })();
+        c_result(result, error_str)
+    }
+}
+
+/// Will overwrite the private key and deallocate memory.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_free_client_options(options: *mut *mut ClientOptions) {
+    unsafe {
+        if options.is_null() || (*options).is_null() {
+            return;
+        }
+        let _ = Box::from_raw(*options);
+        *options = std::ptr::null_mut();
+    }
+}
+
 fn block_on<F: Future>(future: F) -> F::Output {
     tokio::runtime::Builder::new_current_thread()
         .enable_all()
@@ -135,6 +626,29 @@ fn block_on<F: Future>(future: F) -> F::Output {
         .block_on(future)
 }
 
+/// Read the client certificate, key and server CA files again and use them for new
+/// requests, e.g. after a certificate was renewed. Requests in progress are not interrupted.
+/// Credentials set from memory stay the same. If reading or parsing fails, the client keeps
+/// its credentials. If this function returns a 1, the caller must call
+/// [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_reload_credentials(
+    client: *const ETSI014Client,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = match client.as_ref() {
+            Some(client) => client.reload_credentials(),
+            None => Err(Error::new(
+                "Null pointer passed to reload_credentials".to_string(),
+                InvalidArgument,
+                None,
+            )),
+        };
+        c_result(result, error_str)
+    }
+}
+
 /// If this function returns a 1, the caller must call [`e14_free_error_str`].
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn e14_get_status(
@@ -168,31 +682,38 @@ pub unsafe extern "C" fn e14_get_status(
                 return 1;
             }
         };
-        let status_result = block_on(client.get_status(target_sae_id));
+        let target_sae_id = match SaeId::new(target_sae_id) {
+            Ok(id) => id,
+            Err(e) => {
+                *error_str = create_error_cstr(e);
+                return 1;
+            }
+        };
+        let status_result = block_on(client.get_status(&target_sae_id));
         match status_result {
             Ok(s) => {
-                let source_kme_id = match create_cstr(s.source_kme_id) {
+                let source_kme_id = match create_cstr(s.source_kme_id.into()) {
                     Ok(s) => s,
                     Err(e) => {
                         *error_str = create_error_cstr(e);
                         return 1;
                     }
                 };
-                let target_kme_id = match create_cstr(s.target_kme_id) {
+                let target_kme_id = match create_cstr(s.target_kme_id.into()) {
                     Ok(s) => s,
                     Err(e) => {
                         *error_str = create_error_cstr(e);
                         return 1;
                     }
                 };
-                let source_sae_id = match create_cstr(s.source_sae_id) {
+                let source_sae_id = match create_cstr(s.source_sae_id.into()) {
                     Ok(s) => s,
                     Err(e) => {
                         *error_str = create_error_cstr(e);
                         return 1;
                     }
                 };
-                let target_sae_id = match create_cstr(s.target_sae_id) {
+                let target_sae_id = match create_cstr(s.target_sae_id.into()) {
                     Ok(s) => s,
                     Err(e) => {
                         *error_str = create_error_cstr(e);
@@ -290,9 +811,16 @@ pub unsafe extern "C" fn e14_get_keys(
                 return 1;
             }
         };
+        let target_sae_id = match SaeId::new(target_sae_id) {
+            Ok(id) => id,
+            Err(e) => {
+                *error_str = create_error_cstr(e);
+                return 1;
+            }
+        };
         let keys = std::slice::from_raw_parts_mut(keys, amount_of_keys as usize);
         let get_keys_result =
-            block_on(client.get_keys(key_size_bits, target_sae_id, &[], amount_of_keys));
+            block_on(client.get_keys(key_size_bits, &target_sae_id, &[], amount_of_keys));
         match get_keys_result {
             Ok(keys_recv) => {
                 let keys_recv_len = keys_recv.len();
@@ -305,7 +833,7 @@ pub unsafe extern "C" fn e14_get_keys(
                     return 1;
                 }
                 for (i, (uuid_string, key_vec)) in keys_recv.into_iter().enumerate() {
-                    let uuid = match create_cstr(uuid_string) {
+                    let uuid = match create_cstr(uuid_string.into()) {
                         Ok(uuid) => uuid,
                         Err(e) => {
                             *error_str = create_error_cstr(e);
@@ -359,6 +887,13 @@ pub unsafe extern "C" fn e14_get_keys_by_ids(
                 return 1;
             }
         };
+        let target_sae_id = match SaeId::new(target_sae_id) {
+            Ok(id) => id,
+            Err(e) => {
+                *error_str = create_error_cstr(e);
+                return 1;
+            }
+        };
         let mut key_ids_vec = Vec::with_capacity(key_ids_len);
         let key_ids = std::slice::from_raw_parts(key_ids, key_ids_len);
         for (i, &ptr) in key_ids.iter().enumerate() {
@@ -374,11 +909,17 @@ pub unsafe extern "C" fn e14_get_keys_by_ids(
                     return 1;
                 }
             };
-            key_ids_vec.push(key_id);
+            match KeyId::new(key_id) {
+                Ok(key_id) => key_ids_vec.push(key_id),
+                Err(e) => {
+                    *error_str = create_error_cstr(e);
+                    return 1;
+                }
+            }
         }
         let keys = std::slice::from_raw_parts_mut(keys, key_ids_len);
         let get_keys_result =
-            block_on(client.get_keys_by_ids(target_sae_id, key_ids_vec.as_slice()));
+            block_on(client.get_keys_by_ids(&target_sae_id, key_ids_vec.as_slice()));
         match get_keys_result {
             Ok(keys_recv) => {
                 let keys_recv_len = keys_recv.len();
@@ -391,7 +932,7 @@ pub unsafe extern "C" fn e14_get_keys_by_ids(
                     return 1;
                 }
                 for (i, (uuid_string, key_vec)) in keys_recv.into_iter().enumerate() {
-                    let uuid = match create_cstr(uuid_string) {
+                    let uuid = match create_cstr(uuid_string.into()) {
                         Ok(uuid) => uuid,
                         Err(e) => {
                             *error_str = create_error_cstr(e);
@@ -469,6 +1010,119 @@ pub unsafe extern "C" fn e14_free_qkd_key_bytes(
     }
 }
 
+unsafe fn key_from_ptr<'a>(
+    key_bytes_protected: *const KeyBytesProtected,
+    name: &str,
+) -> Result<&'a SecretVec<u8>, Error> {
+    unsafe { (key_bytes_protected as *const SecretVec<u8>).as_ref() }.ok_or_else(|| {
+        Error::new(format!("{name} is a null pointer"), InvalidArgument, None)
+    })
+}
+
+/// Derive a subkey of `derived_len` bytes from a qkd key using HKDF-SHA256, without exposing
+/// either key. Keys passed to this function and the other key operations below must not be
+/// unprotected at the same time. If this function returns a 0, the caller must call
+/// [`e14_free_qkd_key_bytes`] on `derived`. Otherwise, the caller must call
+/// [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_derive_qkd_key(
+    key_bytes_protected: *const KeyBytesProtected,
+    salt: *const u8,
+    salt_len: size_t,
+    info: *const u8,
+    info_len: size_t,
+    derived_len: size_t,
+    derived: *mut *const KeyBytesProtected,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = (|| {
+            let key = key_from_ptr(key_bytes_protected, "key_bytes_protected")?;
+            let salt = if salt_len == 0 {
+                &[]
+            } else {
+                bytes_from_ptr(salt, salt_len, "salt")?
+            };
+            let info = if info_len == 0 {
+                &[]
+            } else {
+                bytes_from_ptr(info, info_len, "info")?
+            };
+            let derived_key = derive_key(key, salt, info, derived_len)?;
+            *derived = Box::into_raw(Box::new(derived_key)) as *const KeyBytesProtected;
+            Ok(())
+        })();
+        c_result(result, error_str)
+    }
+}
+
+/// Combine two keys of equal length with XOR. If this function returns a 0, the caller must
+/// call [`e14_free_qkd_key_bytes`] on `combined`. Otherwise, the caller must call
+/// [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_xor_qkd_keys(
+    a: *const KeyBytesProtected,
+    b: *const KeyBytesProtected,
+    combined: *mut *const KeyBytesProtected,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = (|| {
+            let combined_key = xor_keys(key_from_ptr(a, "a")?, key_from_ptr(b, "b")?)?;
+            *combined = Box::into_raw(Box::new(combined_key)) as *const KeyBytesProtected;
+            Ok(())
+        })();
+        c_result(result, error_str)
+    }
+}
+
+/// Compare two keys in constant time. Returns false if either key is a null pointer or the
+/// keys differ in length.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_compare_qkd_keys(
+    a: *const KeyBytesProtected,
+    b: *const KeyBytesProtected,
+) -> bool {
+    unsafe {
+        match (key_from_ptr(a, "a"), key_from_ptr(b, "b")) {
+            (Ok(a), Ok(b)) => keys_equal(a, b),
+            _ => false,
+        }
+    }
+}
+
+/// Copy a qkd key into `buffer`, which must be at least as large as the key. The caller is
+/// responsible for protecting `buffer`, e.g. by allocating it with `sodium_malloc` or locking
+/// it with `mlock`, and for overwriting it after use. If this function returns a 1, the
+/// caller must call [`e14_free_error_str`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn e14_copy_qkd_key_bytes(
+    key_bytes_protected: *const KeyBytesProtected,
+    buffer: *mut u8,
+    buffer_len: size_t,
+    error_str: *mut *const c_char,
+) -> c_int {
+    unsafe {
+        let result = (|| {
+            let key = key_from_ptr(key_bytes_protected, "key_bytes_protected")?;
+            let key_len = key.len();
+            if buffer.is_null() || buffer_len < key_len {
+                return Err(Error::new(
+                    format!(
+                        "Buffer of {buffer_len} bytes can not hold key of {key_len} bytes"
+                    ),
+                    InvalidArgument,
+                    None,
+                ));
+            }
+            std::slice::from_raw_parts_mut(buffer, key_len)
+                .copy_from_slice(&key.borrow());
+            Ok(())
+        })();
+        c_result(result, error_str)
+    }
+}
+
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn e14_free_error_str(error_str: *mut *const c_char) {
     unsafe {
@@ -8,6 +8,24 @@ pub enum ErrorType {
     InvalidArgument,
     ConnectionError,
     InvalidResponse,
+    /// A key ledger refused a key that was retrieved or used before.
+    KeyReuse,
+    /// Reading or writing a key ledger failed.
+    LedgerError,
+    /// Writing an audit record failed, or an audit log failed verification.
+    AuditError,
+    /// Reading or writing a key cache failed, or it does not contain enough keys.
+    KeyCacheError,
+    /// Communicating with `etsi014-agent` failed, or the agent refused or failed a request.
+    AgentError,
+    /// A [`KeyQuota`](crate::KeyQuota) limit refused the request.
+    QuotaExceeded,
+    /// The KME stores too few keys for requests that are not priority requests, see
+    /// [`KeyQuota::reserve`](crate::KeyQuota::reserve).
+    BelowReserve,
+    /// The KME identifies the client as another SAE than expected, see
+    /// [`ETSI014ClientBuilder::verify_source_sae_id`](crate::ETSI014ClientBuilder::verify_source_sae_id).
+    IdentityMismatch,
 }
 #[derive(Debug)]
 pub struct Error {
@@ -41,3 +59,14 @@ impl std::error::Error for Error {
         self.source.as_deref()
     }
 }
+
+/// Describe a JSON error without the value it quotes, which might be key material.
+pub(crate) fn redacted_json_error(e: &serde_json::Error) -> String {
+    let category = match e.classify() {
+        serde_json::error::Category::Io => "I/O error",
+        serde_json::error::Category::Syntax => "syntax error",
+        serde_json::error::Category::Data => "unexpected data",
+        serde_json::error::Category::Eof => "unexpected end of input",
+    };
+    format!("{category} at line {} column {}", e.line(), e.column())
+}
@@ -0,0 +1,142 @@
+use crate::error::ErrorType::InvalidArgument;
+use crate::pkcs12;
+use crate::{CredentialFormat, Error, SaeId};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use x509_parser::extensions::GeneralName;
+
+/// Identity of the client, parsed from its certificate.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct ClientIdentity {
+    /// Distinguished name of the subject, e.g. `CN=sae-1, O=Example`.
+    pub subject: String,
+    pub common_name: Option<String>,
+    /// Subject alternative names as `DNS:name`, `URI:uri`, `IP:address` or `email:address`.
+    pub subject_alt_names: Vec<String>,
+    pub issuer: String,
+    /// Serial number as colon separated hexadecimal bytes.
+    pub serial: String,
+    /// Seconds since the Unix epoch.
+    pub not_before: u64,
+    /// Seconds since the Unix epoch.
+    pub not_after: u64,
+    /// Hexadecimal SHA-256 hash of the DER encoded certificate.
+    pub sha256_fingerprint: String,
+}
+
+fn parse_error(e: impl std::error::Error + 'static) -> Error {
+    Error::new(
+        "Error parsing client certificate".to_string(),
+        InvalidArgument,
+        Some(Box::new(e)),
+    )
+}
+
+fn general_name(name: &GeneralName) -> Option<String> {
+    match name {
+        GeneralName::DNSName(name) => Some(format!("DNS:{name}")),
+        GeneralName::URI(uri) => Some(format!("URI:{uri}")),
+        GeneralName::RFC822Name(address) => Some(format!("email:{address}")),
+        GeneralName::IPAddress(bytes) => {
+            let address = match bytes.len() {
+                4 => IpAddr::from(<[u8; 4]>::try_from(*bytes).ok()?),
+                16 => IpAddr::from(<[u8; 16]>::try_from(*bytes).ok()?),
+                _ => return None,
+            };
+            Some(format!("IP:{address}"))
+        }
+        _ => None,
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl ClientIdentity {
+    /// Parse the first certificate of `cert`, which is the client certificate in a chain.
+    pub fn parse(format: CredentialFormat, cert: &[u8]) -> Result<ClientIdentity, Error> {
+        let pem;
+        let der = match format {
+            CredentialFormat::Pem => {
+                pem = x509_parser::pem::parse_x509_pem(cert)
+                    .map_err(parse_error)?
+                    .1;
+                &pem.contents
+            }
+            CredentialFormat::Der => cert,
+        };
+        let (_, cert) = x509_parser::parse_x509_certificate(der).map_err(parse_error)?;
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_owned);
+        let subject_alt_names = cert
+            .subject_alternative_name()
+            .map_err(parse_error)?
+            .map(|san| {
+                san.value
+                    .general_names
+                    .iter()
+                    .filter_map(general_name)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let validity = cert.validity();
+        Ok(ClientIdentity {
+            subject: cert.subject().to_string(),
+            common_name,
+            subject_alt_names,
+            issuer: cert.issuer().to_string(),
+            serial: cert.raw_serial_as_string(),
+            not_before: validity.not_before.timestamp().max(0) as u64,
+            not_after: validity.not_after.timestamp().max(0) as u64,
+            sha256_fingerprint: Sha256::digest(der)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect(),
+        })
+    }
+
+    pub fn from_pem(cert: &[u8]) -> Result<ClientIdentity, Error> {
+        Self::parse(CredentialFormat::Pem, cert)
+    }
+
+    pub fn from_der(cert: &[u8]) -> Result<ClientIdentity, Error> {
+        Self::parse(CredentialFormat::Der, cert)
+    }
+
+    /// Parse the client certificate in a PKCS#12 bundle. The passphrase is needed to check
+    /// the integrity of the bundle and decrypt the certificates in it.
+    pub fn from_pkcs12(
+        pkcs12: &[u8],
+        passphrase: &[u8],
+    ) -> Result<ClientIdentity, Error> {
+        let (chain, _) = pkcs12::parse(pkcs12, passphrase)?;
+        Self::from_pem(&chain)
+    }
+
+    /// The common name, which identifies the SAE. `None` if there is no common name or it
+    /// is not a valid SAE ID.
+    pub fn sae_id(&self) -> Option<SaeId> {
+        SaeId::new(self.common_name.as_deref()?).ok()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.not_after <= now()
+    }
+
+    /// Time until the certificate expires, `None` if it has expired.
+    pub fn expires_in(&self) -> Option<Duration> {
+        self.not_after
+            .checked_sub(now())
+            .filter(|seconds| *seconds > 0)
+            .map(Duration::from_secs)
+    }
+}
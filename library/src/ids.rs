@@ -0,0 +1,172 @@
+//! Identifiers of SAEs, KMEs and keys. They are distinct types so they cannot be swapped,
+//! and are validated when they are created.
+
+use crate::Error;
+use crate::error::ErrorType::InvalidArgument;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, PercentEncode, utf8_percent_encode};
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+/// Everything but the unreserved characters of RFC 3986. The `url` crate leaves
+/// sub-delimiters such as `;` and `=` unencoded, which some servers treat as path
+/// parameters.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Check that `id` can be used as a path segment of a request URL, after percent-encoding.
+fn validate(id: &str, what: &str) -> Result<(), Error> {
+    let problem = if id.is_empty() {
+        "is empty"
+    } else if id == "." || id == ".." {
+        "is a relative path"
+    } else if id.chars().any(char::is_control) {
+        "contains control characters"
+    } else {
+        return Ok(());
+    };
+    Err(Error::new(
+        format!("Invalid {what} {id:?}: {problem}"),
+        InvalidArgument,
+        None,
+    ))
+}
+
+macro_rules! identifier {
+    ($(#[$meta:meta])* $name:ident, $what:literal) => {
+        $(#[$meta])*
+        #[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+        #[serde(try_from = "String", into = "String")]
+        pub struct $name(String);
+
+        impl $name {
+            pub fn new(id: impl Into<String>) -> Result<$name, Error> {
+                let id = id.into();
+                validate(&id, $what)?;
+                Ok($name(id))
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Borrow<str> for $name {
+            fn borrow(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Debug::fmt(&self.0, f)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Error;
+
+            fn from_str(id: &str) -> Result<$name, Error> {
+                $name::new(id)
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = Error;
+
+            fn try_from(id: String) -> Result<$name, Error> {
+                $name::new(id)
+            }
+        }
+
+        impl TryFrom<&str> for $name {
+            type Error = Error;
+
+            fn try_from(id: &str) -> Result<$name, Error> {
+                $name::new(id)
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(id: $name) -> String {
+                id.0
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl PartialEq<&str> for $name {
+            fn eq(&self, other: &&str) -> bool {
+                self.0 == *other
+            }
+        }
+    };
+}
+
+identifier!(
+    /// ID of a Secure Application Entity, used in request URLs.
+    SaeId,
+    "SAE ID"
+);
+
+impl SaeId {
+    /// The SAE ID percent-encoded as a path segment of a request URL.
+    pub(crate) fn path_segment(&self) -> PercentEncode<'_> {
+        utf8_percent_encode(&self.0, PATH_SEGMENT)
+    }
+}
+
+identifier!(
+    /// ID of a Key Management Entity.
+    KmeId,
+    "KME ID"
+);
+
+identifier!(
+    /// ID of a key, usually a UUID.
+    KeyId,
+    "key ID"
+);
+
+#[cfg(feature = "uuid")]
+impl KeyId {
+    /// Parse the key ID as a UUID, which the KME should use, but is not required to.
+    pub fn uuid(&self) -> Result<uuid::Uuid, Error> {
+        uuid::Uuid::parse_str(&self.0).map_err(|e| {
+            Error::new(
+                format!("Key ID {} is not a UUID", self.0),
+                InvalidArgument,
+                Some(Box::new(e)),
+            )
+        })
+    }
+}
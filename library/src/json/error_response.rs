@@ -0,0 +1,7 @@
+use serde::Deserialize;
+
+/// Error body of unsuccessful responses. Only the message is used.
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct ErrorResponse {
+    pub message: String,
+}
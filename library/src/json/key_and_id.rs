@@ -1,8 +1,12 @@
+use crate::KeyId;
+use secrets::SecretVec;
 use serde::Deserialize;
 
-#[derive(Debug, PartialEq, Eq, Clone, Deserialize)]
+#[derive(Debug, Deserialize)]
 pub struct KeyAndId {
     #[serde(rename(deserialize = "key_ID"))]
-    pub key_id: String,
-    pub key: String,
+    pub key_id: KeyId,
+    /// Base64 encoded key
+    #[serde(with = "crate::json::secret_string")]
+    pub key: SecretVec<u8>,
 }
@@ -1,7 +1,7 @@
 use crate::json::key_and_id::KeyAndId;
 use serde::Deserialize;
 
-#[derive(Debug, PartialEq, Eq, Clone, Deserialize)]
+#[derive(Debug, Deserialize)]
 pub struct KeyContainer {
     pub keys: Vec<KeyAndId>,
 }
@@ -1,7 +1,8 @@
 use serde::Serialize;
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize)]
-pub struct KeyId<'a> {
+/// Key ID object of a `dec_keys` request.
+pub struct KeyIdRef<'a> {
     #[serde(rename(serialize = "key_ID"))]
     pub key_id: &'a str,
 }
@@ -1,3 +1,4 @@
+use crate::SaeId;
 use serde::Serialize;
 use serde_json::{Map, Value};
 
@@ -7,7 +8,7 @@ pub struct KeyRequest<'a> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub size: Option<u32>,
     #[serde(rename(deserialize = "additional_slave_SAE_IDs"))]
-    pub additional_target_sae_ids: &'a [&'a str],
+    pub additional_target_sae_ids: &'a [SaeId],
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extension_mandatory: Option<&'a [Map<String, Value>]>,
 }
@@ -1,6 +1,8 @@
+pub mod error_response;
 pub mod key_and_id;
 pub mod key_container;
 pub mod key_id;
 pub mod key_request;
 pub mod keys_by_ids_request;
+pub mod secret_string;
 pub mod status_response;
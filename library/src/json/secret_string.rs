@@ -0,0 +1,67 @@
+use secrets::SecretVec;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use serde_json::value::RawValue;
+
+/// Deserialize a JSON string into protected memory. The raw string is borrowed from the
+/// input, so escapes are decoded here instead of in a temporary buffer of `serde_json`.
+/// Only works when deserializing from a slice or `str`.
+pub fn deserialize<'de, D>(deserializer: D) -> Result<SecretVec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw: &'de RawValue = Deserialize::deserialize(deserializer)?;
+    let content = raw
+        .get()
+        .as_bytes()
+        .strip_prefix(b"\"")
+        .and_then(|r| r.strip_suffix(b"\""))
+        .ok_or_else(|| D::Error::custom("expected a string"))?;
+    let mut decoded = SecretVec::<u8>::zero(content.len());
+    let len = unescape(content, &mut decoded.borrow_mut()).map_err(D::Error::custom)?;
+    if len == content.len() {
+        return Ok(decoded);
+    }
+    let decoded = decoded.borrow();
+    Ok(SecretVec::new(len, |s| s.copy_from_slice(&decoded[..len])))
+}
+
+/// Decode the escapes of a JSON string validated by `serde_json` into `out`, returning the
+/// length. Only ASCII is supported, which is all that base64 needs.
+fn unescape(content: &[u8], out: &mut [u8]) -> Result<usize, &'static str> {
+    let mut len = 0;
+    let mut i = 0;
+    while i < content.len() {
+        let byte = match content[i] {
+            b'\\' => {
+                i += 1;
+                match content.get(i) {
+                    Some(b'"') => b'"',
+                    Some(b'\\') => b'\\',
+                    Some(b'/') => b'/',
+                    Some(b'b') => 0x08,
+                    Some(b'f') => 0x0c,
+                    Some(b'n') => b'\n',
+                    Some(b'r') => b'\r',
+                    Some(b't') => b'\t',
+                    Some(b'u') => {
+                        let code = content
+                            .get(i + 1..i + 5)
+                            .and_then(|hex| std::str::from_utf8(hex).ok())
+                            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                            .filter(|code| code.is_ascii())
+                            .ok_or("unsupported escape in string")?;
+                        i += 4;
+                        code
+                    }
+                    _ => return Err("invalid escape in string"),
+                }
+            }
+            byte => byte,
+        };
+        out[len] = byte;
+        len += 1;
+        i += 1;
+    }
+    Ok(len)
+}
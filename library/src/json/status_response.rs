@@ -1,21 +1,59 @@
-use serde::Deserialize;
+use crate::status::Status;
+use crate::{KmeId, SaeId};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq, Eq, Clone, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct StatusResponse {
-    #[serde(rename(deserialize = "source_KME_ID"))]
-    pub source_kme_id: String,
-    #[serde(rename(deserialize = "target_KME_ID"))]
-    pub target_kme_id: String,
-    #[serde(rename(deserialize = "master_SAE_ID"))]
-    pub source_sae_id: String,
-    #[serde(rename(deserialize = "slave_SAE_ID"))]
-    pub target_sae_id: String,
+    #[serde(rename = "source_KME_ID")]
+    pub source_kme_id: KmeId,
+    #[serde(rename = "target_KME_ID")]
+    pub target_kme_id: KmeId,
+    #[serde(rename = "master_SAE_ID")]
+    pub source_sae_id: SaeId,
+    #[serde(rename = "slave_SAE_ID")]
+    pub target_sae_id: SaeId,
     pub key_size: u32,
     pub stored_key_count: u32,
     pub max_key_count: u32,
     pub max_key_per_request: u32,
     pub max_key_size: u32,
     pub min_key_size: u32,
-    #[serde(rename(deserialize = "max_SAE_ID_count"))]
+    #[serde(rename = "max_SAE_ID_count")]
     pub max_sae_id_count: u32,
 }
+
+impl From<StatusResponse> for Status {
+    fn from(sr: StatusResponse) -> Status {
+        Status {
+            source_kme_id: sr.source_kme_id,
+            target_kme_id: sr.target_kme_id,
+            source_sae_id: sr.source_sae_id,
+            target_sae_id: sr.target_sae_id,
+            key_size: sr.key_size,
+            stored_key_count: sr.stored_key_count,
+            max_key_count: sr.max_key_count,
+            max_key_per_request: sr.max_key_per_request,
+            max_key_size: sr.max_key_size,
+            min_key_size: sr.min_key_size,
+            max_sae_id_count: sr.max_sae_id_count,
+        }
+    }
+}
+
+impl From<Status> for StatusResponse {
+    fn from(s: Status) -> StatusResponse {
+        StatusResponse {
+            source_kme_id: s.source_kme_id,
+            target_kme_id: s.target_kme_id,
+            source_sae_id: s.source_sae_id,
+            target_sae_id: s.target_sae_id,
+            key_size: s.key_size,
+            stored_key_count: s.stored_key_count,
+            max_key_count: s.max_key_count,
+            max_key_per_request: s.max_key_per_request,
+            max_key_size: s.max_key_size,
+            min_key_size: s.min_key_size,
+            max_sae_id_count: s.max_sae_id_count,
+        }
+    }
+}
@@ -0,0 +1,643 @@
+use crate::Error;
+use crate::error::ErrorType::KeyCacheError;
+use crate::ids::{KeyId, SaeId};
+use crate::utils::{FileLock, create_new_private, read_or_create_secret};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{Key, Tag, XChaCha20Poly1305, XNonce};
+use secrets::SecretVec;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use zeroize::Zeroize;
+
+const SEALING_KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+const SALT_LEN: usize = 16;
+
+/// Where the key that encrypts cached keys comes from.
+pub enum SealingKeySource {
+    /// Entry in the OS keyring, which is created with a random key if it does not exist.
+    /// Requires the `keyring` feature.
+    Keyring { service: String, user: String },
+    /// File containing 32 random bytes, which is created if it does not exist. Does not
+    /// depend on a keyring or TPM, but the file must be protected like the cache itself.
+    File(PathBuf),
+    /// Passphrase stretched with Argon2id and a random salt stored in the cache directory.
+    Passphrase(SecretVec<u8>),
+}
+
+impl fmt::Debug for SealingKeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SealingKeySource::Keyring { service, user } => f
+                .debug_struct("Keyring")
+                .field("service", service)
+                .field("user", user)
+                .finish(),
+            SealingKeySource::File(path) => f.debug_tuple("File").field(path).finish(),
+            SealingKeySource::Passphrase(_) => f.write_str("Passphrase"),
+        }
+    }
+}
+
+/// Metadata of a cached key, stored unencrypted but authenticated next to the key.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct CachedKeyInfo {
+    #[serde(rename = "key_ID")]
+    pub key_id: KeyId,
+    #[serde(rename = "target_SAE_ID")]
+    pub target_sae_id: SaeId,
+    #[serde(rename = "additional_target_SAE_IDs", default)]
+    pub additional_target_sae_ids: Vec<SaeId>,
+    pub key_size_bits: u32,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    /// Seconds since the Unix epoch, after which the key is no longer handed out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+impl CachedKeyInfo {
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= now())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn cache_error(msg: String, e: impl std::error::Error + 'static) -> Error {
+    Error::new(msg, KeyCacheError, Some(Box::new(e)))
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_secret(service: &str, user: &str) -> Result<SecretVec<u8>, Error> {
+    let keyring_error =
+        |e| cache_error(format!("Error accessing keyring entry {service}/{user}"), e);
+    let entry = keyring::Entry::new(service, user).map_err(keyring_error)?;
+    match entry.get_secret() {
+        Ok(mut stored) => {
+            let secret = (stored.len() == SEALING_KEY_LEN)
+                .then(|| SecretVec::new(SEALING_KEY_LEN, |s| s.copy_from_slice(&stored)));
+            stored.zeroize();
+            secret.ok_or_else(|| {
+                Error::new(
+                    format!("Keyring entry {service}/{user} is not a sealing key"),
+                    KeyCacheError,
+                    None,
+                )
+            })
+        }
+        Err(keyring::Error::NoEntry) => {
+            let secret = SecretVec::random(SEALING_KEY_LEN);
+            entry.set_secret(&secret.borrow()).map_err(keyring_error)?;
+            Ok(secret)
+        }
+        Err(e) => Err(keyring_error(e)),
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_secret(_service: &str, _user: &str) -> Result<SecretVec<u8>, Error> {
+    Err(Error::new(
+        "Keyring support requires the keyring feature".to_string(),
+        KeyCacheError,
+        None,
+    ))
+}
+
+/// Encrypted on-disk cache of keys fetched in advance, for when the KME cannot be reached.
+/// Every key is stored in its own file, encrypted with XChaCha20-Poly1305. A key is handed
+/// out only once: its file is overwritten and deleted when it is taken. Overwriting does not
+/// reliably erase data on SSDs and copy-on-write file systems, so the cache directory
+/// should be on an encrypted file system.
+pub struct KeyCache {
+    dir: PathBuf,
+    // SecretVec is not Sync
+    sealing_key: Mutex<SecretVec<u8>>,
+    default_ttl: Option<Duration>,
+}
+
+impl fmt::Debug for KeyCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyCache")
+            .field("dir", &self.dir)
+            .field("default_ttl", &self.default_ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyCache {
+    /// Open the cache in `dir`, creating the directory if it does not exist.
+    pub fn open(dir: &Path, source: SealingKeySource) -> Result<KeyCache, Error> {
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder
+            .create(dir)
+            .map_err(|e| cache_error(format!("Error creating {}", dir.display()), e))?;
+        let sealing_key = match source {
+            SealingKeySource::Keyring { service, user } => {
+                keyring_secret(&service, &user)?
+            }
+            SealingKeySource::File(path) => {
+                read_or_create_secret(&path, SEALING_KEY_LEN, cache_error)?
+            }
+            SealingKeySource::Passphrase(passphrase) => {
+                let salt =
+                    read_or_create_secret(&dir.join("salt"), SALT_LEN, cache_error)?;
+                SecretVec::try_new(SEALING_KEY_LEN, |key| {
+                    Argon2::default().hash_password_into(
+                        &passphrase.borrow(),
+                        &salt.borrow(),
+                        key,
+                    )
+                })
+                .map_err(|e| {
+                    Error::new(
+                        format!("Error deriving sealing key: {e}"),
+                        KeyCacheError,
+                        None,
+                    )
+                })?
+            }
+        };
+        Ok(KeyCache {
+            dir: dir.to_owned(),
+            sealing_key: Mutex::new(sealing_key),
+            default_ttl: None,
+        })
+    }
+
+    /// Time after which inserted keys expire, unless given to [`KeyCache::insert`]. Keys do
+    /// not expire by default.
+    pub fn default_ttl(&mut self, ttl: Option<Duration>) -> &mut Self {
+        self.default_ttl = ttl;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        let sealing_key = self.sealing_key.lock().unwrap_or_else(|e| e.into_inner());
+        XChaCha20Poly1305::new(Key::from_slice(&sealing_key.borrow()))
+    }
+
+    fn lock(&self) -> Result<FileLock, Error> {
+        let path = self.dir.join("lock");
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .and_then(FileLock::exclusive)
+            .map_err(|e| cache_error(format!("Error locking {}", path.display()), e))
+    }
+
+    /// Paths of the key files with their metadata, skipping corrupt files.
+    fn key_files(&self) -> Result<KeyFiles, Error> {
+        let read_error =
+            |e| cache_error(format!("Error reading {}", self.dir.display()), e);
+        let mut files = Vec::new();
+        let mut corrupt = Vec::new();
+        for entry in std::fs::read_dir(&self.dir).map_err(read_error)? {
+            let path = entry.map_err(read_error)?.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("key") => match read_key_file(&path)? {
+                    (Some(info), _) => files.push((path, info)),
+                    (None, _) => {
+                        warn!(path = %path.display(), "Skipping corrupt cached key");
+                        corrupt.push(path);
+                    }
+                },
+                Some("tmp") => corrupt.push(path),
+                _ => {}
+            }
+        }
+        files.sort_by_key(|(_, info)| info.created);
+        Ok(KeyFiles { files, corrupt })
+    }
+
+    /// Store keys for `target_sae_id` that may also be shared with
+    /// `additional_target_sae_ids`, expiring after `ttl` or the default TTL.
+    pub fn insert(
+        &self,
+        target_sae_id: &SaeId,
+        additional_target_sae_ids: &[SaeId],
+        keys: &[(KeyId, SecretVec<u8>)],
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        let created = now();
+        let expires = ttl.or(self.default_ttl).map(|ttl| created + ttl.as_secs());
+        let cipher = self.cipher();
+        for (key_id, key) in keys {
+            let info = CachedKeyInfo {
+                key_id: key_id.clone(),
+                target_sae_id: target_sae_id.clone(),
+                additional_target_sae_ids: additional_target_sae_ids.to_vec(),
+                key_size_bits: key.len() as u32 * 8,
+                created,
+                expires,
+            };
+            let mut header =
+                serde_json::to_vec(&info).expect("Error serializing cached key info");
+            let nonce = random_bytes(NONCE_LEN);
+            let mut sealed = key.clone();
+            let tag = cipher
+                .encrypt_in_place_detached(
+                    XNonce::from_slice(&nonce),
+                    &header,
+                    &mut sealed.borrow_mut(),
+                )
+                .map_err(|_| {
+                    Error::new(
+                        format!("Error encrypting key {key_id}"),
+                        KeyCacheError,
+                        None,
+                    )
+                })?;
+            header.push(b'\n');
+            header.extend_from_slice(&nonce);
+            header.extend_from_slice(&tag);
+            header.extend_from_slice(&sealed.borrow());
+            // Written to a temporary file first, so a crash never leaves a partial key
+            let name = hex_string(&random_bytes(16));
+            let temp_path = self.dir.join(format!("{name}.tmp"));
+            let path = self.dir.join(format!("{name}.key"));
+            let write_error =
+                |e| cache_error(format!("Error writing {}", path.display()), e);
+            let mut file = create_new_private(&temp_path).map_err(write_error)?;
+            file.write_all(&header)
+                .and_then(|_| file.sync_all())
+                .and_then(|_| std::fs::rename(&temp_path, &path))
+                .map_err(write_error)?;
+        }
+        sync_dir(&self.dir)
+    }
+
+    /// Metadata of all cached keys, oldest first.
+    pub fn entries(&self) -> Result<Vec<CachedKeyInfo>, Error> {
+        let _lock = self.lock()?;
+        Ok(self
+            .key_files()?
+            .files
+            .into_iter()
+            .map(|(_, info)| info)
+            .collect())
+    }
+
+    /// Take `amount` unexpired keys of `key_size_bits` for exactly the given SAEs, oldest
+    /// first. The keys are deleted from the cache. Fails without taking any key if there are
+    /// not enough.
+    pub fn take(
+        &self,
+        key_size_bits: u32,
+        target_sae_id: &SaeId,
+        additional_target_sae_ids: &[SaeId],
+        amount_of_keys: u32,
+    ) -> Result<Vec<(KeyId, SecretVec<u8>)>, Error> {
+        let _lock = self.lock()?;
+        let mut additional = additional_target_sae_ids.to_vec();
+        additional.sort_unstable();
+        let matching = self
+            .key_files()?
+            .files
+            .into_iter()
+            .filter(|(_, info)| {
+                let mut info_additional = info.additional_target_sae_ids.clone();
+                info_additional.sort_unstable();
+                info.target_sae_id == *target_sae_id
+                    && info.key_size_bits == key_size_bits
+                    && info_additional == additional
+                    && !info.is_expired()
+            })
+            .take(amount_of_keys as usize)
+            .collect::<Vec<_>>();
+        if matching.len() < amount_of_keys as usize {
+            return Err(Error::new(
+                format!(
+                    "Not enough cached keys for {target_sae_id}: {} of {amount_of_keys}",
+                    matching.len()
+                ),
+                KeyCacheError,
+                None,
+            ));
+        }
+        let cipher = self.cipher();
+        let mut keys = Vec::with_capacity(matching.len());
+        for (path, info) in matching {
+            let (_, mut content) = read_key_file(&path)?;
+            let header_len = content.iter().position(|b| *b == b'\n').unwrap_or(0);
+            let sealed = &content[header_len + 1..];
+            let invalid = || {
+                Error::new(
+                    format!(
+                        "Error decrypting {}, wrong sealing key or corrupted",
+                        path.display()
+                    ),
+                    KeyCacheError,
+                    None,
+                )
+            };
+            if sealed.len() < NONCE_LEN + TAG_LEN {
+                return Err(invalid());
+            }
+            let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+            let (tag, ciphertext) = sealed.split_at(TAG_LEN);
+            let key = SecretVec::try_new(ciphertext.len(), |key| {
+                key.copy_from_slice(ciphertext);
+                cipher.decrypt_in_place_detached(
+                    XNonce::from_slice(nonce),
+                    &content[..header_len],
+                    key,
+                    Tag::from_slice(tag),
+                )
+            })
+            .map_err(|_| invalid())?;
+            content.zeroize();
+            keys.push((path, info.key_id, key));
+        }
+        // Only delete keys once all of them could be decrypted
+        for (path, _, _) in &keys {
+            erase(path)?;
+        }
+        sync_dir(&self.dir)?;
+        let keys = keys
+            .into_iter()
+            .map(|(_, key_id, key)| (key_id, key))
+            .collect();
+        Ok(keys)
+    }
+
+    /// Delete all keys, or only expired keys. Returns the number of keys deleted. Corrupt
+    /// key files are always deleted.
+    pub fn purge(&self, expired_only: bool) -> Result<usize, Error> {
+        let _lock = self.lock()?;
+        let KeyFiles { files, corrupt } = self.key_files()?;
+        for path in corrupt {
+            erase(&path)?;
+        }
+        let mut purged = 0;
+        for (path, info) in files {
+            if !expired_only || info.is_expired() {
+                erase(&path)?;
+                purged += 1;
+            }
+        }
+        sync_dir(&self.dir)?;
+        Ok(purged)
+    }
+}
+
+struct KeyFiles {
+    files: Vec<(PathBuf, CachedKeyInfo)>,
+    /// Files left by an interrupted write or with an invalid header.
+    corrupt: Vec<PathBuf>,
+}
+
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Metadata and content of a key file, without metadata if its header is invalid.
+fn read_key_file(path: &Path) -> Result<(Option<CachedKeyInfo>, Vec<u8>), Error> {
+    let content = std::fs::read(path)
+        .map_err(|e| cache_error(format!("Error reading {}", path.display()), e))?;
+    let header_len = content
+        .iter()
+        .position(|b| *b == b'\n')
+        .unwrap_or(content.len());
+    let info = serde_json::from_slice(&content[..header_len]).ok();
+    Ok((info, content))
+}
+
+/// Overwrite the file with zeros before deleting it.
+fn erase(path: &Path) -> Result<(), Error> {
+    let erase_error = |e| cache_error(format!("Error deleting {}", path.display()), e);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(erase_error)?;
+    let len = file.metadata().map_err(erase_error)?.len();
+    file.write_all(&vec![0; len as usize])
+        .and_then(|_| file.sync_all())
+        .map_err(erase_error)?;
+    drop(file);
+    std::fs::remove_file(path).map_err(erase_error)
+}
+
+fn sync_dir(dir: &Path) -> Result<(), Error> {
+    #[cfg(unix)]
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| cache_error(format!("Error syncing {}", dir.display()), e))?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn temp_path(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!(
+            "etsi014-key-cache-{}-{n}-{name}",
+            std::process::id()
+        ))
+    }
+
+    fn open(dir: &Path, sealing_key: &Path) -> KeyCache {
+        KeyCache::open(dir, SealingKeySource::File(sealing_key.to_owned())).unwrap()
+    }
+
+    fn sae(id: &str) -> SaeId {
+        SaeId::new(id).unwrap()
+    }
+
+    fn keys(ids: &[&str]) -> Vec<(KeyId, SecretVec<u8>)> {
+        ids.iter()
+            .enumerate()
+            .map(|(i, id)| {
+                let key = SecretVec::new(32, |key| key.fill(i as u8 + 1));
+                (KeyId::new(*id).unwrap(), key)
+            })
+            .collect()
+    }
+
+    fn key_ids(keys: &[(KeyId, SecretVec<u8>)]) -> Vec<String> {
+        keys.iter().map(|(id, _)| id.to_string()).collect()
+    }
+
+    fn key_files(dir: &Path) -> Vec<PathBuf> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|e| e == "key"))
+            .collect()
+    }
+
+    #[test]
+    fn keys_are_taken_once() {
+        let dir = temp_path("cache");
+        let cache = open(&dir, &temp_path("sealing-key"));
+        let target = sae("client-2");
+        cache
+            .insert(&target, &[], &keys(&["a", "b"]), None)
+            .unwrap();
+        // Keys created in the same second are taken in any order
+        let mut taken = Vec::new();
+        for _ in 0..2 {
+            taken.extend(cache.take(256, &target, &[], 1).unwrap());
+        }
+        taken.sort_by_key(|(id, _)| id.to_string());
+        assert_eq!(key_ids(&taken), ["a", "b"]);
+        assert_eq!(*taken[0].1.borrow(), [1; 32]);
+        assert_eq!(*taken[1].1.borrow(), [2; 32]);
+        assert!(cache.take(256, &target, &[], 1).is_err());
+        assert!(cache.entries().unwrap().is_empty());
+        assert!(key_files(&dir).is_empty());
+    }
+
+    #[test]
+    fn keys_are_matched_by_size_and_saes() {
+        let cache = open(&temp_path("cache"), &temp_path("sealing-key"));
+        let additional = [sae("client-3"), sae("client-4")];
+        cache
+            .insert(&sae("client-2"), &additional, &keys(&["a"]), None)
+            .unwrap();
+        assert!(cache.take(256, &sae("client-2"), &[], 1).is_err());
+        assert!(cache.take(128, &sae("client-2"), &additional, 1).is_err());
+        assert!(cache.take(256, &sae("client-3"), &additional, 1).is_err());
+        let reversed = [sae("client-4"), sae("client-3")];
+        let taken = cache.take(256, &sae("client-2"), &reversed, 1).unwrap();
+        assert_eq!(key_ids(&taken), ["a"]);
+    }
+
+    #[test]
+    fn expired_keys_are_not_taken() {
+        let cache = open(&temp_path("cache"), &temp_path("sealing-key"));
+        let target = sae("client-2");
+        cache
+            .insert(&target, &[], &keys(&["a"]), Some(Duration::ZERO))
+            .unwrap();
+        assert!(cache.entries().unwrap()[0].is_expired());
+        let e = cache.take(256, &target, &[], 1).unwrap_err();
+        assert_eq!(e.msg, "Not enough cached keys for client-2: 0 of 1");
+    }
+
+    #[test]
+    fn nothing_is_taken_without_enough_keys() {
+        let cache = open(&temp_path("cache"), &temp_path("sealing-key"));
+        let target = sae("client-2");
+        cache
+            .insert(&target, &[], &keys(&["a", "b"]), None)
+            .unwrap();
+        let e = cache.take(256, &target, &[], 3).unwrap_err();
+        assert_eq!(e.msg, "Not enough cached keys for client-2: 2 of 3");
+        assert_eq!(cache.entries().unwrap().len(), 2);
+        assert_eq!(cache.take(256, &target, &[], 2).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn wrong_sealing_key_fails_decryption() {
+        let dir = temp_path("cache");
+        let cache = open(&dir, &temp_path("sealing-key"));
+        let target = sae("client-2");
+        cache.insert(&target, &[], &keys(&["a"]), None).unwrap();
+        let other = open(&dir, &temp_path("other-sealing-key"));
+        let e = other.take(256, &target, &[], 1).unwrap_err();
+        assert!(
+            e.msg.contains("wrong sealing key or corrupted"),
+            "{}",
+            e.msg
+        );
+        // The key is kept for the right sealing key
+        assert_eq!(key_ids(&cache.take(256, &target, &[], 1).unwrap()), ["a"]);
+    }
+
+    #[test]
+    fn changed_header_fails_decryption() {
+        let dir = temp_path("cache");
+        let cache = open(&dir, &temp_path("sealing-key"));
+        let target = sae("client-2");
+        cache
+            .insert(&target, &[], &keys(&["a"]), Some(Duration::from_secs(60)))
+            .unwrap();
+        let path = &key_files(&dir)[0];
+        let content = fs::read(path).unwrap();
+        let expires = cache.entries().unwrap()[0].expires.unwrap();
+        let changed = String::from_utf8_lossy(&content)
+            .replace(&expires.to_string(), &(expires + 3600).to_string());
+        fs::write(path, changed.as_bytes()).unwrap();
+        let e = cache.take(256, &target, &[], 1).unwrap_err();
+        assert!(
+            e.msg.contains("wrong sealing key or corrupted"),
+            "{}",
+            e.msg
+        );
+    }
+
+    #[test]
+    fn corrupt_files_are_skipped_and_purged() {
+        let dir = temp_path("cache");
+        let cache = open(&dir, &temp_path("sealing-key"));
+        let target = sae("client-2");
+        cache.insert(&target, &[], &keys(&["a"]), None).unwrap();
+        fs::write(dir.join("truncated.key"), b"{\"key_ID\":\"b\",\"tar").unwrap();
+        fs::write(dir.join("interrupted.tmp"), b"{").unwrap();
+        assert_eq!(cache.entries().unwrap().len(), 1);
+        assert_eq!(cache.purge(true).unwrap(), 0);
+        assert!(!dir.join("truncated.key").exists());
+        assert!(!dir.join("interrupted.tmp").exists());
+        assert_eq!(key_ids(&cache.take(256, &target, &[], 1).unwrap()), ["a"]);
+    }
+
+    #[test]
+    fn purge_removes_expired_keys() {
+        let cache = open(&temp_path("cache"), &temp_path("sealing-key"));
+        let target = sae("client-2");
+        cache
+            .insert(&target, &[], &keys(&["a"]), Some(Duration::ZERO))
+            .unwrap();
+        cache
+            .insert(&target, &[], &keys(&["b"]), Some(Duration::from_secs(60)))
+            .unwrap();
+        cache.insert(&target, &[], &keys(&["c"]), None).unwrap();
+        assert_eq!(cache.purge(true).unwrap(), 1);
+        let ids = cache.entries().unwrap().into_iter();
+        let mut ids = ids.map(|info| info.key_id.to_string()).collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, ["b", "c"]);
+        assert_eq!(cache.purge(false).unwrap(), 2);
+        assert!(cache.entries().unwrap().is_empty());
+    }
+}
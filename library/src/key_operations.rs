@@ -0,0 +1,110 @@
+use crate::Error;
+use crate::error::ErrorType::InvalidArgument;
+use hkdf::Hkdf;
+use secrets::SecretVec;
+use sha2::Sha256;
+
+/// Derive a subkey of `length` bytes from a qkd key using HKDF-SHA256. Different `info` values
+/// give independent subkeys of the same qkd key.
+pub fn derive_key(
+    key: &SecretVec<u8>,
+    salt: &[u8],
+    info: &[u8],
+    length: usize,
+) -> Result<SecretVec<u8>, Error> {
+    let hkdf = Hkdf::<Sha256>::new(Some(salt), &key.borrow());
+    SecretVec::try_new(length, |derived| hkdf.expand(info, derived)).map_err(|_| {
+        Error::new(
+            format!(
+                "Can not derive key of {length} bytes, at most 8160 bytes are supported"
+            ),
+            InvalidArgument,
+            None,
+        )
+    })
+}
+
+/// Combine two keys of equal length with XOR, e.g. a qkd key with a post-quantum key.
+pub fn xor_keys(a: &SecretVec<u8>, b: &SecretVec<u8>) -> Result<SecretVec<u8>, Error> {
+    let (a_len, b_len) = (a.len(), b.len());
+    if a_len != b_len {
+        return Err(Error::new(
+            format!("Can not XOR keys of different length ({a_len} and {b_len} bytes)"),
+            InvalidArgument,
+            None,
+        ));
+    }
+    let (a, b) = (a.borrow(), b.borrow());
+    Ok(SecretVec::new(a_len, |combined| {
+        for (c, (x, y)) in combined.iter_mut().zip(a.iter().zip(b.iter())) {
+            *c = x ^ y;
+        }
+    }))
+}
+
+/// Compare keys in constant time. Keys of different length are never equal.
+pub fn keys_equal(a: &SecretVec<u8>, b: &SecretVec<u8>) -> bool {
+    a == b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret(bytes: &[u8]) -> SecretVec<u8> {
+        SecretVec::new(bytes.len(), |s| s.copy_from_slice(bytes))
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn derive_key_matches_rfc_5869() {
+        // Test case 1 of RFC 5869 appendix A
+        let key = secret(&[0x0b; 22]);
+        let salt = hex("000102030405060708090a0b0c");
+        let info = hex("f0f1f2f3f4f5f6f7f8f9");
+        let derived = derive_key(&key, &salt, &info, 42).unwrap();
+        let expected = hex(
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf\
+             34007208d5b887185865",
+        );
+        assert_eq!(*derived.borrow(), *expected);
+    }
+
+    #[test]
+    fn derive_key_is_limited_to_8160_bytes() {
+        let key = secret(&[1; 32]);
+        assert_eq!(derive_key(&key, b"", b"", 8160).unwrap().len(), 8160);
+        let e = derive_key(&key, b"", b"", 8161).unwrap_err();
+        assert_eq!(
+            e.msg,
+            "Can not derive key of 8161 bytes, at most 8160 bytes are supported"
+        );
+        assert!(matches!(e.kind, InvalidArgument));
+    }
+
+    #[test]
+    fn xor_keys_combines_keys_of_equal_length() {
+        let combined = xor_keys(&secret(&[0x0f, 0xff]), &secret(&[0xff, 0x0f])).unwrap();
+        assert_eq!(*combined.borrow(), [0xf0, 0xf0]);
+        let e = xor_keys(&secret(&[1; 32]), &secret(&[1; 16])).unwrap_err();
+        assert_eq!(
+            e.msg,
+            "Can not XOR keys of different length (32 and 16 bytes)"
+        );
+        assert!(matches!(e.kind, InvalidArgument));
+    }
+
+    #[test]
+    fn keys_equal_compares_content_and_length() {
+        assert!(keys_equal(&secret(&[1, 2, 3]), &secret(&[1, 2, 3])));
+        assert!(!keys_equal(&secret(&[1, 2, 3]), &secret(&[1, 2, 4])));
+        assert!(!keys_equal(&secret(&[1, 2, 3]), &secret(&[1, 2])));
+        assert!(!keys_equal(&secret(&[1, 2]), &secret(&[1, 2, 3])));
+    }
+}
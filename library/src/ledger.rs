@@ -0,0 +1,366 @@
+use crate::Error;
+use crate::error::ErrorType::{KeyReuse, LedgerError};
+use crate::utils::FileLock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerEvent {
+    /// The key was received from the KME.
+    Retrieved,
+    /// The key was used for a purpose, see [`KeyLedger::record_use`].
+    Used,
+}
+
+/// Line of the ledger file.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub event: LedgerEvent,
+    #[serde(rename = "key_ID")]
+    pub key_id: String,
+    #[serde(rename = "target_SAE_ID", skip_serializing_if = "Option::is_none")]
+    pub target_sae_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purpose: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consumer: Option<String>,
+}
+
+struct LedgerState {
+    file: File,
+    /// Bytes of the file that have been indexed.
+    offset: u64,
+    retrieved: HashSet<String>,
+    used: HashSet<String>,
+}
+
+/// Append-only file recording every key obtained through a client and every use of a key,
+/// one JSON object per line. Keys that were retrieved or used before are refused, unless
+/// reuse is allowed. The file is locked while it is updated, so several processes can share
+/// a ledger.
+pub struct KeyLedger {
+    path: PathBuf,
+    consumer: Option<String>,
+    allow_reuse: bool,
+    state: Mutex<LedgerState>,
+}
+
+impl fmt::Debug for KeyLedger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyLedger")
+            .field("path", &self.path)
+            .field("consumer", &self.consumer)
+            .field("allow_reuse", &self.allow_reuse)
+            .finish_non_exhaustive()
+    }
+}
+
+fn ledger_error(msg: String, e: std::io::Error) -> Error {
+    Error::new(msg, LedgerError, Some(Box::new(e)))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn parse_entries(path: &Path, content: &[u8]) -> Result<Vec<LedgerEntry>, Error> {
+    content
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            serde_json::from_slice(line).map_err(|e| {
+                Error::new(
+                    format!("Invalid entry in key ledger {}", path.display()),
+                    LedgerError,
+                    Some(Box::new(e)),
+                )
+            })
+        })
+        .collect()
+}
+
+impl LedgerState {
+    /// Index entries appended since the last call, including those of other processes.
+    /// A partially written last line is left for later.
+    fn refresh(&mut self, path: &Path) -> Result<(), Error> {
+        let read_error = |e| ledger_error(format!("Error reading {}", path.display()), e);
+        self.file
+            .seek(SeekFrom::Start(self.offset))
+            .map_err(read_error)?;
+        let mut content = Vec::new();
+        self.file.read_to_end(&mut content).map_err(read_error)?;
+        let complete = content
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .map_or(0, |i| i + 1);
+        for entry in parse_entries(path, &content[..complete])? {
+            match entry.event {
+                LedgerEvent::Retrieved => self.retrieved.insert(entry.key_id),
+                LedgerEvent::Used => self.used.insert(entry.key_id),
+            };
+        }
+        self.offset += complete as u64;
+        Ok(())
+    }
+
+    fn append(&mut self, path: &Path, entries: &[LedgerEntry]) -> Result<(), Error> {
+        let mut lines = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut lines, entry)
+                .expect("Error serializing ledger entry");
+            lines.push(b'\n');
+        }
+        let write_error =
+            |e| ledger_error(format!("Error writing {}", path.display()), e);
+        self.file.write_all(&lines).map_err(write_error)?;
+        self.file.sync_data().map_err(write_error)
+    }
+}
+
+impl KeyLedger {
+    /// Open the ledger at `path`, creating it if it does not exist.
+    pub fn open(path: &Path) -> Result<KeyLedger, Error> {
+        let mut options = OpenOptions::new();
+        options.read(true).append(true).create(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options
+            .open(path)
+            .map_err(|e| ledger_error(format!("Error opening {}", path.display()), e))?;
+        let ledger = KeyLedger {
+            path: path.to_owned(),
+            consumer: None,
+            allow_reuse: false,
+            state: Mutex::new(LedgerState {
+                file,
+                offset: 0,
+                retrieved: HashSet::new(),
+                used: HashSet::new(),
+            }),
+        };
+        ledger.locked(|_| Ok(()))?;
+        Ok(ledger)
+    }
+
+    /// Name of the application or user recorded with every entry.
+    pub fn consumer(&mut self, consumer: &str) -> &mut Self {
+        self.consumer = Some(consumer.to_owned());
+        self
+    }
+
+    /// Record keys that were retrieved or used before instead of refusing them.
+    pub fn allow_reuse(&mut self, allow_reuse: bool) -> &mut Self {
+        self.allow_reuse = allow_reuse;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn locked<R>(
+        &self,
+        f: impl FnOnce(&mut LedgerState) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let _lock = state
+            .file
+            .try_clone()
+            .and_then(FileLock::exclusive)
+            .map_err(|e| {
+                ledger_error(format!("Error locking {}", self.path.display()), e)
+            })?;
+        state.refresh(&self.path)?;
+        f(&mut state)
+    }
+
+    fn entry(
+        &self,
+        event: LedgerEvent,
+        key_id: &str,
+        target_sae_id: Option<&str>,
+        purpose: Option<&str>,
+    ) -> LedgerEntry {
+        LedgerEntry {
+            time: now(),
+            event,
+            key_id: key_id.to_owned(),
+            target_sae_id: target_sae_id.map(str::to_owned),
+            purpose: purpose.map(str::to_owned),
+            consumer: self.consumer.clone(),
+        }
+    }
+
+    fn refuse_reused<'a>(
+        &self,
+        seen: &HashSet<String>,
+        key_ids: impl IntoIterator<Item = &'a str>,
+        action: &str,
+    ) -> Result<(), Error> {
+        if self.allow_reuse {
+            return Ok(());
+        }
+        let reused = key_ids
+            .into_iter()
+            .filter(|key_id| seen.contains(*key_id))
+            .collect::<Vec<_>>();
+        if reused.is_empty() {
+            return Ok(());
+        }
+        Err(Error::new(
+            format!("Keys were {action} before: {}", reused.join(", ")),
+            KeyReuse,
+            None,
+        ))
+    }
+
+    /// Fail if any of the keys was retrieved before.
+    pub(crate) fn check_retrieval(&self, key_ids: &[&str]) -> Result<(), Error> {
+        self.locked(|state| {
+            self.refuse_reused(&state.retrieved, key_ids.iter().copied(), "retrieved")
+        })
+    }
+
+    /// Record that keys were retrieved, failing if any of them was retrieved before.
+    pub(crate) fn record_retrieval(
+        &self,
+        target_sae_id: &str,
+        key_ids: &[&str],
+    ) -> Result<(), Error> {
+        self.locked(|state| {
+            self.refuse_reused(&state.retrieved, key_ids.iter().copied(), "retrieved")?;
+            let entries = key_ids
+                .iter()
+                .map(|key_id| {
+                    self.entry(LedgerEvent::Retrieved, key_id, Some(target_sae_id), None)
+                })
+                .collect::<Vec<_>>();
+            state.append(&self.path, &entries)
+        })
+    }
+
+    /// Record that a key is used for `purpose`. Fails if the key was used before.
+    pub fn record_use(&self, key_id: &str, purpose: &str) -> Result<(), Error> {
+        self.locked(|state| {
+            self.refuse_reused(&state.used, [key_id], "used")?;
+            let entry = self.entry(LedgerEvent::Used, key_id, None, Some(purpose));
+            state.append(&self.path, &[entry])
+        })
+    }
+
+    /// All entries of the ledger, oldest first.
+    pub fn entries(&self) -> Result<Vec<LedgerEntry>, Error> {
+        self.locked(|state| {
+            let read_error =
+                |e| ledger_error(format!("Error reading {}", self.path.display()), e);
+            let mut content = Vec::new();
+            state.file.seek(SeekFrom::Start(0)).map_err(read_error)?;
+            state.file.read_to_end(&mut content).map_err(read_error)?;
+            let complete = content
+                .iter()
+                .rposition(|b| *b == b'\n')
+                .map_or(0, |i| i + 1);
+            parse_entries(&self.path, &content[..complete])
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn temp_path(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir()
+            .join(format!("etsi014-ledger-{}-{n}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn retrieval_is_refused_twice() {
+        let mut ledger = KeyLedger::open(&temp_path("ledger")).unwrap();
+        ledger.record_retrieval("client-2", &["a", "b"]).unwrap();
+        ledger.check_retrieval(&["c"]).unwrap();
+        let e = ledger
+            .record_retrieval("client-2", &["b", "c"])
+            .unwrap_err();
+        assert_eq!(e.msg, "Keys were retrieved before: b");
+        assert!(matches!(e.kind, KeyReuse));
+        assert!(ledger.check_retrieval(&["a"]).is_err());
+        // Nothing is recorded for a refused retrieval
+        ledger.record_retrieval("client-2", &["c"]).unwrap();
+        ledger.allow_reuse(true);
+        ledger.record_retrieval("client-2", &["a"]).unwrap();
+        ledger.check_retrieval(&["a"]).unwrap();
+        assert_eq!(ledger.entries().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn use_is_refused_twice() {
+        let mut ledger = KeyLedger::open(&temp_path("ledger")).unwrap();
+        ledger.consumer("test");
+        ledger.record_retrieval("client-2", &["a"]).unwrap();
+        ledger.record_use("a", "encryption").unwrap();
+        let e = ledger.record_use("a", "encryption").unwrap_err();
+        assert_eq!(e.msg, "Keys were used before: a");
+        assert!(matches!(e.kind, KeyReuse));
+        ledger.allow_reuse(true);
+        ledger.record_use("a", "authentication").unwrap();
+        let entries = ledger.entries().unwrap();
+        let events = entries.iter().map(|entry| entry.event).collect::<Vec<_>>();
+        use LedgerEvent::{Retrieved, Used};
+        assert_eq!(events, [Retrieved, Used, Used]);
+        assert_eq!(entries[1].purpose.as_deref(), Some("encryption"));
+        assert_eq!(entries[2].consumer.as_deref(), Some("test"));
+    }
+
+    #[test]
+    fn entries_of_other_ledgers_are_seen() {
+        let path = temp_path("ledger");
+        let first = KeyLedger::open(&path).unwrap();
+        let second = KeyLedger::open(&path).unwrap();
+        first.record_retrieval("client-2", &["a"]).unwrap();
+        second.record_use("a", "encryption").unwrap();
+        assert!(second.check_retrieval(&["a"]).is_err());
+        assert!(first.record_use("a", "encryption").is_err());
+        assert_eq!(first.entries().unwrap(), second.entries().unwrap());
+        // Entries written before opening are read as well
+        let third = KeyLedger::open(&path).unwrap();
+        assert!(third.record_retrieval("client-2", &["a"]).is_err());
+    }
+
+    #[test]
+    fn partial_line_is_ignored_until_complete() {
+        let path = temp_path("ledger");
+        let ledger = KeyLedger::open(&path).unwrap();
+        ledger.record_retrieval("client-2", &["a"]).unwrap();
+        let line = r#"{"time":0,"event":"retrieved","key_ID":"b"}"#;
+        let (start, end) = line.split_at(20);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(start.as_bytes()).unwrap();
+        ledger.check_retrieval(&["b"]).unwrap();
+        assert_eq!(ledger.entries().unwrap().len(), 1);
+        file.write_all(end.as_bytes()).unwrap();
+        file.write_all(b"\n").unwrap();
+        let e = ledger.check_retrieval(&["b"]).unwrap_err();
+        assert_eq!(e.msg, "Keys were retrieved before: b");
+        assert_eq!(ledger.entries().unwrap().len(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+    }
+}
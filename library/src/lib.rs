@@ -1,224 +1,500 @@
 extern crate core;
 
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("Enable the native-tls or rustls feature to select a TLS backend");
+
+#[cfg(unix)]
+pub mod agent;
+mod audit;
+mod builder;
 mod c;
 mod error;
+mod identity;
+mod ids;
 mod json;
+mod key_cache;
+mod key_operations;
+mod ledger;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+mod pkcs12;
+mod private_key;
+mod quota;
+#[cfg(test)]
+#[path = "../tests/support/sae_ids.rs"]
+mod sae_ids;
+mod secret_buffer;
 mod status;
+mod tls;
 mod utils;
+mod watch;
 
+pub use audit::{
+    AuditLog, AuditOutcome, AuditRecord, AuditSummary, AuditVerification, GENESIS_HASH,
+    verify_audit_log,
+};
+pub use builder::{CredentialFormat, ETSI014ClientBuilder, HttpVersion, TlsVersion};
 pub use error::Error;
 pub use etsi014_client::ETSI014Client;
+pub use identity::ClientIdentity;
+pub use ids::{KeyId, KmeId, SaeId};
+pub use key_cache::{CachedKeyInfo, KeyCache, SealingKeySource};
+pub use key_operations::{derive_key, keys_equal, xor_keys};
+pub use ledger::{KeyLedger, LedgerEntry, LedgerEvent};
+pub use quota::KeyQuota;
 pub use secrets::SecretVec;
 pub use status::Status;
+pub use watch::CredentialWatcher;
 
 pub mod etsi014_client {
-    use crate::Error;
+    use crate::audit::AuditEvent;
     use crate::error::ErrorType::{
-        ConnectionError, InvalidArgument, InvalidHost, InvalidResponse,
+        ConnectionError, IdentityMismatch, InvalidArgument, InvalidResponse,
+        KeyCacheError,
     };
+    use crate::error::redacted_json_error;
+    use crate::json::error_response::ErrorResponse;
     use crate::json::key_container::KeyContainer;
-    use crate::json::key_id::KeyId;
+    use crate::json::key_id::KeyIdRef;
     use crate::json::key_request::KeyRequest;
     use crate::json::keys_by_ids_request::KeysByIdsRequest;
     use crate::json::status_response::StatusResponse;
+    use crate::quota::QuotaTracker;
+    use crate::secret_buffer::SecretBuffer;
     use crate::status::Status;
-    use crate::utils::read_file;
+    use crate::watch::CredentialWatcher;
+    use crate::{
+        AuditLog, ClientIdentity, CredentialFormat, ETSI014ClientBuilder, Error,
+        KeyCache, KeyId, KeyLedger, SaeId,
+    };
     use base64ct::{Base64, Encoding};
     use reqwest::header::CONTENT_TYPE;
-    use reqwest::{Client, Identity, Url};
+    use reqwest::{Client, Request, StatusCode, Url};
     pub use secrets::Secret;
     pub use secrets::SecretBox;
     pub use secrets::SecretVec;
     use serde::de;
-    use std::path::PathBuf;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex, PoisonError, RwLock};
+    use std::time::{Duration, Instant};
+    use tracing::{Span, debug, error, field, info, instrument, warn};
+
+    /// The HTTP client and the identity in its certificate, which are replaced together
+    /// when the credentials are reloaded.
+    #[derive(Debug)]
+    pub(crate) struct ClientState {
+        pub(crate) http_client: Client,
+        pub(crate) base_url: Url,
+        pub(crate) identity: Option<ClientIdentity>,
+        pub(crate) expected_sae_id: Option<SaeId>,
+        pub(crate) identity_verified: AtomicBool,
+    }
 
     #[derive(Debug)]
     pub struct ETSI014Client {
-        http_client: Client,
-        base_url: Url,
+        pub(crate) state: RwLock<Arc<ClientState>>,
+        /// Options the client was built with, to rebuild the state from.
+        pub(crate) options: Mutex<ETSI014ClientBuilder>,
+        pub(crate) path_prefix: String,
+        pub(crate) max_retries: u32,
+        pub(crate) retry_delay: Duration,
+        pub(crate) verbose_diagnostics: bool,
+        pub(crate) ledger: Option<Arc<KeyLedger>>,
+        pub(crate) audit_log: Option<Arc<AuditLog>>,
+        pub(crate) key_cache: Option<Arc<KeyCache>>,
+        pub(crate) quota: Option<QuotaTracker>,
     }
 
-    impl ETSI014Client {
-        const PATH_PREFIX: &'static str = "api/v1/keys";
+    /// Decode a response body, or describe why it could not be decoded. Without `verbose`,
+    /// the error never contains the body, which may contain keys.
+    fn decode_response<T>(
+        response: &[u8],
+        http_code: StatusCode,
+        request_info: &str,
+        verbose: bool,
+    ) -> Result<T, Error>
+    where
+        T: de::DeserializeOwned,
+    {
+        let error_info = |summary: &str, details: &str| {
+            if verbose {
+                format!(
+                    "{summary}{details}\n\n\
+                     HTTP Code: {http_code}\n\
+                     Response:\n{}\n\
+                     Using request: {request_info}",
+                    String::from_utf8_lossy(response)
+                )
+            } else {
+                format!("{summary} for {request_info}{details}")
+            }
+        };
+        if !http_code.is_success() {
+            let message = match serde_json::from_slice::<ErrorResponse>(response) {
+                Ok(error_response) => format!(": {}", error_response.message),
+                Err(_) => "".to_owned(),
+            };
+            return Err(Error::new(
+                error_info(&format!("Unsuccessful HTTP code {http_code}"), &message),
+                InvalidResponse,
+                None,
+            ));
+        }
+        serde_json::from_slice::<T>(response).map_err(|e| {
+            let summary = "Unable to deserialize JSON from response";
+            if verbose {
+                Error::new(error_info(summary, ""), InvalidResponse, Some(Box::new(e)))
+            } else {
+                let details = format!(": {}", redacted_json_error(&e));
+                Error::new(error_info(summary, &details), InvalidResponse, None)
+            }
+        })
+    }
+
+    /// URL of `endpoint` for `target_sae_id`. The SAE ID is percent-encoded, so it cannot
+    /// change the endpoint.
+    fn request_url(
+        base_url: &Url,
+        path_prefix: &str,
+        target_sae_id: &SaeId,
+        endpoint: &str,
+    ) -> Url {
+        let mut url = base_url.clone();
+        let sae_id = target_sae_id.path_segment();
+        url.set_path(&format!("{path_prefix}/{sae_id}/{endpoint}"));
+        url
+    }
 
+    impl ETSI014Client {
         pub fn new(
             host: &str,
             port: u16,
-            cert_path: &PathBuf,
-            key_path: &PathBuf,
-            server_ca_path: &PathBuf,
+            cert_path: &Path,
+            key_path: &Path,
+            server_ca_path: &Path,
         ) -> Result<Self, Error> {
-            // Can not set host and port without parsing something first
-            let mut base_url =
-                Url::parse("https://localhost").expect("Error parsing hardcoded URL");
-            base_url
-                .set_scheme("https")
-                .expect("Error setting https as scheme");
-            base_url.set_host(Some(host)).map_err(|e| {
-                Error::new(
-                    format!("Invalid host: {host}"),
-                    InvalidHost,
-                    Some(Box::new(e)),
+            ETSI014ClientBuilder::new(host, port)
+                .identity_files(
+                    CredentialFormat::Pem,
+                    cert_path.to_path_buf(),
+                    key_path.to_path_buf(),
                 )
-            })?;
-            base_url
-                .set_port(Some(port))
-                // Might fail if host invalid
-                .map_err(|_| {
-                    Error::new(
-                        format!("Error setting port for host: '{host}"),
-                        InvalidHost,
-                        None,
-                    )
-                })?;
-            let server_ca = reqwest::Certificate::from_pem(&read_file(server_ca_path)?)
-                .map_err(|e| {
-                Error::new(
-                    format!("Error parsing {server_ca_path:?}"),
-                    InvalidArgument,
-                    Some(Box::new(e)),
-                )
-            })?;
-            let identity =
-                Identity::from_pkcs8_pem(&read_file(cert_path)?, &read_file(key_path)?)
-                    .map_err(|e| {
-                    Error::new(
-                        format!("Error parsing {cert_path:?} or {key_path:?}"),
-                        InvalidArgument,
-                        Some(Box::new(e)),
-                    )
-                })?;
-            let http_client = Client::builder()
-                .tls_backend_native()
-                .tls_certs_only([server_ca])
-                .identity(identity)
+                .server_ca_file(CredentialFormat::Pem, server_ca_path.to_path_buf())
                 .build()
-                .map_err(|e| {
-                    Error::new(
-                        "Error creating http client".to_string(),
-                        InvalidArgument,
-                        Some(Box::new(e)),
-                    )
-                })?;
-            Ok(ETSI014Client {
-                http_client,
-                base_url,
-            })
         }
 
-        async fn send_request<T>(
+        /// The current state. Requests keep the state they started with, so replacing it
+        /// never interrupts them.
+        pub(crate) fn state(&self) -> Arc<ClientState> {
+            self.state
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone()
+        }
+
+        /// Read the credentials again from the files they were configured with, and
+        /// replace the HTTP client if they are valid. Credentials from memory stay the same.
+        /// Requests in progress finish with the old credentials.
+        pub fn reload_credentials(&self) -> Result<(), Error> {
+            self.update_credentials(|_| {})
+        }
+
+        /// Change the options the client was built with, e.g. set a renewed certificate with
+        /// [`ETSI014ClientBuilder::identity`], and replace the HTTP client. Only the
+        /// credentials, TLS and connection options take effect, the other options are fixed
+        /// when the client is built. If building fails, the client keeps its current options
+        /// and HTTP client. Requests in progress finish with the old credentials.
+        pub fn update_credentials(
+            &self,
+            update: impl FnOnce(&mut ETSI014ClientBuilder),
+        ) -> Result<(), Error> {
+            let mut options = self.options.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut updated = options.clone();
+            update(&mut updated);
+            let state = updated.build_state()?;
+            *options = updated;
+            let identity = state.identity.as_ref();
+            info!(
+                subject = identity.map(|identity| identity.subject.as_str()),
+                not_after = identity.map(|identity| identity.not_after),
+                "Reloaded client credentials"
+            );
+            *self.state.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(state);
+            Ok(())
+        }
+
+        /// Reload the credentials whenever the certificate, key or CA files change, see
+        /// [`Self::reload_credentials`]. Changes are picked up with inotify on Linux and by
+        /// polling elsewhere. Failed reloads are logged and retried, while the client keeps
+        /// using the old credentials. Watching stops when the returned watcher is dropped.
+        pub fn watch_credentials(self: &Arc<Self>) -> Result<CredentialWatcher, Error> {
+            let paths = self
+                .options
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .credential_paths();
+            CredentialWatcher::start(Arc::downgrade(self), paths)
+        }
+
+        pub fn key_ledger(&self) -> Option<&Arc<KeyLedger>> {
+            self.ledger.as_ref()
+        }
+
+        pub fn key_cache(&self) -> Option<&Arc<KeyCache>> {
+            self.key_cache.as_ref()
+        }
+
+        /// Identity in the current client certificate.
+        pub fn identity(&self) -> Option<ClientIdentity> {
+            self.state().identity.clone()
+        }
+
+        /// Common name of the current client certificate.
+        pub fn local_sae_id(&self) -> Option<String> {
+            self.state().identity.as_ref()?.common_name.clone()
+        }
+
+        /// Write the audit record of an operation. The result is returned even if the record
+        /// cannot be written.
+        fn audit<T>(
+            &self,
+            operation: &str,
+            target_sae_id: &str,
+            requested_key_ids: &[&str],
+            result: Result<T, Error>,
+            keys: impl FnOnce(&T) -> Vec<(&str, usize)>,
+        ) -> Result<T, Error> {
+            let Some(audit_log) = &self.audit_log else {
+                return result;
+            };
+            let local_sae_id = self.local_sae_id();
+            let mut event = AuditEvent {
+                local_sae_id: local_sae_id.as_deref(),
+                operation,
+                target_sae_id,
+                requested_key_ids,
+                keys: Vec::new(),
+                error: None,
+            };
+            match &result {
+                Ok(value) => event.keys = keys(value),
+                Err(e) => event.error = Some(e),
+            }
+            // Keys were consumed from the KME and recorded in the ledger by now, failing
+            // would lose them for good. The failure is reported separately instead.
+            if let Err(audit_error) = audit_log.record(event) {
+                error!(
+                    kind = ?audit_error.kind,
+                    error = %audit_error.msg,
+                    operation,
+                    "Error writing audit record"
+                );
+                #[cfg(feature = "metrics")]
+                crate::metrics::record_error(operation, target_sae_id, &audit_error);
+            }
+            result
+        }
+
+        fn record_retrieval(
             &self,
             target_sae_id: &str,
+            keys: Vec<(KeyId, SecretVec<u8>)>,
+        ) -> Result<Vec<(KeyId, SecretVec<u8>)>, Error> {
+            if let Some(ledger) = &self.ledger {
+                let key_ids = keys.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>();
+                ledger.record_retrieval(target_sae_id, &key_ids)?;
+            }
+            Ok(keys)
+        }
+
+        // Spans and events must never contain keys or request and response bodies, so
+        // errors are logged by kind only.
+        #[instrument(
+            level = "debug",
+            skip(self, body),
+            fields(http_status = field::Empty, latency_ms = field::Empty)
+        )]
+        async fn send_request<T>(
+            &self,
+            target_sae_id: &SaeId,
             endpoint: &str,
             body: Option<&str>,
         ) -> Result<T, Error>
         where
             T: de::DeserializeOwned,
         {
-            let mut url = self.base_url.clone();
-            let path_prefix = Self::PATH_PREFIX;
-            url.set_path(&format!("{path_prefix}/{target_sae_id}/{endpoint}"));
+            let start = Instant::now();
+            let result = self.execute_request(target_sae_id, endpoint, body).await;
+            let latency = start.elapsed();
+            Span::current().record("latency_ms", latency.as_millis() as u64);
+            match &result {
+                Ok(_) => debug!("Request finished"),
+                Err(e) => debug!(kind = ?e.kind, "Request failed"),
+            }
+            #[cfg(feature = "metrics")]
+            crate::metrics::record_request(endpoint, target_sae_id, latency);
+            result
+        }
+
+        async fn execute_request<T>(
+            &self,
+            target_sae_id: &SaeId,
+            endpoint: &str,
+            body: Option<&str>,
+        ) -> Result<T, Error>
+        where
+            T: de::DeserializeOwned,
+        {
+            let state = self.state();
+            let url =
+                request_url(&state.base_url, &self.path_prefix, target_sae_id, endpoint);
+            let verbose = self.verbose_diagnostics;
+            // Without verbose diagnostics, errors never contain request or response bodies,
+            // which may contain keys.
+            let describe_request = |request: &Request| {
+                let summary = format!("{} {}", request.method(), request.url());
+                match body {
+                    Some(body) if verbose => {
+                        format!("{request:#?}\nUsing POST body: {body}")
+                    }
+                    _ if verbose => format!("{request:#?}"),
+                    _ => summary,
+                }
+            };
             let request = match body {
-                None => self
+                None => state.http_client.get(url.clone()),
+                Some(body) => state
                     .http_client
-                    .get(url.clone())
-                    .build()
-                    .map_err(|e| Error::new(
-                        format!("Error building request for url: {url}"),
-                        InvalidArgument,
-                        Some(Box::new(e)),
-                    )),
-                Some(body) =>
-                    self.http_client
-                        .post(url.clone())
-                        .header(CONTENT_TYPE, "application/json")
-                        .body(body.to_owned())
-                        .build()
-                        .map_err(|e| Error::new(
-                            format!("Error building request for url: {url}\n\nRequest body: {body}"),
-                            InvalidArgument,
-                            Some(Box::new(e))
-                        )),
-            }?;
-
-            let response = self
-                .http_client
-                .execute(request.try_clone().unwrap())
-                .await
-                .map_err(|e| {
+                    .post(url.clone())
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body.to_owned()),
+            }
+            .build()
+            .map_err(|e| {
+                let body_info = match body {
+                    Some(body) if verbose => format!("\n\nRequest body: {body}"),
+                    _ => "".to_owned(),
+                };
+                Error::new(
+                    format!("Error building request for url: {url}{body_info}"),
+                    InvalidArgument,
+                    Some(Box::new(e)),
+                )
+            })?;
+
+            let mut attempt = 0;
+            let response = loop {
+                match state
+                    .http_client
+                    .execute(request.try_clone().unwrap())
+                    .await
+                {
+                    Ok(response) => break response,
+                    // Only retry when the KME cannot have handed out keys yet
+                    Err(e)
+                        if attempt < self.max_retries
+                            && (e.is_connect() || (body.is_none() && e.is_timeout())) =>
+                    {
+                        attempt += 1;
+                        warn!(attempt, error = %e, "Retrying request");
+                        tokio::time::sleep(self.retry_delay).await;
+                    }
+                    Err(e) => {
+                        return Err(Error::new(
+                            format!(
+                                "Error sending request: {}",
+                                describe_request(&request)
+                            ),
+                            ConnectionError,
+                            Some(Box::new(e)),
+                        ));
+                    }
+                }
+            };
+            let http_code = response.status();
+            Span::current().record("http_status", http_code.as_u16());
+            // Keep the body in protected memory, as it may contain keys
+            let response_body =
+                SecretBuffer::from_response(response).await.map_err(|e| {
                     Error::new(
-                        format!("Error sending request: {request:#?}"),
+                        "Error reading response".to_string(),
                         ConnectionError,
                         Some(Box::new(e)),
                     )
                 })?;
-            let http_code = response.status();
-            let response_string = response.text().await.map_err(|e| {
-                Error::new(
-                    "Response not UTF-8".to_string(),
-                    InvalidResponse,
-                    Some(Box::new(e)),
-                )
-            })?;
-            let error_info = |s: String| {
-                let body_info = match body {
-                    None => "".to_owned(),
-                    Some(body) => format!("\nUsing POST body: {body}"),
-                };
-                format!(
-                    "{s}\n\n\
-                         HTTP Code: {http_code}\n\
-                         Response:\n{response_string}\n\
-                         Using request: {request:#?}{body_info}"
-                )
+            let request_info = describe_request(&request);
+            response_body.with_slice(|response| {
+                decode_response(response, http_code, &request_info, verbose)
+            })
+        }
+
+        #[instrument(skip(self))]
+        pub async fn get_status(&self, target_sae_id: &SaeId) -> Result<Status, Error> {
+            let result = self
+                .send_request::<StatusResponse>(target_sae_id, "status", None)
+                .await
+                .map(Status::from)
+                .and_then(|status| self.check_source_sae_id(status));
+            let result = self.audit("status", target_sae_id, &[], result, |_| Vec::new());
+            if let (Ok(status), Some(quota)) = (&result, &self.quota) {
+                quota.record_status(target_sae_id, status.stored_key_count);
+            }
+            match &result {
+                Ok(status) => info!(status.stored_key_count, "Received status"),
+                Err(e) => info!(kind = ?e.kind, "Error getting status"),
+            }
+            #[cfg(feature = "metrics")]
+            crate::metrics::record_status(target_sae_id, &result);
+            result
+        }
+
+        /// Compare the SAE ID the KME identifies the client as with the expected SAE ID.
+        fn check_source_sae_id(&self, status: Status) -> Result<Status, Error> {
+            let state = self.state();
+            let Some(expected) = &state.expected_sae_id else {
+                return Ok(status);
             };
-            if !http_code.is_success() {
+            if status.source_sae_id != *expected {
+                warn!(
+                    source_sae_id = status.source_sae_id.as_str(),
+                    expected_sae_id = expected.as_str(),
+                    "The KME identifies the client as another SAE"
+                );
                 return Err(Error::new(
-                    error_info("Unsuccessful HTTP code".to_string()),
-                    InvalidResponse,
+                    format!(
+                        "The KME identifies this client as {}, expected {expected}",
+                        status.source_sae_id
+                    ),
+                    IdentityMismatch,
                     None,
                 ));
             }
-            serde_json::from_str::<T>(&response_string).map_err(|e| {
-                Error::new(
-                    error_info("Unable to deserialize JSON from response.".to_string()),
-                    InvalidResponse,
-                    Some(Box::new(e)),
-                )
-            })
+            state.identity_verified.store(true, Ordering::Relaxed);
+            Ok(status)
         }
 
-        pub async fn get_status(&self, target_sae_id: &str) -> Result<Status, Error> {
-            let sr: StatusResponse =
-                self.send_request(target_sae_id, "status", None).await?;
-            Ok(Status {
-                source_kme_id: sr.source_kme_id,
-                target_kme_id: sr.target_kme_id,
-                source_sae_id: sr.source_sae_id,
-                target_sae_id: sr.target_sae_id,
-                key_size: sr.key_size,
-                stored_key_count: sr.stored_key_count,
-                max_key_count: sr.max_key_count,
-                max_key_per_request: sr.max_key_per_request,
-                max_key_size: sr.max_key_size,
-                min_key_size: sr.min_key_size,
-                max_sae_id_count: sr.max_sae_id_count,
-            })
+        /// Check the source SAE ID with a status request before the first keys are
+        /// requested, if it is verified.
+        async fn verify_identity(&self, target_sae_id: &SaeId) -> Result<(), Error> {
+            let state = self.state();
+            if state.expected_sae_id.is_some()
+                && !state.identity_verified.load(Ordering::Relaxed)
+            {
+                self.get_status(target_sae_id).await?;
+            }
+            Ok(())
         }
 
-        fn key_container_to_vector(
+        pub(crate) fn key_container_to_vector(
             kc: KeyContainer,
-        ) -> Result<Vec<(String, SecretVec<u8>)>, Error> {
+        ) -> Result<Vec<(KeyId, SecretVec<u8>)>, Error> {
             let amount_of_keys = kc.keys.len();
             kc.keys.into_iter().try_fold(
                 Vec::with_capacity(amount_of_keys),
-                |mut l, key_and_id| {
+                |mut l, mut key_and_id| {
                     let uuid = &key_and_id.key_id;
-                    let base64_string = key_and_id.key;
-                    let mut base64_vec = base64_string.into_bytes();
-                    let base64_slice = base64_vec.as_mut();
-                    let mut secret_base64 = SecretVec::from(base64_slice);
-                    let mut secret_base64_ref_mut = secret_base64.borrow_mut();
+                    let mut secret_base64_ref_mut = key_and_id.key.borrow_mut();
                     let secret_slice = Base64::decode_in_place(
                         secret_base64_ref_mut.as_mut(),
                     )
@@ -233,19 +509,141 @@ pub mod etsi014_client {
                     let secret = SecretVec::new(secret_slice.len(), |sv| {
                         sv.copy_from_slice(secret_slice);
                     });
+                    drop(secret_base64_ref_mut);
                     l.push((key_and_id.key_id, secret));
                     Ok(l)
                 },
             )
         }
 
+        fn audited_keys(keys: &[(KeyId, SecretVec<u8>)]) -> Vec<(&str, usize)> {
+            keys.iter()
+                .map(|(id, key)| (id.as_str(), key.len()))
+                .collect()
+        }
+
+        fn trace_keys(result: &Result<Vec<(KeyId, SecretVec<u8>)>, Error>) {
+            match result {
+                Ok(keys) => {
+                    Span::current().record("key_count", keys.len());
+                    info!("Received keys");
+                }
+                Err(e) => info!(kind = ?e.kind, "Error getting keys"),
+            }
+        }
+
         pub async fn get_keys(
             &self,
             key_size_bits: u32,
-            target_sae_id: &str,
-            additional_target_sae_ids: &[&str],
+            target_sae_id: &SaeId,
+            additional_target_sae_ids: &[SaeId],
+            amount_of_keys: u32,
+        ) -> Result<Vec<(KeyId, SecretVec<u8>)>, Error> {
+            self.get_keys_with_priority(
+                key_size_bits,
+                target_sae_id,
+                additional_target_sae_ids,
+                amount_of_keys,
+                false,
+            )
+            .await
+        }
+
+        /// Like [`get_keys`](Self::get_keys), but may use the reserve of the
+        /// [`KeyQuota`](crate::KeyQuota). Other limits of the quota still apply.
+        pub async fn get_priority_keys(
+            &self,
+            key_size_bits: u32,
+            target_sae_id: &SaeId,
+            additional_target_sae_ids: &[SaeId],
+            amount_of_keys: u32,
+        ) -> Result<Vec<(KeyId, SecretVec<u8>)>, Error> {
+            self.get_keys_with_priority(
+                key_size_bits,
+                target_sae_id,
+                additional_target_sae_ids,
+                amount_of_keys,
+                true,
+            )
+            .await
+        }
+
+        #[instrument(
+            name = "get_keys",
+            skip(self, additional_target_sae_ids),
+            fields(key_count = field::Empty)
+        )]
+        async fn get_keys_with_priority(
+            &self,
+            key_size_bits: u32,
+            target_sae_id: &SaeId,
+            additional_target_sae_ids: &[SaeId],
+            amount_of_keys: u32,
+            priority: bool,
+        ) -> Result<Vec<(KeyId, SecretVec<u8>)>, Error> {
+            let result = self
+                .request_keys(
+                    key_size_bits,
+                    target_sae_id,
+                    additional_target_sae_ids,
+                    amount_of_keys,
+                    priority,
+                )
+                .await;
+            let result = match (result, &self.key_cache) {
+                (Err(e), Some(key_cache)) if matches!(e.kind, ConnectionError) => {
+                    let cached = key_cache.take(
+                        key_size_bits,
+                        target_sae_id,
+                        additional_target_sae_ids,
+                        amount_of_keys,
+                    );
+                    match self.audit("key_cache", target_sae_id, &[], cached, |keys| {
+                        Self::audited_keys(keys)
+                    }) {
+                        Ok(keys) => {
+                            info!("Using cached keys, the KME cannot be reached");
+                            Ok(keys)
+                        }
+                        Err(cache_error) => {
+                            info!(kind = ?cache_error.kind, "No cached keys");
+                            Err(e)
+                        }
+                    }
+                }
+                (result, _) => result,
+            };
+            Self::trace_keys(&result);
+            #[cfg(feature = "metrics")]
+            crate::metrics::record_keys("enc_keys", target_sae_id, &result);
+            result
+        }
+
+        /// Take the keys from the quota, refreshing the stored key count if the reserve
+        /// needs it.
+        async fn acquire_quota(
+            &self,
+            quota: &QuotaTracker,
+            key_size_bits: u32,
+            target_sae_id: &SaeId,
+            amount_of_keys: u32,
+            priority: bool,
+        ) -> Result<(), Error> {
+            if !priority && quota.needs_status(target_sae_id) {
+                self.get_status(target_sae_id).await?;
+            }
+            quota.acquire(target_sae_id, amount_of_keys, key_size_bits, priority)
+        }
+
+        /// Request keys from the KME, bypassing the key cache.
+        async fn request_keys(
+            &self,
+            key_size_bits: u32,
+            target_sae_id: &SaeId,
+            additional_target_sae_ids: &[SaeId],
             amount_of_keys: u32,
-        ) -> Result<Vec<(String, SecretVec<u8>)>, Error> {
+            priority: bool,
+        ) -> Result<Vec<(KeyId, SecretVec<u8>)>, Error> {
             let post_body = serde_json::to_string(&KeyRequest {
                 number: amount_of_keys,
                 size: Some(key_size_bits),
@@ -253,25 +651,235 @@ pub mod etsi014_client {
                 extension_mandatory: None,
             })
             .expect("Error serializing key request.");
-            let key_container = self
-                .send_request::<KeyContainer>(target_sae_id, "enc_keys", Some(&post_body))
-                .await?;
-            Self::key_container_to_vector(key_container)
+            let result = async {
+                self.verify_identity(target_sae_id).await?;
+                if let Some(quota) = &self.quota {
+                    self.acquire_quota(
+                        quota,
+                        key_size_bits,
+                        target_sae_id,
+                        amount_of_keys,
+                        priority,
+                    )
+                    .await?;
+                }
+                let result = self
+                    .send_request::<KeyContainer>(
+                        target_sae_id,
+                        "enc_keys",
+                        Some(&post_body),
+                    )
+                    .await
+                    .and_then(Self::key_container_to_vector)
+                    .and_then(|keys| self.record_retrieval(target_sae_id, keys));
+                if let Some(quota) = &self.quota {
+                    let received = result.as_ref().map_or(0, |keys| keys.len() as u32);
+                    quota.settle(target_sae_id, amount_of_keys, received, key_size_bits);
+                }
+                result
+            }
+            .await;
+            self.audit("enc_keys", target_sae_id, &[], result, |keys| {
+                Self::audited_keys(keys)
+            })
+        }
+
+        /// Request keys from the KME and store them in the key cache, returning their IDs.
+        /// The keys expire after `ttl`, or the default TTL of the cache.
+        #[instrument(
+            skip(self, additional_target_sae_ids),
+            fields(key_count = field::Empty)
+        )]
+        pub async fn fill_key_cache(
+            &self,
+            key_size_bits: u32,
+            target_sae_id: &SaeId,
+            additional_target_sae_ids: &[SaeId],
+            amount_of_keys: u32,
+            ttl: Option<Duration>,
+        ) -> Result<Vec<KeyId>, Error> {
+            let result = async {
+                let key_cache = self.key_cache.as_ref().ok_or_else(|| {
+                    Error::new("No key cache configured".to_string(), KeyCacheError, None)
+                })?;
+                let keys = self
+                    .request_keys(
+                        key_size_bits,
+                        target_sae_id,
+                        additional_target_sae_ids,
+                        amount_of_keys,
+                        false,
+                    )
+                    .await?;
+                key_cache.insert(target_sae_id, additional_target_sae_ids, &keys, ttl)?;
+                Ok(keys)
+            }
+            .await;
+            Self::trace_keys(&result);
+            #[cfg(feature = "metrics")]
+            crate::metrics::record_keys("enc_keys", target_sae_id, &result);
+            Ok(result?.into_iter().map(|(key_id, _)| key_id).collect())
         }
 
+        #[instrument(
+            skip(self, key_ids),
+            fields(requested = key_ids.len(), key_count = field::Empty)
+        )]
         pub async fn get_keys_by_ids(
             &self,
-            target_sae_id: &str,
-            key_ids: &[&str],
-        ) -> Result<Vec<(String, SecretVec<u8>)>, Error> {
+            target_sae_id: &SaeId,
+            key_ids: &[KeyId],
+        ) -> Result<Vec<(KeyId, SecretVec<u8>)>, Error> {
             let post_body = serde_json::to_string(&KeysByIdsRequest {
-                key_ids: key_ids.iter().map(|key_id| KeyId { key_id }).collect(),
+                key_ids: key_ids.iter().map(|key_id| KeyIdRef { key_id }).collect(),
             })
             .expect("Error serializing keys by ids reqeust");
-            let key_container = self
-                .send_request::<KeyContainer>(target_sae_id, "dec_keys", Some(&post_body))
-                .await?;
-            Self::key_container_to_vector(key_container)
+            let requested = key_ids.iter().map(KeyId::as_str).collect::<Vec<_>>();
+            let result = async {
+                self.verify_identity(target_sae_id).await?;
+                // Refuse before requesting, the keys are checked again after receiving them
+                if let Some(ledger) = &self.ledger {
+                    ledger.check_retrieval(&requested)?;
+                }
+                self.send_request::<KeyContainer>(
+                    target_sae_id,
+                    "dec_keys",
+                    Some(&post_body),
+                )
+                .await
+                .and_then(Self::key_container_to_vector)
+                .and_then(|keys| self.record_retrieval(target_sae_id, keys))
+            }
+            .await;
+            let result =
+                self.audit("dec_keys", target_sae_id, &requested, result, |keys| {
+                    Self::audited_keys(keys)
+                });
+            Self::trace_keys(&result);
+            #[cfg(feature = "metrics")]
+            crate::metrics::record_keys("dec_keys", target_sae_id, &result);
+            result
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::sae_ids::sae_ids;
+        use percent_encoding::percent_decode_str;
+        use proptest::prelude::*;
+
+        /// Recognizable base64 encoded key, which errors must not contain unless verbose
+        /// diagnostics are enabled.
+        const KEY: &str = "S2V5TWF0ZXJpYWxUaGF0TXVzdE5vdExlYWsxMjM0NTY=";
+
+        /// Bodies of malformed `enc_keys` and `dec_keys` responses containing `KEY`.
+        fn malformed_responses() -> Vec<(StatusCode, String)> {
+            vec![
+                // Trailing comma
+                (
+                    StatusCode::OK,
+                    format!(r#"{{"keys":[{{"key_ID":"a","key":"{KEY}",}}]}}"#),
+                ),
+                // A key instead of a list of keys
+                (StatusCode::OK, format!(r#"{{"keys":"{KEY}"}}"#)),
+                // A key ID of the wrong type
+                (
+                    StatusCode::OK,
+                    format!(r#"{{"keys":[{{"key_ID":["{KEY}"],"key":"{KEY}"}}]}}"#),
+                ),
+                // Truncated
+                (
+                    StatusCode::OK,
+                    format!(r#"{{"keys":[{{"key_ID":"a","key":"{KEY}"#),
+                ),
+                // Keys with an error status
+                (
+                    StatusCode::BAD_GATEWAY,
+                    format!(r#"{{"keys":[{{"key_ID":"a","key":"{KEY}"}}]}}"#),
+                ),
+            ]
+        }
+
+        fn decode_keys(
+            response: &str,
+            http_code: StatusCode,
+            endpoint: &str,
+            verbose: bool,
+        ) -> Error {
+            let request_info =
+                format!("POST https://kme.example.org/api/v1/keys/client-2/{endpoint}");
+            let result = decode_response::<KeyContainer>(
+                response.as_bytes(),
+                http_code,
+                &request_info,
+                verbose,
+            )
+            .and_then(ETSI014Client::key_container_to_vector);
+            match result {
+                Ok(_) => panic!("Decoded malformed response {response}"),
+                Err(e) => e,
+            }
+        }
+
+        fn contains_key(e: &Error) -> bool {
+            // Also catch a part of the key, e.g. of a truncated response
+            let part = &KEY[..12];
+            e.to_string().contains(part) || format!("{e:?}").contains(part)
+        }
+
+        #[test]
+        fn malformed_responses_are_redacted() {
+            for endpoint in ["enc_keys", "dec_keys"] {
+                for (http_code, response) in malformed_responses() {
+                    let e = decode_keys(&response, http_code, endpoint, false);
+                    assert!(!contains_key(&e), "Key in error for {response}: {e}");
+                    assert!(e.to_string().contains(endpoint));
+                }
+            }
+        }
+
+        #[test]
+        fn verbose_diagnostics_contain_response() {
+            for endpoint in ["enc_keys", "dec_keys"] {
+                for (http_code, response) in malformed_responses() {
+                    let e = decode_keys(&response, http_code, endpoint, true);
+                    assert!(e.to_string().contains(KEY), "No key in error: {e}");
+                }
+            }
+        }
+
+        #[test]
+        fn invalid_base64_key_is_redacted() {
+            let response = format!(r#"{{"keys":[{{"key_ID":"a","key":"{KEY}!"}}]}}"#);
+            for verbose in [false, true] {
+                let e = decode_keys(&response, StatusCode::OK, "enc_keys", verbose);
+                assert!(matches!(e.kind, InvalidResponse));
+                assert!(!contains_key(&e), "Key in error: {e}");
+            }
+        }
+
+        proptest! {
+            #[test]
+            fn sae_id_is_one_path_segment(
+                sae_id in sae_ids(),
+                path_prefix in prop::sample::select(&["api/v1/keys", "kme/api/v1/keys"][..]),
+            ) {
+                let base_url = Url::parse("https://kme.example.org:8443").unwrap();
+                let prefix: Vec<&str> = path_prefix.split('/').collect();
+                for endpoint in ["status", "enc_keys", "dec_keys"] {
+                    let url = request_url(&base_url, path_prefix, &sae_id, endpoint);
+                    prop_assert_eq!(url.host_str(), Some("kme.example.org"));
+                    prop_assert_eq!(url.query(), None);
+                    prop_assert_eq!(url.fragment(), None);
+                    let segments: Vec<&str> = url.path_segments().unwrap().collect();
+                    prop_assert_eq!(segments.len(), prefix.len() + 2);
+                    prop_assert_eq!(&segments[..prefix.len()], prefix.as_slice());
+                    prop_assert_eq!(segments[prefix.len() + 1], endpoint);
+                    let decoded = percent_decode_str(segments[prefix.len()]).decode_utf8();
+                    prop_assert_eq!(decoded.unwrap(), sae_id.as_str());
+                }
+            }
         }
     }
 }
@@ -0,0 +1,171 @@
+//! Prometheus metrics of all [`ETSI014Client`](crate::ETSI014Client)s in the process,
+//! available with the `metrics` feature. Metrics are always collected, [`register`] makes
+//! them available in a registry.
+
+use crate::Error;
+use crate::error::ErrorType::InvalidArgument;
+use crate::ids::KeyId;
+use crate::status::Status;
+use prometheus::core::Collector;
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+};
+use secrets::SecretVec;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+/// The version of `prometheus` used for the metrics.
+pub use prometheus;
+
+struct Metrics {
+    requests: IntCounterVec,
+    errors: IntCounterVec,
+    keys: IntCounterVec,
+    key_bytes: IntCounterVec,
+    request_duration: HistogramVec,
+    stored_key_count: IntGaugeVec,
+    max_key_count: IntGaugeVec,
+    key_size: IntGaugeVec,
+}
+
+const NAMESPACE: &str = "etsi014_client";
+
+fn counter(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    IntCounterVec::new(Opts::new(name, help).namespace(NAMESPACE), labels)
+        .expect("Invalid counter")
+}
+
+fn gauge(name: &str, help: &str) -> IntGaugeVec {
+    IntGaugeVec::new(
+        Opts::new(name, help).namespace(NAMESPACE),
+        &["target_sae_id"],
+    )
+    .expect("Invalid gauge")
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics {
+    requests: counter(
+        "requests_total",
+        "Requests sent to the KME",
+        &["endpoint", "target_sae_id"],
+    ),
+    errors: counter(
+        "errors_total",
+        "Failed requests by error type",
+        &["endpoint", "target_sae_id", "kind"],
+    ),
+    keys: counter(
+        "keys_total",
+        "Keys received from the KME",
+        &["endpoint", "target_sae_id"],
+    ),
+    key_bytes: counter(
+        "key_bytes_total",
+        "Bytes of key material received from the KME",
+        &["endpoint", "target_sae_id"],
+    ),
+    request_duration: HistogramVec::new(
+        HistogramOpts::new(
+            "request_duration_seconds",
+            "Duration of requests to the KME, including retries",
+        )
+        .namespace(NAMESPACE),
+        &["endpoint"],
+    )
+    .expect("Invalid histogram"),
+    stored_key_count: gauge(
+        "stored_key_count",
+        "Keys stored by the KME for the target SAE, from the last status",
+    ),
+    max_key_count: gauge(
+        "max_key_count",
+        "Maximum amount of stored keys for the target SAE, from the last status",
+    ),
+    key_size: gauge(
+        "key_size_bits",
+        "Default key size for the target SAE, from the last status",
+    ),
+});
+
+/// Register the client metrics with `registry`, for example
+/// [`prometheus::default_registry()`].
+pub fn register(registry: &Registry) -> Result<(), Error> {
+    let m = &*METRICS;
+    let collectors: [Box<dyn Collector>; 8] = [
+        Box::new(m.requests.clone()),
+        Box::new(m.errors.clone()),
+        Box::new(m.keys.clone()),
+        Box::new(m.key_bytes.clone()),
+        Box::new(m.request_duration.clone()),
+        Box::new(m.stored_key_count.clone()),
+        Box::new(m.max_key_count.clone()),
+        Box::new(m.key_size.clone()),
+    ];
+    for collector in collectors {
+        registry.register(collector).map_err(|e| {
+            Error::new(
+                "Error registering metrics".to_string(),
+                InvalidArgument,
+                Some(Box::new(e)),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+pub(crate) fn record_request(endpoint: &str, target_sae_id: &str, duration: Duration) {
+    METRICS
+        .requests
+        .with_label_values(&[endpoint, target_sae_id])
+        .inc();
+    METRICS
+        .request_duration
+        .with_label_values(&[endpoint])
+        .observe(duration.as_secs_f64());
+}
+
+pub(crate) fn record_error(endpoint: &str, target_sae_id: &str, error: &Error) {
+    let kind = format!("{:?}", error.kind);
+    METRICS
+        .errors
+        .with_label_values(&[endpoint, target_sae_id, &kind])
+        .inc();
+}
+
+pub(crate) fn record_status(target_sae_id: &str, result: &Result<Status, Error>) {
+    match result {
+        Ok(status) => {
+            let labels = [target_sae_id];
+            let m = &*METRICS;
+            m.stored_key_count
+                .with_label_values(&labels)
+                .set(status.stored_key_count.into());
+            m.max_key_count
+                .with_label_values(&labels)
+                .set(status.max_key_count.into());
+            m.key_size
+                .with_label_values(&labels)
+                .set(status.key_size.into());
+        }
+        Err(e) => record_error("status", target_sae_id, e),
+    }
+}
+
+pub(crate) fn record_keys(
+    endpoint: &str,
+    target_sae_id: &str,
+    result: &Result<Vec<(KeyId, SecretVec<u8>)>, Error>,
+) {
+    match result {
+        Ok(keys) => {
+            let labels = [endpoint, target_sae_id];
+            let bytes = keys.iter().map(|(_, key)| key.len() as u64).sum();
+            METRICS
+                .keys
+                .with_label_values(&labels)
+                .inc_by(keys.len() as u64);
+            METRICS.key_bytes.with_label_values(&labels).inc_by(bytes);
+        }
+        Err(e) => record_error(endpoint, target_sae_id, e),
+    }
+}
@@ -0,0 +1,122 @@
+//! Client certificate and private key from a PKCS#12 bundle, as created by
+//! `openssl pkcs12 -export`. Bundles with PBES2 and AES, the default of OpenSSL 3, and
+//! with the legacy 3DES and RC2 encryption of OpenSSL 1 and Java keytool are supported.
+
+use crate::Error;
+use crate::error::ErrorType::InvalidArgument;
+use crate::utils::der_to_pem;
+use p12_keystore::KeyStore;
+use p12_keystore::error::Error as KeyStoreError;
+use secrets::SecretVec;
+
+fn pkcs12_error(reason: &str) -> Error {
+    Error::new(
+        format!("Error parsing PKCS#12 bundle: {reason}"),
+        InvalidArgument,
+        None,
+    )
+}
+
+/// The certificate chain as PEM with the certificate of the private key first, and the
+/// private key as PKCS#8 DER.
+pub(crate) fn parse(
+    pkcs12: &[u8],
+    passphrase: &[u8],
+) -> Result<(Vec<u8>, SecretVec<u8>), Error> {
+    let passphrase = std::str::from_utf8(passphrase)
+        .map_err(|_| pkcs12_error("the passphrase is not valid UTF-8"))?;
+    let key_store = KeyStore::from_pkcs12(pkcs12, passphrase).map_err(|e| match e {
+        KeyStoreError::MacError(_)
+        | KeyStoreError::Pkcs5Error(_)
+        | KeyStoreError::UnpadError => pkcs12_error("wrong passphrase"),
+        e => Error::new(
+            format!("Error parsing PKCS#12 bundle: {e}"),
+            InvalidArgument,
+            Some(Box::new(e)),
+        ),
+    })?;
+    let (_, key_chain) = key_store
+        .private_key_chain()
+        .ok_or_else(|| pkcs12_error("no private key with its certificate"))?;
+    // The key store keeps its own copy of the key until it is dropped at the end of this
+    // function, the returned copy is in locked memory
+    let key = key_chain.key();
+    let key = SecretVec::new(key.len(), |k| k.copy_from_slice(key));
+    let chain = key_chain
+        .chain()
+        .iter()
+        .flat_map(|cert| der_to_pem("CERTIFICATE", cert.as_der()).borrow().to_vec())
+        .collect();
+    Ok((chain, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testdata;
+
+    fn error(file: &str, passphrase: &[u8]) -> String {
+        parse(&testdata(file), passphrase).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn chain_and_key_are_read() {
+        let (chain, key) = parse(&testdata("client-1.p12"), b"passphrase").unwrap();
+        let expected = [testdata("mock:client-1.crt"), testdata("mock:ca.crt")].concat();
+        assert_eq!(
+            String::from_utf8(chain).unwrap().replace("\r\n", "\n"),
+            String::from_utf8(expected).unwrap(),
+        );
+        assert_eq!(*key.borrow(), *testdata("ec.der"));
+    }
+
+    #[test]
+    fn wrong_passphrase_is_refused() {
+        let e = error("client-1.p12", b"wrong");
+        assert_eq!(e, "Error parsing PKCS#12 bundle: wrong passphrase");
+    }
+
+    #[test]
+    fn legacy_bundles_are_read() {
+        let (expected_chain, expected_key) =
+            parse(&testdata("client-1.p12"), b"passphrase").unwrap();
+        let (chain, key) =
+            parse(&testdata("client-1-legacy.p12"), b"passphrase").unwrap();
+        assert_eq!(chain, expected_chain);
+        assert_eq!(*key.borrow(), *expected_key.borrow());
+        // Bundled without the CA certificate
+        let (chain, key) = parse(&testdata("client-1-3des.p12"), b"passphrase").unwrap();
+        assert!(expected_chain.starts_with(&chain));
+        assert_eq!(
+            String::from_utf8(chain)
+                .unwrap()
+                .matches("BEGIN CERTIFICATE")
+                .count(),
+            1
+        );
+        assert_eq!(*key.borrow(), *expected_key.borrow());
+        for file in ["client-1-legacy.p12", "client-1-3des.p12"] {
+            let e = error(file, b"wrong");
+            assert_eq!(e, "Error parsing PKCS#12 bundle: wrong passphrase");
+        }
+    }
+
+    #[test]
+    fn malformed_bundles_are_refused() {
+        let pkcs12 = testdata("client-1.p12");
+        for len in 0..pkcs12.len() {
+            assert!(
+                parse(&pkcs12[..len], b"passphrase").is_err(),
+                "truncated to {len}"
+            );
+        }
+        // Every fourth byte, as each attempt runs the key derivation
+        for i in (0..pkcs12.len()).step_by(4) {
+            let mut corrupt = pkcs12.clone();
+            corrupt[i] ^= 0xff;
+            let _ = parse(&corrupt, b"passphrase");
+        }
+        let e = error("mock:client-1.crt", b"passphrase");
+        assert!(e.starts_with("Error parsing PKCS#12 bundle: "), "{e}");
+    }
+}
@@ -0,0 +1,424 @@
+use crate::Error;
+use crate::error::ErrorType::{BelowReserve, QuotaExceeded};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+/// Age after which the stored key count is refreshed before checking the reserve.
+const STATUS_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// Client-side limits on keys requested with `enc_keys`, applied to every target SAE
+/// separately. Requests exceeding a limit are refused without contacting the KME.
+#[derive(Debug, Clone, Default)]
+pub struct KeyQuota {
+    keys_per_second: Option<(f64, u32)>,
+    bits_per_hour: Option<u64>,
+    daily_cap: Option<u64>,
+    reserve: Option<u32>,
+}
+
+impl KeyQuota {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Token bucket refilled with `rate` keys per second, holding at most `burst` keys.
+    pub fn keys_per_second(&mut self, rate: f64, burst: u32) -> &mut Self {
+        self.keys_per_second = Some((rate, burst));
+        self
+    }
+
+    /// Token bucket holding `bits` bits of key material, refilled over an hour.
+    pub fn bits_per_hour(&mut self, bits: u64) -> &mut Self {
+        self.bits_per_hour = Some(bits);
+        self
+    }
+
+    /// Hand out at most `keys` keys per UTC day.
+    pub fn daily_cap(&mut self, keys: u64) -> &mut Self {
+        self.daily_cap = Some(keys);
+        self
+    }
+
+    /// Refuse requests that are not priority requests when they would leave the KME with
+    /// fewer than `stored_key_count` keys, according to the latest status.
+    pub fn reserve(&mut self, stored_key_count: u32) -> &mut Self {
+        self.reserve = Some(stored_key_count);
+        self
+    }
+}
+
+/// Source of the time for quotas, replaced in tests.
+trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+    fn system_time(&self) -> SystemTime;
+
+    /// Days since the Unix epoch, in UTC.
+    fn today(&self) -> u64 {
+        let now = self
+            .system_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        now.as_secs() / SECONDS_PER_DAY
+    }
+}
+
+#[derive(Debug)]
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(capacity: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate_per_second: f64, capacity: f64, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate_per_second).min(capacity);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug)]
+struct TargetState {
+    keys: TokenBucket,
+    bits: TokenBucket,
+    day: u64,
+    keys_today: u64,
+    stored_key_count: Option<(u32, Instant)>,
+}
+
+impl TargetState {
+    fn new(quota: &KeyQuota, clock: &dyn Clock) -> TargetState {
+        let key_capacity = quota.keys_per_second.map_or(0, |(_, burst)| burst);
+        let now = clock.now();
+        TargetState {
+            keys: TokenBucket::full(key_capacity.into(), now),
+            bits: TokenBucket::full(quota.bits_per_hour.unwrap_or(0) as f64, now),
+            day: clock.today(),
+            keys_today: 0,
+            stored_key_count: None,
+        }
+    }
+}
+
+/// Usage of a [`KeyQuota`] per target SAE.
+#[derive(Debug)]
+pub(crate) struct QuotaTracker {
+    quota: KeyQuota,
+    targets: Mutex<HashMap<String, TargetState>>,
+    clock: Box<dyn Clock>,
+}
+
+impl QuotaTracker {
+    pub(crate) fn new(quota: KeyQuota) -> QuotaTracker {
+        QuotaTracker::with_clock(quota, Box::new(SystemClock))
+    }
+
+    fn with_clock(quota: KeyQuota, clock: Box<dyn Clock>) -> QuotaTracker {
+        QuotaTracker {
+            quota,
+            targets: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    fn with_target<T>(
+        &self,
+        target_sae_id: &str,
+        f: impl FnOnce(&KeyQuota, &mut TargetState) -> T,
+    ) -> T {
+        let mut targets = self.targets.lock().unwrap_or_else(|e| e.into_inner());
+        let state = targets
+            .entry(target_sae_id.to_owned())
+            .or_insert_with(|| TargetState::new(&self.quota, &*self.clock));
+        f(&self.quota, state)
+    }
+
+    /// Whether the stored key count must be refreshed with a status request before keys
+    /// are requested for `target_sae_id`.
+    pub(crate) fn needs_status(&self, target_sae_id: &str) -> bool {
+        let now = self.clock.now();
+        self.quota.reserve.is_some()
+            && self.with_target(target_sae_id, |_, state| {
+                state.stored_key_count.is_none_or(|(_, updated)| {
+                    now.duration_since(updated) > STATUS_MAX_AGE
+                })
+            })
+    }
+
+    pub(crate) fn record_status(&self, target_sae_id: &str, stored_key_count: u32) {
+        if self.quota.reserve.is_some() {
+            self.with_target(target_sae_id, |_, state| {
+                state.stored_key_count = Some((stored_key_count, self.clock.now()));
+            })
+        }
+    }
+
+    /// Take `keys` keys of `key_size_bits` bits from the quota, or fail without taking
+    /// anything if a limit would be exceeded.
+    pub(crate) fn acquire(
+        &self,
+        target_sae_id: &str,
+        keys: u32,
+        key_size_bits: u32,
+        priority: bool,
+    ) -> Result<(), Error> {
+        let bits = u64::from(keys) * u64::from(key_size_bits);
+        let (now, today) = (self.clock.now(), self.clock.today());
+        self.with_target(target_sae_id, |quota, state| {
+            let exceeded = |limit: String| {
+                Err(Error::new(
+                    format!("Requesting {keys} keys for {target_sae_id} exceeds {limit}"),
+                    QuotaExceeded,
+                    None,
+                ))
+            };
+            if let (Some(reserve), false, Some((stored, _))) =
+                (quota.reserve, priority, state.stored_key_count)
+            {
+                if stored.saturating_sub(keys) < reserve {
+                    return Err(Error::new(
+                        format!(
+                            "The KME stores {stored} keys for {target_sae_id}, only \
+                             priority requests may use the reserve of {reserve} keys"
+                        ),
+                        BelowReserve,
+                        None,
+                    ));
+                }
+            }
+            if let Some((rate, burst)) = quota.keys_per_second {
+                state.keys.refill(rate, burst.into(), now);
+                if state.keys.tokens < keys.into() {
+                    return exceeded(format!("the limit of {rate} keys per second"));
+                }
+            }
+            if let Some(bits_per_hour) = quota.bits_per_hour {
+                state.bits.refill(
+                    bits_per_hour as f64 / 3600.0,
+                    bits_per_hour as f64,
+                    now,
+                );
+                if state.bits.tokens < bits as f64 {
+                    return exceeded(format!(
+                        "the limit of {bits_per_hour} bits per hour"
+                    ));
+                }
+            }
+            if state.day != today {
+                state.day = today;
+                state.keys_today = 0;
+            }
+            if let Some(cap) = quota.daily_cap {
+                if state.keys_today + u64::from(keys) > cap {
+                    return exceeded(format!("the daily cap of {cap} keys"));
+                }
+            }
+            state.keys.tokens -= f64::from(keys);
+            state.bits.tokens -= bits as f64;
+            state.keys_today += u64::from(keys);
+            Ok(())
+        })
+    }
+
+    /// Settle a request that acquired `requested` keys and received `received` keys,
+    /// returning the keys that were not handed out to the quota.
+    pub(crate) fn settle(
+        &self,
+        target_sae_id: &str,
+        requested: u32,
+        received: u32,
+        key_size_bits: u32,
+    ) {
+        let unused = requested.saturating_sub(received);
+        self.with_target(target_sae_id, |_, state| {
+            state.keys.tokens += f64::from(unused);
+            state.bits.tokens += f64::from(unused) * f64::from(key_size_bits);
+            state.keys_today = state.keys_today.saturating_sub(unused.into());
+            if let Some((stored, _)) = &mut state.stored_key_count {
+                *stored = stored.saturating_sub(received);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Clock that only moves when advanced, starting an hour before midnight UTC.
+    #[derive(Debug, Clone)]
+    struct TestClock {
+        start: Instant,
+        elapsed: Arc<Mutex<Duration>>,
+    }
+
+    impl TestClock {
+        fn new() -> TestClock {
+            TestClock {
+                start: Instant::now(),
+                elapsed: Arc::new(Mutex::new(Duration::ZERO)),
+            }
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.elapsed.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> Instant {
+            self.start + *self.elapsed.lock().unwrap()
+        }
+
+        fn system_time(&self) -> SystemTime {
+            let start = Duration::from_secs(20000 * SECONDS_PER_DAY - 3600);
+            UNIX_EPOCH + start + *self.elapsed.lock().unwrap()
+        }
+    }
+
+    fn tracker(quota: &KeyQuota) -> (QuotaTracker, TestClock) {
+        let clock = TestClock::new();
+        let tracker = QuotaTracker::with_clock(quota.clone(), Box::new(clock.clone()));
+        (tracker, clock)
+    }
+
+    fn refused(result: Result<(), Error>) -> String {
+        format!("{:?}", result.expect_err("Request was not refused").kind)
+    }
+
+    #[test]
+    fn keys_per_second_bucket_refills_up_to_burst() {
+        let (tracker, clock) = tracker(KeyQuota::new().keys_per_second(2.0, 4));
+        tracker.acquire("sae", 4, 256, false).unwrap();
+        assert_eq!(
+            refused(tracker.acquire("sae", 1, 256, false)),
+            "QuotaExceeded"
+        );
+        // Other targets have their own bucket
+        tracker.acquire("other", 4, 256, false).unwrap();
+
+        clock.advance(Duration::from_millis(500));
+        tracker.acquire("sae", 1, 256, false).unwrap();
+        assert_eq!(
+            refused(tracker.acquire("sae", 1, 256, false)),
+            "QuotaExceeded"
+        );
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(
+            refused(tracker.acquire("sae", 5, 256, false)),
+            "QuotaExceeded"
+        );
+        tracker.acquire("sae", 4, 256, false).unwrap();
+    }
+
+    #[test]
+    fn bits_per_hour_bucket_refills_over_an_hour() {
+        let (tracker, clock) = tracker(KeyQuota::new().bits_per_hour(3600));
+        tracker.acquire("sae", 12, 256, false).unwrap();
+        assert_eq!(
+            refused(tracker.acquire("sae", 1, 1024, false)),
+            "QuotaExceeded"
+        );
+        // 528 bits are left, one bit is added per second
+        clock.advance(Duration::from_secs(490));
+        assert_eq!(
+            refused(tracker.acquire("sae", 1, 1024, false)),
+            "QuotaExceeded"
+        );
+        clock.advance(Duration::from_secs(10));
+        tracker.acquire("sae", 1, 1024, false).unwrap();
+    }
+
+    #[test]
+    fn daily_cap_rolls_over_at_midnight_utc() {
+        let (tracker, clock) = tracker(KeyQuota::new().daily_cap(10));
+        tracker.acquire("sae", 10, 256, false).unwrap();
+        assert_eq!(
+            refused(tracker.acquire("sae", 1, 256, false)),
+            "QuotaExceeded"
+        );
+        clock.advance(Duration::from_secs(59 * 60));
+        assert_eq!(
+            refused(tracker.acquire("sae", 1, 256, false)),
+            "QuotaExceeded"
+        );
+        clock.advance(Duration::from_secs(2 * 60));
+        tracker.acquire("sae", 10, 256, false).unwrap();
+        assert_eq!(
+            refused(tracker.acquire("sae", 1, 256, false)),
+            "QuotaExceeded"
+        );
+    }
+
+    #[test]
+    fn reserve_is_only_used_by_priority_requests() {
+        let (tracker, clock) = tracker(KeyQuota::new().reserve(100));
+        assert!(tracker.needs_status("sae"));
+        // Without a status, the reserve cannot be checked and is not enforced
+        tracker.acquire("sae", 60, 256, false).unwrap();
+
+        tracker.record_status("sae", 150);
+        assert!(!tracker.needs_status("sae"));
+        assert_eq!(
+            refused(tracker.acquire("sae", 60, 256, false)),
+            "BelowReserve"
+        );
+        tracker.acquire("sae", 60, 256, true).unwrap();
+        tracker.acquire("sae", 50, 256, false).unwrap();
+        tracker.settle("sae", 50, 50, 256);
+        assert_eq!(
+            refused(tracker.acquire("sae", 1, 256, false)),
+            "BelowReserve"
+        );
+
+        clock.advance(STATUS_MAX_AGE + Duration::from_secs(1));
+        assert!(tracker.needs_status("sae"));
+    }
+
+    #[test]
+    fn settle_returns_keys_that_were_not_received() {
+        let quota = KeyQuota::new()
+            .keys_per_second(1.0, 4)
+            .bits_per_hour(1024)
+            .daily_cap(4)
+            .clone();
+        let (tracker, _clock) = tracker(&quota);
+        tracker.acquire("sae", 4, 256, false).unwrap();
+        assert_eq!(
+            refused(tracker.acquire("sae", 1, 256, false)),
+            "QuotaExceeded"
+        );
+        tracker.settle("sae", 4, 1, 256);
+        tracker.acquire("sae", 3, 256, false).unwrap();
+        assert_eq!(
+            refused(tracker.acquire("sae", 1, 256, false)),
+            "QuotaExceeded"
+        );
+        tracker.settle("sae", 3, 0, 256);
+        tracker.acquire("sae", 3, 256, false).unwrap();
+    }
+}
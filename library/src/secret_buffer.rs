@@ -0,0 +1,216 @@
+use reqwest::Response;
+use secrets::SecretVec;
+use zeroize::Zeroize;
+
+/// Growable buffer in protected memory. Memory released when growing is zeroed.
+pub(crate) struct SecretBuffer {
+    data: SecretVec<u8>,
+    len: usize,
+}
+
+impl SecretBuffer {
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        SecretBuffer {
+            data: SecretVec::zero(capacity),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn extend_from_slice(&mut self, bytes: &[u8]) {
+        let required = self.len + bytes.len();
+        if required > self.data.len() {
+            let capacity = required.max(self.data.len() * 2);
+            let old = self.data.borrow();
+            let data = SecretVec::new(capacity, |s| {
+                s[..self.len].copy_from_slice(&old[..self.len]);
+                s[self.len..].fill(0);
+            });
+            drop(old);
+            self.data = data;
+        }
+        self.data.borrow_mut()[self.len..required].copy_from_slice(bytes);
+        self.len = required;
+    }
+
+    /// Run `f` with the contents of the buffer, which are only accessible during the call.
+    pub(crate) fn with_slice<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        f(&self.data.borrow()[..self.len])
+    }
+
+    /// Read the body of `response` into protected memory, zeroing the chunks received from
+    /// the HTTP client where possible. Buffers of the HTTP and TLS implementation are out of
+    /// reach.
+    pub(crate) async fn from_response(
+        mut response: Response,
+    ) -> Result<SecretBuffer, reqwest::Error> {
+        // Do not trust the length for allocating locked memory up front
+        let capacity =
+            response.content_length().unwrap_or(0).clamp(4096, 1 << 20) as usize;
+        let mut buffer = SecretBuffer::with_capacity(capacity);
+        while let Some(chunk) = response.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+            // Only possible if the chunk is not shared
+            if let Ok(mut chunk) = chunk.try_into_mut() {
+                chunk.as_mut().zeroize();
+            }
+        }
+        Ok(buffer)
+    }
+}
+
+/// Decodes a mock `enc_keys` response while an allocator scans every block freed on the
+/// test thread for key material, which would be an unprotected copy that was not zeroed.
+/// Only in debug builds, as the allocator slows down all tests.
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+    use crate::ETSI014Client;
+    use crate::json::key_container::KeyContainer;
+    use base64ct::{Base64, Encoding};
+    use bytes::Bytes;
+    use http_body::Frame;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Start of every test key, and its base64 encoding.
+    const MARKER: [u8; 12] = [
+        0x9e, 0x37, 0x79, 0xb9, 0x7f, 0x4a, 0x7c, 0x15, 0xf3, 0x9c, 0xc0, 0x60,
+    ];
+    const MARKER_BASE64: &[u8] = b"njd5uX9KfBXznMBg";
+
+    thread_local! {
+        static SCANNING: Cell<bool> = const { Cell::new(false) };
+        static FOUND: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Zeroes every freed block, so that memory freed before scanning started cannot be
+    /// reused and reported when it is freed again.
+    struct ScanningAllocator;
+
+    #[global_allocator]
+    static ALLOCATOR: ScanningAllocator = ScanningAllocator;
+
+    impl ScanningAllocator {
+        /// Count `block` if it contains key material, and zero it. Must not allocate.
+        unsafe fn scan_and_zero(ptr: *mut u8, size: usize) {
+            let block = unsafe { std::slice::from_raw_parts_mut(ptr, size) };
+            if !SCANNING.try_with(Cell::get).unwrap_or(false) {
+                block.fill(0);
+                return;
+            }
+            let found = [&MARKER[..], MARKER_BASE64]
+                .iter()
+                .any(|pattern| block.windows(pattern.len()).any(|w| w == *pattern));
+            if found {
+                FOUND.with(|count| count.set(count.get() + 1));
+            }
+            block.fill(0);
+        }
+    }
+
+    unsafe impl GlobalAlloc for ScanningAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe {
+                Self::scan_and_zero(ptr, layout.size());
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        unsafe fn realloc(
+            &self,
+            ptr: *mut u8,
+            layout: Layout,
+            new_size: usize,
+        ) -> *mut u8 {
+            // Moving a block frees the old one, which the system allocator does not zero
+            unsafe {
+                let new_layout =
+                    Layout::from_size_align_unchecked(new_size, layout.align());
+                let new_ptr = System.alloc(new_layout);
+                if !new_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(
+                        ptr,
+                        new_ptr,
+                        layout.size().min(new_size),
+                    );
+                    self.dealloc(ptr, layout);
+                }
+                new_ptr
+            }
+        }
+    }
+
+    /// Response body received in chunks, like from the network.
+    struct Chunks(VecDeque<Bytes>);
+
+    impl http_body::Body for Chunks {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+            Poll::Ready(self.0.pop_front().map(|chunk| Ok(Frame::data(chunk))))
+        }
+    }
+
+    #[test]
+    fn decoding_keys_leaves_no_copies_in_freed_memory() {
+        let keys: Vec<Vec<u8>> = (0..200u8)
+            .map(|i| [&MARKER[..], &[i; 20]].concat())
+            .collect();
+        let key_objects = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                // Escaped slashes are decoded by the deserializer
+                let key = Base64::encode_string(key).replace('/', "\\/");
+                format!(r#"{{"key_ID":"key-{i}","key":"{key}"}}"#)
+            })
+            .collect::<Vec<_>>();
+        let body = format!(r#"{{"keys":[{}]}}"#, key_objects.join(","));
+        drop(key_objects);
+        // Larger than the initial capacity, so the buffer grows
+        assert!(body.len() > 2 * 4096);
+        let chunks = body
+            .as_bytes()
+            .chunks(1000)
+            .map(Bytes::copy_from_slice)
+            .collect();
+        drop(body);
+        let response = reqwest::Response::from(http::Response::new(reqwest::Body::wrap(
+            Chunks(chunks),
+        )));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        SCANNING.set(true);
+        let decoded = runtime.block_on(async {
+            let buffer = SecretBuffer::from_response(response).await.unwrap();
+            buffer.with_slice(|body| serde_json::from_slice::<KeyContainer>(body))
+        });
+        let decoded = decoded.map_err(|e| e.to_string()).and_then(|container| {
+            ETSI014Client::key_container_to_vector(container).map_err(|e| e.msg)
+        });
+        SCANNING.set(false);
+
+        let found = FOUND.get();
+        assert_eq!(found, 0, "{found} freed blocks contained key material");
+        let decoded = decoded.unwrap();
+        assert_eq!(decoded.len(), keys.len());
+        for ((id, key), (i, expected)) in decoded.iter().zip(keys.iter().enumerate()) {
+            assert_eq!(id.as_str(), format!("key-{i}"));
+            assert_eq!(*key.borrow(), expected[..]);
+        }
+    }
+}
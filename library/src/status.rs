@@ -1,9 +1,16 @@
-#[derive(Debug, PartialEq, Eq, Clone)]
+use crate::json::status_response::StatusResponse;
+use crate::{KmeId, SaeId};
+use serde::Serialize;
+
+/// Status of the key store for a pair of SAEs. Serializes to the ETSI GS QKD 014 status
+/// format.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(into = "StatusResponse")]
 pub struct Status {
-    pub source_kme_id: String,
-    pub target_kme_id: String,
-    pub source_sae_id: String,
-    pub target_sae_id: String,
+    pub source_kme_id: KmeId,
+    pub target_kme_id: KmeId,
+    pub source_sae_id: SaeId,
+    pub target_sae_id: SaeId,
     pub key_size: u32,
     pub stored_key_count: u32,
     pub max_key_count: u32,
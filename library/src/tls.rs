@@ -0,0 +1,174 @@
+//! TLS policies that reqwest does not offer itself: pinning the public key of the KME and
+//! connecting to another host than the server name that is verified.
+
+use crate::Error;
+use crate::error::ErrorType::InvalidArgument;
+use base64ct::{Base64, Encoding};
+use hyper_util::client::legacy::connect::Connection;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::tls::TlsInfo;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// SHA-256 hash of a DER encoded SubjectPublicKeyInfo.
+pub(crate) type SpkiPin = [u8; 32];
+
+/// Parse a pin in the format of `curl --pinnedpubkey`, `sha256//` followed by the base64
+/// encoded hash. The prefix is optional.
+pub(crate) fn parse_spki_pin(pin: &str) -> Result<SpkiPin, Error> {
+    let encoded = pin.strip_prefix("sha256//").unwrap_or(pin);
+    match Base64::decode_vec(encoded).map(SpkiPin::try_from) {
+        Ok(Ok(hash)) => Ok(hash),
+        _ => Err(Error::new(
+            format!(
+                "Invalid SPKI pin {pin:?}, expected sha256// and a base64 SHA-256 hash"
+            ),
+            InvalidArgument,
+            None,
+        )),
+    }
+}
+
+/// Pin of the public key in a DER encoded certificate.
+pub(crate) fn spki_pin(cert: &[u8]) -> Option<SpkiPin> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    Some(Sha256::digest(cert.public_key().raw).into())
+}
+
+/// Refuses connections to servers whose certificate does not contain one of the pinned
+/// public keys, before any request is sent. Requires `tls_info` on the reqwest client.
+#[derive(Clone)]
+pub(crate) struct SpkiPinLayer {
+    pins: Arc<Vec<SpkiPin>>,
+}
+
+impl SpkiPinLayer {
+    pub(crate) fn new(pins: Vec<SpkiPin>) -> SpkiPinLayer {
+        SpkiPinLayer {
+            pins: Arc::new(pins),
+        }
+    }
+}
+
+impl<S> Layer<S> for SpkiPinLayer {
+    type Service = SpkiPinService<S>;
+
+    fn layer(&self, inner: S) -> SpkiPinService<S> {
+        SpkiPinService {
+            inner,
+            pins: self.pins.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct SpkiPinService<S> {
+    inner: S,
+    pins: Arc<Vec<SpkiPin>>,
+}
+
+impl<S, R> Service<R> for SpkiPinService<S>
+where
+    S: Service<R, Error = BoxError>,
+    S::Response: Connection,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let connecting = self.inner.call(request);
+        let pins = self.pins.clone();
+        Box::pin(async move {
+            let connection = connecting.await?;
+            let mut extensions = http::Extensions::new();
+            connection.connected().get_extras(&mut extensions);
+            let pin = extensions
+                .get::<TlsInfo>()
+                .and_then(TlsInfo::peer_certificate)
+                .and_then(spki_pin);
+            match pin {
+                Some(pin) if pins.contains(&pin) => Ok(connection),
+                Some(pin) => Err(format!(
+                    "The public key of the KME, sha256//{}, is not pinned",
+                    Base64::encode_string(&pin)
+                )
+                .into()),
+                None => {
+                    Err("No server certificate to check the SPKI pins against".into())
+                }
+            }
+        })
+    }
+}
+
+/// Resolves the server name of the KME to the host it is reached at, so the certificate
+/// is verified against the server name while connecting to another host or IP address.
+pub(crate) struct ServerNameResolver {
+    pub(crate) server_name: String,
+    pub(crate) host: String,
+}
+
+impl Resolve for ServerNameResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = if name.as_str().eq_ignore_ascii_case(&self.server_name) {
+            self.host.clone()
+        } else {
+            name.as_str().to_owned()
+        };
+        Box::pin(async move {
+            // The port is replaced by the port of the URL
+            let addrs = tokio::net::lookup_host((host.as_str(), 0)).await?;
+            Ok(Box::new(addrs.collect::<Vec<_>>().into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    fn resolve(resolver: &ServerNameResolver, name: &str) -> Vec<SocketAddr> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let addrs = runtime
+            .block_on(resolver.resolve(name.parse().unwrap()))
+            .unwrap();
+        addrs.collect()
+    }
+
+    #[test]
+    fn server_name_resolves_to_host() {
+        let resolver = ServerNameResolver {
+            server_name: "kme.example.org".to_owned(),
+            host: "192.0.2.10".to_owned(),
+        };
+        let host = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10));
+        assert_eq!(
+            resolve(&resolver, "kme.example.org"),
+            [SocketAddr::new(host, 0)]
+        );
+        assert_eq!(
+            resolve(&resolver, "KME.Example.org"),
+            [SocketAddr::new(host, 0)]
+        );
+        // Other names are resolved as usual
+        let localhost = resolve(&resolver, "localhost");
+        assert!(!localhost.is_empty());
+        assert!(localhost.iter().all(|addr| addr.ip().is_loopback()));
+    }
+}
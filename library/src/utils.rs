@@ -1,7 +1,12 @@
 use crate::Error;
 use crate::error::ErrorType::InvalidArgument;
+use base64ct::{Base64, Encoding};
+use secrets::SecretVec;
 use std::fs;
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 pub fn read_file(path: &PathBuf) -> Result<Vec<u8>, Error> {
     fs::read(path).map_err(|e| {
@@ -12,3 +17,114 @@ pub fn read_file(path: &PathBuf) -> Result<Vec<u8>, Error> {
         )
     })
 }
+
+/// Create a new file only readable and writable by the current user.
+pub(crate) fn create_new_private(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// Read the file at `path` into protected memory, or create it with `len` random bytes.
+/// Errors are described by `error`.
+pub(crate) fn read_or_create_secret(
+    path: &Path,
+    len: usize,
+    error: fn(String, io::Error) -> Error,
+) -> Result<SecretVec<u8>, Error> {
+    match create_new_private(path) {
+        Ok(mut file) => {
+            let secret = SecretVec::random(len);
+            file.write_all(&secret.borrow())
+                .and_then(|_| file.sync_all())
+                .map_err(|e| error(format!("Error writing {}", path.display()), e))?;
+            Ok(secret)
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            read_secret(path, len, error)
+        }
+        Err(e) => Err(error(format!("Error creating {}", path.display()), e)),
+    }
+}
+
+/// Read `len` bytes from the file at `path` into protected memory.
+pub(crate) fn read_secret(
+    path: &Path,
+    len: usize,
+    error: fn(String, io::Error) -> Error,
+) -> Result<SecretVec<u8>, Error> {
+    let read_error = |e| error(format!("Error reading {}", path.display()), e);
+    let mut file = File::open(path).map_err(read_error)?;
+    let mut secret = SecretVec::zero(len);
+    file.read_exact(&mut secret.borrow_mut())
+        .map_err(read_error)?;
+    Ok(secret)
+}
+
+/// Encode DER as PEM with the given label. The result is stored in protected memory, as it
+/// might be a private key.
+pub fn der_to_pem(label: &str, der: &[u8]) -> SecretVec<u8> {
+    let header = format!("-----BEGIN {label}-----\n");
+    let footer = format!("-----END {label}-----\n");
+    // PEM bodies are wrapped at 64 characters, which is 48 bytes of input
+    let body_len: usize = der
+        .chunks(48)
+        .map(|chunk| Base64::encoded_len(chunk) + 1)
+        .sum();
+    SecretVec::new(header.len() + body_len + footer.len(), |pem| {
+        pem[..header.len()].copy_from_slice(header.as_bytes());
+        let mut position = header.len();
+        for chunk in der.chunks(48) {
+            position += Base64::encode(chunk, &mut pem[position..])
+                .expect("Error encoding base64")
+                .len();
+            pem[position] = b'\n';
+            position += 1;
+        }
+        pem[position..].copy_from_slice(footer.as_bytes());
+    })
+}
+
+/// Exclusive lock on a file, released when dropped. Holds a duplicate of the file
+/// descriptor, which shares the lock with the original.
+pub(crate) struct FileLock(#[cfg_attr(not(unix), allow(dead_code))] File);
+
+impl FileLock {
+    pub(crate) fn exclusive(file: File) -> std::io::Result<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::fd::AsRawFd;
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(FileLock(file))
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            use std::os::fd::AsRawFd;
+            unsafe { libc::flock(self.0.as_raw_fd(), libc::LOCK_UN) };
+        }
+    }
+}
+
+/// Contents of a file in `library/testdata`, or with a `mock:` prefix, in the testdata of
+/// the mock KME.
+#[cfg(test)]
+pub(crate) fn testdata(file: &str) -> Vec<u8> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let path = match file.strip_prefix("mock:") {
+        Some(file) => dir.join("../mock-kme/testdata").join(file),
+        None => dir.join("testdata").join(file),
+    };
+    fs::read(path).unwrap()
+}
@@ -0,0 +1,265 @@
+//! Reloading the client credentials when their files change, e.g. when a short-lived
+//! certificate is renewed.
+
+use crate::error::ErrorType::InvalidArgument;
+use crate::{ETSI014Client, Error};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+/// How long to wait for more changes, as a certificate and key are often written one
+/// after the other.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+/// Delay before retrying a failed reload, which may have read a new certificate with the
+/// old key.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+/// How often to check whether the watcher is stopped.
+const STOP_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Modification time, size and inode of a file, `None` if it does not exist.
+type FileVersion = Option<(Option<SystemTime>, u64, u64)>;
+
+fn file_version(path: &Path) -> FileVersion {
+    // Follows symlinks, which are swapped when Kubernetes updates a secret
+    let metadata = fs::metadata(path).ok()?;
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+    #[cfg(not(unix))]
+    let inode = 0;
+    Some((metadata.modified().ok(), metadata.len(), inode))
+}
+
+/// Versions of the files and of the files in the directories, to tell whether the
+/// credentials changed since they were loaded.
+fn versions(paths: &[PathBuf]) -> Vec<(PathBuf, FileVersion)> {
+    let mut versions = Vec::new();
+    for path in paths {
+        versions.push((path.clone(), file_version(path)));
+        if let Ok(entries) = fs::read_dir(path) {
+            let mut files: Vec<_> = entries.flatten().map(|entry| entry.path()).collect();
+            files.sort();
+            versions.extend(files.into_iter().map(|file| {
+                let version = file_version(&file);
+                (file, version)
+            }));
+        }
+    }
+    versions
+}
+
+/// Changes in the directories containing the credential files, from inotify.
+#[cfg(target_os = "linux")]
+struct Changes {
+    fd: std::os::fd::OwnedFd,
+}
+
+#[cfg(target_os = "linux")]
+impl Changes {
+    fn new(paths: &[PathBuf]) -> io::Result<Changes> {
+        use std::collections::BTreeSet;
+        use std::ffi::CString;
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+        use std::os::unix::ffi::OsStrExt;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // Files are usually replaced by renaming a new file over them, which is only seen
+        // in the directory
+        let mut dirs = BTreeSet::new();
+        for path in paths {
+            if path.is_dir() {
+                dirs.insert(path.clone());
+            }
+            match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => {
+                    dirs.insert(parent.to_path_buf())
+                }
+                _ => dirs.insert(PathBuf::from(".")),
+            };
+        }
+        let mask = libc::IN_CLOSE_WRITE
+            | libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO;
+        for dir in dirs {
+            let c_dir = CString::new(dir.as_os_str().as_bytes())?;
+            if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), c_dir.as_ptr(), mask) }
+                < 0
+            {
+                let e = io::Error::last_os_error();
+                return Err(io::Error::new(e.kind(), format!("{}: {e}", dir.display())));
+            }
+        }
+        Ok(Changes { fd })
+    }
+
+    /// Wait up to `timeout` for changes, returns whether there were any.
+    fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+        use std::os::fd::AsRawFd;
+
+        let mut poll_fd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready =
+            unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int) };
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            return if e.kind() == io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(e)
+            };
+        }
+        if ready == 0 {
+            return Ok(false);
+        }
+        // Which files changed does not matter, the versions are compared before reloading
+        let mut buffer = [0u8; 4096];
+        while unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+            )
+        } > 0
+        {}
+        Ok(true)
+    }
+}
+
+/// Changes found by comparing the versions of the files every few seconds, on systems
+/// without inotify.
+#[cfg(not(target_os = "linux"))]
+struct Changes {
+    paths: Vec<PathBuf>,
+    versions: Vec<(PathBuf, FileVersion)>,
+    last_poll: Instant,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Changes {
+    const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+    fn new(paths: &[PathBuf]) -> io::Result<Changes> {
+        Ok(Changes {
+            paths: paths.to_vec(),
+            versions: versions(paths),
+            last_poll: Instant::now(),
+        })
+    }
+
+    fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+        std::thread::sleep(timeout);
+        if self.last_poll.elapsed() < Self::POLL_INTERVAL {
+            return Ok(false);
+        }
+        self.last_poll = Instant::now();
+        let versions = versions(&self.paths);
+        let changed = versions != self.versions;
+        self.versions = versions;
+        Ok(changed)
+    }
+}
+
+/// Reloads the credentials of a client when their files change, until it is dropped. See
+/// [`ETSI014Client::watch_credentials`].
+#[derive(Debug)]
+pub struct CredentialWatcher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CredentialWatcher {
+    pub(crate) fn start(
+        client: Weak<ETSI014Client>,
+        paths: Vec<PathBuf>,
+    ) -> Result<CredentialWatcher, Error> {
+        let watch_error = |e: io::Error| {
+            Error::new(
+                "Error watching credential files".to_string(),
+                InvalidArgument,
+                Some(Box::new(e)),
+            )
+        };
+        if paths.is_empty() {
+            return Err(Error::new(
+                "No credential files to watch, the credentials were set from memory"
+                    .to_string(),
+                InvalidArgument,
+                None,
+            ));
+        }
+        let changes = Changes::new(&paths).map_err(watch_error)?;
+        info!(?paths, "Watching client credentials");
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::Builder::new()
+            .name("etsi014-credentials".to_owned())
+            .spawn({
+                let stop = stop.clone();
+                move || watch(client, paths, changes, &stop)
+            })
+            .map_err(watch_error)?;
+        Ok(CredentialWatcher {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for CredentialWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn watch(
+    client: Weak<ETSI014Client>,
+    paths: Vec<PathBuf>,
+    mut changes: Changes,
+    stop: &AtomicBool,
+) {
+    let mut loaded = versions(&paths);
+    let mut reload_at = None;
+    while !stop.load(Ordering::Relaxed) {
+        match changes.wait(STOP_INTERVAL) {
+            Ok(true) => reload_at = Some(Instant::now() + DEBOUNCE),
+            Ok(false) => {}
+            Err(e) => {
+                warn!(error = %e, "Error watching credential files, stopped reloading");
+                return;
+            }
+        }
+        if reload_at.is_none_or(|reload_at| Instant::now() < reload_at) {
+            continue;
+        }
+        reload_at = None;
+        let current = versions(&paths);
+        if current == loaded {
+            continue;
+        }
+        let Some(client) = client.upgrade() else {
+            return;
+        };
+        match client.reload_credentials() {
+            Ok(()) => loaded = current,
+            Err(e) => {
+                warn!(kind = ?e.kind, error = %e.msg, "Error reloading client credentials");
+                reload_at = Some(Instant::now() + RETRY_DELAY);
+            }
+        }
+    }
+}
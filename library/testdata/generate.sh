@@ -0,0 +1,36 @@
+#!/bin/sh
+# Generate the private keys and PKCS#12 bundles read by the tests, from the key and
+# certificate of client-1 of the mock KME and a new RSA key.
+set -eu
+
+cd "$(dirname "$0")"
+mock=../../mock-kme/testdata
+pass=pass:passphrase
+
+# EC key in every supported format
+openssl pkcs8 -topk8 -nocrypt -in "$mock/client-1.key" -outform DER -out ec.der
+openssl ec -in "$mock/client-1.key" -out ec-sec1.key
+openssl ec -in "$mock/client-1.key" -outform DER -out ec-sec1.der
+openssl pkcs8 -topk8 -v2 aes-256-cbc -v2prf hmacWithSHA256 -in "$mock/client-1.key" \
+    -passout "$pass" -out ec-aes.key
+openssl pkcs8 -topk8 -v2 aes-128-cbc -v2prf hmacWithSHA1 -in "$mock/client-1.key" \
+    -passout "$pass" -outform DER -out ec-aes.der
+
+# RSA key as PKCS#8 and PKCS#1
+openssl genpkey -algorithm RSA -pkeyopt rsa_keygen_bits:2048 -out rsa.key
+openssl rsa -in rsa.key -traditional -out rsa-pkcs1.key
+openssl rsa -in rsa.key -traditional -outform DER -out rsa-pkcs1.der
+
+# Legacy encryption, which is refused
+openssl pkcs8 -topk8 -v1 PBE-SHA1-3DES -in "$mock/client-1.key" -passout "$pass" \
+    -out ec-3des.key
+openssl ec -in "$mock/client-1.key" -aes128 -passout "$pass" -out ec-openssl.key
+
+# PKCS#12 bundles with the chain, with the defaults of OpenSSL 3 and of OpenSSL 1
+openssl pkcs12 -export -inkey "$mock/client-1.key" -in "$mock/client-1.crt" \
+    -certfile "$mock/ca.crt" -passout "$pass" -out client-1.p12
+openssl pkcs12 -export -legacy -inkey "$mock/client-1.key" -in "$mock/client-1.crt" \
+    -certfile "$mock/ca.crt" -passout "$pass" -out client-1-legacy.p12
+openssl pkcs12 -export -keypbe PBE-SHA1-3DES -certpbe PBE-SHA1-3DES -macalg sha1 \
+    -inkey "$mock/client-1.key" -in "$mock/client-1.crt" -passout "$pass" \
+    -out client-1-3des.p12
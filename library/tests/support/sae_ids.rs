@@ -0,0 +1,17 @@
+//! Proptest strategy shared by the unit tests in `src/lib.rs` and the integration tests.
+
+use super::SaeId;
+use proptest::prelude::*;
+
+/// Valid SAE IDs of arbitrary Unicode, mixed with characters that have a meaning in URLs.
+pub fn sae_ids() -> impl Strategy<Value = SaeId> {
+    const SPECIAL: &[&str] = &[
+        "/", "?", "#", "%", "%2F", "%25", ".", "..", "\\", ";", "=", "&", "+", " ",
+    ];
+    let part = prop_oneof![
+        any::<String>().prop_map(|s| s.chars().filter(|c| !c.is_control()).collect()),
+        prop::sample::select(SPECIAL).prop_map(str::to_owned),
+    ];
+    prop::collection::vec(part, 1..6)
+        .prop_filter_map("Invalid SAE ID", |parts| SaeId::new(parts.concat()).ok())
+}
@@ -0,0 +1,506 @@
+//! Mock ETSI GS QKD 014 KME for testing clients. It serves the key delivery API over mutual
+//! TLS with the certificates in `testdata`, hands out keys that can be retrieved once by
+//! the target SAE, and records every request.
+
+use base64ct::{Base64, Encoding};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::TokioIo;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::RootCertStore;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+pub use hyper::{Method, StatusCode};
+
+const PATH_PREFIX: &str = "/api/v1/keys/";
+/// ID of the mock KME, and of the KME of the target SAEs.
+pub const KME_ID: &str = "kme-1";
+pub const TARGET_KME_ID: &str = "kme-2";
+/// Key size used when a request does not give one.
+pub const DEFAULT_KEY_SIZE: u32 = 256;
+pub const MAX_KEY_PER_REQUEST: u32 = 128;
+pub const MIN_KEY_SIZE: u32 = 64;
+pub const MAX_KEY_SIZE: u32 = 1024;
+/// Stored key count reported in the status until changed.
+pub const STORED_KEY_COUNT: u32 = 25000;
+
+/// Path of a file in `testdata`: the CA `ca.crt`, the certificate of the KME `kme.crt`
+/// for `localhost` and `127.0.0.1`, and certificates of the SAEs `client-1.crt` to
+/// `client-3.crt`, each with a `.key` file.
+pub fn testdata(file: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata")
+        .join(file)
+}
+
+/// Request received by the mock KME.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedRequest {
+    /// Common name of the client certificate.
+    pub sae_id: String,
+    pub method: Method,
+    /// Target SAE ID in the path, percent-decoded.
+    pub target_sae_id: String,
+    /// `status`, `enc_keys` or `dec_keys`.
+    pub endpoint: String,
+    pub query: Option<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct StoredKey {
+    /// SAE that requested the key with `enc_keys`.
+    master_sae_id: String,
+    /// SAEs that may retrieve the key with `dec_keys`.
+    sae_ids: Vec<String>,
+    key: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct State {
+    requests: Vec<RecordedRequest>,
+    failures: VecDeque<(StatusCode, String)>,
+    keys: HashMap<String, StoredKey>,
+    next_key: u64,
+    stored_key_count: u32,
+    max_keys_per_response: Option<u32>,
+}
+
+/// Error returned as an ETSI GS QKD 014 error body.
+struct ApiError(StatusCode, String);
+
+fn bad_request(message: impl Into<String>) -> ApiError {
+    ApiError(StatusCode::BAD_REQUEST, message.into())
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct KeyRequest {
+    number: Option<u32>,
+    size: Option<u32>,
+    #[serde(rename = "additional_slave_SAE_IDs", default)]
+    additional_slave_sae_ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyIdRequest {
+    #[serde(rename = "key_ID")]
+    key_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyIdsRequest {
+    #[serde(rename = "key_IDs")]
+    key_ids: Vec<KeyIdRequest>,
+}
+
+/// Running mock KME, which is stopped when dropped.
+#[derive(Debug)]
+pub struct MockKme {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockKme {
+    /// Start a KME on a free port of `127.0.0.1`.
+    pub fn start() -> MockKme {
+        MockKme::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .expect("Error starting mock KME")
+    }
+
+    /// Start a KME listening on `addr`, serving connections on a thread of its own.
+    pub fn bind(addr: SocketAddr) -> io::Result<MockKme> {
+        let acceptor = tls_acceptor()?;
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State {
+            requests: Vec::new(),
+            failures: VecDeque::new(),
+            keys: HashMap::new(),
+            next_key: 0,
+            stored_key_count: STORED_KEY_COUNT,
+            max_keys_per_response: None,
+        }));
+        let (shutdown, stopped) = oneshot::channel();
+        let server_state = state.clone();
+        let thread = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Error creating runtime");
+            runtime.block_on(serve(listener, acceptor, server_state, stopped));
+        });
+        Ok(MockKme {
+            addr,
+            state,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Requests received so far.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state().requests.clone()
+    }
+
+    /// Answer the next request with an error body with `code` and `message`, after
+    /// recording it.
+    pub fn fail_next(&self, code: StatusCode, message: &str) {
+        self.state().failures.push_back((code, message.to_owned()));
+    }
+
+    /// Stored key count reported in the status, which decreases with every key handed
+    /// out with `enc_keys`.
+    pub fn set_stored_key_count(&self, count: u32) {
+        self.state().stored_key_count = count;
+    }
+
+    /// Hand out at most `max` keys per `enc_keys` response, fewer than requested, like a
+    /// KME running low on keys.
+    pub fn set_max_keys_per_response(&self, max: u32) {
+        self.state().max_keys_per_response = Some(max);
+    }
+
+    /// Keys handed out with `enc_keys` that were not retrieved with `dec_keys` yet.
+    pub fn pending_keys(&self) -> usize {
+        self.state().keys.len()
+    }
+}
+
+impl Drop for MockKme {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn tls_acceptor() -> io::Result<TlsAcceptor> {
+    let pem_error = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    let certs = CertificateDer::pem_file_iter(testdata("kme.crt"))
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(pem_error)?;
+    let key = PrivateKeyDer::from_pem_file(testdata("kme.key")).map_err(pem_error)?;
+    let mut roots = RootCertStore::empty();
+    for ca in CertificateDer::pem_file_iter(testdata("ca.crt")).map_err(pem_error)? {
+        roots
+            .add(ca.map_err(pem_error)?)
+            .map_err(io::Error::other)?;
+    }
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .build()
+        .map_err(io::Error::other)?;
+    let server_config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+async fn serve(
+    listener: std::net::TcpListener,
+    acceptor: TlsAcceptor,
+    state: Arc<Mutex<State>>,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let listener = TcpListener::from_std(listener).expect("Error listening");
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(_) => continue,
+            },
+            _ = &mut stopped => return,
+        };
+        tokio::spawn(serve_connection(acceptor.clone(), state.clone(), stream));
+    }
+}
+
+/// Common name of the client certificate, which identifies the SAE.
+fn certificate_cn(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(str::to_owned)
+}
+
+async fn serve_connection(
+    acceptor: TlsAcceptor,
+    state: Arc<Mutex<State>>,
+    stream: TcpStream,
+) {
+    // Failed handshakes are up to the tests to check on the client side
+    let Ok(stream) = acceptor.accept(stream).await else {
+        return;
+    };
+    let cn = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(certificate_cn)
+        .unwrap_or_default();
+    let service = service_fn(|request| {
+        let state = state.clone();
+        let cn = cn.clone();
+        async move { Ok::<_, Infallible>(handle(&state, cn, request).await) }
+    });
+    let _ = http1::Builder::new()
+        .serve_connection(TokioIo::new(stream), service)
+        .await;
+}
+
+fn json_response(code: StatusCode, body: &Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .expect("Error building response")
+}
+
+async fn handle(
+    state: &Mutex<State>,
+    sae_id: String,
+    request: Request<Incoming>,
+) -> Response<Full<Bytes>> {
+    let (parts, body) = request.into_parts();
+    let body = match Limited::new(body, 64 * 1024).collect().await {
+        Ok(body) => body.to_bytes().to_vec(),
+        Err(e) => {
+            let body = json!({ "message": format!("Error reading request body: {e}") });
+            return json_response(StatusCode::BAD_REQUEST, &body);
+        }
+    };
+    let path = parts.uri.path();
+    let Some((target_sae_id, endpoint)) = path
+        .strip_prefix(PATH_PREFIX)
+        .and_then(|rest| rest.split_once('/'))
+    else {
+        let body = json!({ "message": format!("Unknown path {path}") });
+        return json_response(StatusCode::NOT_FOUND, &body);
+    };
+    let request = RecordedRequest {
+        sae_id,
+        method: parts.method.clone(),
+        target_sae_id: percent_decode_str(target_sae_id)
+            .decode_utf8_lossy()
+            .into_owned(),
+        endpoint: endpoint.to_owned(),
+        query: parts.uri.query().map(str::to_owned),
+        body,
+    };
+    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+    state.requests.push(request.clone());
+    let result = match state.failures.pop_front() {
+        Some((code, message)) => Err(ApiError(code, message)),
+        None => state.respond(&request),
+    };
+    match result {
+        Ok(body) => json_response(StatusCode::OK, &body),
+        Err(ApiError(code, message)) => {
+            json_response(code, &json!({ "message": message }))
+        }
+    }
+}
+
+fn query_params(request: &RecordedRequest) -> Vec<(String, String)> {
+    let query = request.query.as_deref().unwrap_or_default();
+    url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect()
+}
+
+fn query_number(
+    params: &[(String, String)],
+    name: &str,
+) -> Result<Option<u32>, ApiError> {
+    params
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| {
+            value
+                .parse()
+                .map_err(|_| bad_request(format!("Invalid {name}: {value}")))
+        })
+        .transpose()
+}
+
+fn json_body<T: for<'de> Deserialize<'de>>(
+    request: &RecordedRequest,
+) -> Result<T, ApiError> {
+    serde_json::from_slice(&request.body)
+        .map_err(|e| bad_request(format!("Invalid request body: {e}")))
+}
+
+impl State {
+    fn respond(&mut self, request: &RecordedRequest) -> Result<Value, ApiError> {
+        let method = &request.method;
+        match (request.endpoint.as_str(), method) {
+            ("status", &Method::GET) => Ok(self.status(request)),
+            ("enc_keys", &Method::GET | &Method::POST) => self.enc_keys(request),
+            ("dec_keys", &Method::GET | &Method::POST) => self.dec_keys(request),
+            ("status" | "enc_keys" | "dec_keys", _) => Err(ApiError(
+                StatusCode::METHOD_NOT_ALLOWED,
+                format!("Method {method} is not supported"),
+            )),
+            (endpoint, _) => Err(ApiError(
+                StatusCode::NOT_FOUND,
+                format!("Unknown endpoint {endpoint}"),
+            )),
+        }
+    }
+
+    fn status(&self, request: &RecordedRequest) -> Value {
+        json!({
+            "source_KME_ID": KME_ID,
+            "target_KME_ID": TARGET_KME_ID,
+            "master_SAE_ID": request.sae_id,
+            "slave_SAE_ID": request.target_sae_id,
+            "key_size": DEFAULT_KEY_SIZE,
+            "stored_key_count": self.stored_key_count,
+            "max_key_count": 100000,
+            "max_key_per_request": MAX_KEY_PER_REQUEST,
+            "max_key_size": MAX_KEY_SIZE,
+            "min_key_size": MIN_KEY_SIZE,
+            "max_SAE_ID_count": 8,
+        })
+    }
+
+    fn enc_keys(&mut self, request: &RecordedRequest) -> Result<Value, ApiError> {
+        let key_request = if request.method == Method::POST {
+            json_body::<KeyRequest>(request)?
+        } else {
+            let params = query_params(request);
+            KeyRequest {
+                number: query_number(&params, "number")?,
+                size: query_number(&params, "size")?,
+                ..Default::default()
+            }
+        };
+        let number = key_request.number.unwrap_or(1);
+        let size = key_request.size.unwrap_or(DEFAULT_KEY_SIZE);
+        if !(1..=MAX_KEY_PER_REQUEST).contains(&number) {
+            return Err(bad_request(format!("Invalid number of keys {number}")));
+        }
+        if !(MIN_KEY_SIZE..=MAX_KEY_SIZE).contains(&size) || size % 8 != 0 {
+            return Err(bad_request(format!("Invalid key size {size}")));
+        }
+        let mut sae_ids = key_request.additional_slave_sae_ids;
+        sae_ids.push(request.target_sae_id.clone());
+        let number = self
+            .max_keys_per_response
+            .map_or(number, |max| number.min(max));
+        let mut keys = Vec::new();
+        for _ in 0..number {
+            let (key_id, key) = self.new_key(size as usize / 8);
+            keys.push(json!({ "key_ID": key_id, "key": Base64::encode_string(&key) }));
+            let stored = StoredKey {
+                master_sae_id: request.sae_id.clone(),
+                sae_ids: sae_ids.clone(),
+                key,
+            };
+            self.keys.insert(key_id, stored);
+        }
+        self.stored_key_count = self.stored_key_count.saturating_sub(number);
+        Ok(json!({ "keys": keys }))
+    }
+
+    /// A new key ID and key of `len` bytes, derived from a counter.
+    fn new_key(&mut self, len: usize) -> (String, Vec<u8>) {
+        let n = self.next_key;
+        self.next_key += 1;
+        let mut key = Vec::with_capacity(len);
+        for block in 0u64.. {
+            if key.len() >= len {
+                break;
+            }
+            let hash = Sha256::new()
+                .chain_update(n.to_be_bytes())
+                .chain_update(block.to_be_bytes())
+                .finalize();
+            key.extend_from_slice(&hash);
+        }
+        key.truncate(len);
+        (format!("00000000-0000-4000-8000-{n:012x}"), key)
+    }
+
+    fn dec_keys(&mut self, request: &RecordedRequest) -> Result<Value, ApiError> {
+        let key_ids: Vec<String> = if request.method == Method::POST {
+            json_body::<KeyIdsRequest>(request)?
+                .key_ids
+                .into_iter()
+                .map(|k| k.key_id)
+                .collect()
+        } else {
+            query_params(request)
+                .into_iter()
+                .filter(|(key, _)| key == "key_ID")
+                .map(|(_, value)| value)
+                .collect()
+        };
+        if key_ids.is_empty() {
+            return Err(bad_request("No key IDs"));
+        }
+        for key_id in &key_ids {
+            match self.keys.get(key_id) {
+                Some(stored) if stored.master_sae_id != request.target_sae_id => {
+                    return Err(bad_request(format!("Key {key_id} not found")));
+                }
+                None => return Err(bad_request(format!("Key {key_id} not found"))),
+                Some(stored) if !stored.sae_ids.contains(&request.sae_id) => {
+                    return Err(ApiError(
+                        StatusCode::UNAUTHORIZED,
+                        format!("{} may not retrieve key {key_id}", request.sae_id),
+                    ));
+                }
+                Some(_) => {}
+            }
+        }
+        // Keys are handed out once
+        let keys: Vec<Value> = key_ids
+            .iter()
+            .filter_map(|key_id| {
+                let stored = self.keys.remove(key_id)?;
+                Some(json!({ "key_ID": key_id, "key": Base64::encode_string(&stored.key) }))
+            })
+            .collect();
+        Ok(json!({ "keys": keys }))
+    }
+}
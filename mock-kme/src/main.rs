@@ -0,0 +1,32 @@
+use clap::Parser;
+use etsi014_mock_kme::{MockKme, testdata};
+use std::net::SocketAddr;
+use std::process::exit;
+use std::thread;
+
+/// Serve a mock ETSI GS QKD 014 KME for testing clients, until interrupted.
+#[derive(Debug, Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    #[arg(long, default_value = "127.0.0.1:8443")]
+    listen: SocketAddr,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let kme = match MockKme::bind(cli.listen) {
+        Ok(kme) => kme,
+        Err(e) => {
+            eprintln!("Error starting mock KME on {}: {e}", cli.listen);
+            exit(1);
+        }
+    };
+    println!(
+        "Listening on {}, certificates in {}",
+        kme.addr(),
+        testdata("").display()
+    );
+    loop {
+        thread::park();
+    }
+}
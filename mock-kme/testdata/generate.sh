@@ -0,0 +1,29 @@
+#!/bin/sh
+# Generate the CA and the certificates of the mock KME and its SAEs, valid for 100 years.
+set -eu
+
+cd "$(dirname "$0")"
+days=36500
+ext=$(mktemp)
+trap 'rm -f "$ext" ca.key ca.srl ./*.csr' EXIT
+
+new_key() {
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1.key"
+}
+
+# issue NAME COMMON_NAME EXTENSIONS
+issue() {
+    new_key "$1"
+    openssl req -new -key "$1.key" -subj "/CN=$2" -out "$1.csr"
+    printf '%s\n' "$3" > "$ext"
+    openssl x509 -req -in "$1.csr" -CA ca.crt -CAkey ca.key -CAcreateserial \
+        -days "$days" -extfile "$ext" -out "$1.crt"
+}
+
+new_key ca
+openssl req -x509 -key ca.key -subj "/CN=Mock KME CA" -days "$days" -out ca.crt
+issue kme kme-1 "subjectAltName = DNS:localhost, IP:127.0.0.1
+extendedKeyUsage = serverAuth"
+for sae in client-1 client-2 client-3; do
+    issue "$sae" "$sae" "extendedKeyUsage = clientAuth"
+done
@@ -0,0 +1,74 @@
+from collections.abc import Awaitable, Sequence
+from contextlib import AbstractContextManager
+from os import PathLike
+
+class ETSI014Error(Exception):
+    """Arguments are the message and the kind of error, e.g. "InvalidResponse"."""
+
+class Status:
+    source_kme_id: str
+    target_kme_id: str
+    source_sae_id: str
+    target_sae_id: str
+    key_size: int
+    stored_key_count: int
+    max_key_count: int
+    max_key_per_request: int
+    max_key_size: int
+    min_key_size: int
+    max_sae_id_count: int
+
+class Key:
+    key_id: str
+    def unlocked(self) -> AbstractContextManager[memoryview]: ...
+    def __len__(self) -> int: ...
+
+class ETSI014Client:
+    def __init__(
+        self,
+        host: str,
+        port: int,
+        cert: str | PathLike[str],
+        key: str | PathLike[str],
+        server_ca: str | PathLike[str],
+        *,
+        timeout: float | None = None,
+        connect_timeout: float | None = None,
+        retries: int = 0,
+        retry_delay: float = 1.0,
+        verbose_diagnostics: bool = False,
+    ) -> None: ...
+    @staticmethod
+    def from_pem(
+        host: str,
+        port: int,
+        cert: bytes,
+        key: bytes,
+        server_ca: bytes,
+        *,
+        timeout: float | None = None,
+        connect_timeout: float | None = None,
+        retries: int = 0,
+        retry_delay: float = 1.0,
+        verbose_diagnostics: bool = False,
+    ) -> ETSI014Client: ...
+    def get_status(self, target_sae_id: str) -> Status: ...
+    def get_status_async(self, target_sae_id: str) -> Awaitable[Status]: ...
+    def get_keys(
+        self,
+        target_sae_id: str,
+        amount: int = 1,
+        key_size: int = 256,
+        additional_target_sae_ids: Sequence[str] = (),
+    ) -> list[Key]: ...
+    def get_keys_async(
+        self,
+        target_sae_id: str,
+        amount: int = 1,
+        key_size: int = 256,
+        additional_target_sae_ids: Sequence[str] = (),
+    ) -> Awaitable[list[Key]]: ...
+    def get_keys_by_ids(self, target_sae_id: str, key_ids: Sequence[str]) -> list[Key]: ...
+    def get_keys_by_ids_async(
+        self, target_sae_id: str, key_ids: Sequence[str]
+    ) -> Awaitable[list[Key]]: ...
@@ -0,0 +1,518 @@
+use crate::runtime::{block_on, future_into_py, wait_for_pending};
+use ::etsi014_client::etsi014_client::SecretVec;
+use ::etsi014_client::{ETSI014ClientBuilder, Error, KeyId, SaeId};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyBufferError, PyException, PyRuntimeError};
+use pyo3::ffi;
+use pyo3::prelude::*;
+use pyo3::types::PyMemoryView;
+use std::fs;
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+mod runtime;
+
+create_exception!(
+    etsi014_client,
+    ETSI014Error,
+    PyException,
+    "Error raised by the client. The arguments are the message and the kind of error."
+);
+
+fn to_py_err(e: Error) -> PyErr {
+    ETSI014Error::new_err((e.to_string(), format!("{:?}", e.kind)))
+}
+
+fn sae_ids(ids: Vec<String>) -> PyResult<Vec<SaeId>> {
+    ids.into_iter()
+        .map(|id| SaeId::new(id).map_err(to_py_err))
+        .collect()
+}
+
+/// KME status as returned by `ETSI014Client.get_status`.
+#[pyclass(frozen, get_all, module = "etsi014_client")]
+struct Status {
+    source_kme_id: String,
+    target_kme_id: String,
+    source_sae_id: String,
+    target_sae_id: String,
+    key_size: u32,
+    stored_key_count: u32,
+    max_key_count: u32,
+    max_key_per_request: u32,
+    max_key_size: u32,
+    min_key_size: u32,
+    max_sae_id_count: u32,
+}
+
+#[pymethods]
+impl Status {
+    fn __repr__(&self) -> String {
+        format!(
+            "Status(source_kme_id={:?}, target_kme_id={:?}, source_sae_id={:?}, \
+            target_sae_id={:?}, key_size={}, stored_key_count={}, max_key_count={}, \
+            max_key_per_request={}, max_key_size={}, min_key_size={}, max_sae_id_count={})",
+            self.source_kme_id,
+            self.target_kme_id,
+            self.source_sae_id,
+            self.target_sae_id,
+            self.key_size,
+            self.stored_key_count,
+            self.max_key_count,
+            self.max_key_per_request,
+            self.max_key_size,
+            self.min_key_size,
+            self.max_sae_id_count,
+        )
+    }
+}
+
+impl From<::etsi014_client::Status> for Status {
+    fn from(s: ::etsi014_client::Status) -> Self {
+        Status {
+            source_kme_id: s.source_kme_id.into(),
+            target_kme_id: s.target_kme_id.into(),
+            source_sae_id: s.source_sae_id.into(),
+            target_sae_id: s.target_sae_id.into(),
+            key_size: s.key_size,
+            stored_key_count: s.stored_key_count,
+            max_key_count: s.max_key_count,
+            max_key_per_request: s.max_key_per_request,
+            max_key_size: s.max_key_size,
+            min_key_size: s.min_key_size,
+            max_sae_id_count: s.max_sae_id_count,
+        }
+    }
+}
+
+#[allow(dyn_drop)]
+struct Unlocked {
+    /// Memoryview returned by `KeyUnlocked.__enter__`, released by `__exit__`.
+    view: Option<Py<PyAny>>,
+    buf: *const u8,
+    len: usize,
+    /// Buffers exported by `KeyBuffer`, which must all be released before the memory is
+    /// protected again.
+    exports: usize,
+    _borrow: Box<dyn Drop>,
+}
+
+struct KeyBytes {
+    // Declared before `secret` so the borrow is dropped first
+    unlocked: Option<Unlocked>,
+    // Boxed so the address stays the same while it is borrowed by `unlocked`
+    secret: Box<SecretVec<u8>>,
+}
+
+// SAFETY: the borrow in `unlocked` is only created, used and dropped while holding the mutex
+// around `KeyBytes`.
+unsafe impl Send for KeyBytes {}
+
+/// QKD key stored in protected memory. The key bytes can only be read inside
+/// `with key.unlocked() as buf:`.
+#[pyclass(frozen, module = "etsi014_client")]
+struct Key {
+    #[pyo3(get)]
+    key_id: String,
+    size: usize,
+    bytes: Mutex<KeyBytes>,
+}
+
+impl Key {
+    fn new(key_id: String, secret: SecretVec<u8>) -> Self {
+        Key {
+            key_id,
+            size: secret.len(),
+            bytes: Mutex::new(KeyBytes {
+                unlocked: None,
+                secret: Box::new(secret),
+            }),
+        }
+    }
+}
+
+#[pymethods]
+impl Key {
+    /// Context manager giving a read-only memoryview of the key bytes. The memoryview is
+    /// released and the memory protected again when the context exits.
+    fn unlocked(slf: Py<Self>) -> KeyUnlocked {
+        KeyUnlocked { key: slf }
+    }
+
+    /// Key size in bytes.
+    fn __len__(&self) -> usize {
+        self.size
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Key(key_id={:?}, size={})", self.key_id, self.size)
+    }
+}
+
+/// Exports the bytes of an unlocked key with the buffer protocol. Every memoryview of the
+/// key references it, so the key cannot be freed while its bytes are exported.
+#[pyclass(frozen, module = "etsi014_client")]
+struct KeyBuffer {
+    key: Py<Key>,
+}
+
+#[pymethods]
+impl KeyBuffer {
+    unsafe fn __getbuffer__(
+        slf: Bound<'_, Self>,
+        view: *mut ffi::Py_buffer,
+        flags: c_int,
+    ) -> PyResult<()> {
+        if view.is_null() {
+            return Err(PyBufferError::new_err("View is null"));
+        }
+        if flags & ffi::PyBUF_WRITABLE != 0 {
+            return Err(PyBufferError::new_err("Key is read-only"));
+        }
+        let mut bytes = slf.get().key.get().bytes.lock().unwrap();
+        let Some(unlocked) = bytes.unlocked.as_mut() else {
+            return Err(PyBufferError::new_err("Key is not unlocked"));
+        };
+        unlocked.exports += 1;
+        // SAFETY: `view` is a valid Py_buffer passed by Python. The memory stays unprotected
+        // until the export is released, see KeyUnlocked.__exit__.
+        unsafe {
+            (*view).buf = unlocked.buf as *mut c_void;
+            (*view).len = unlocked.len as ffi::Py_ssize_t;
+            (*view).readonly = 1;
+            (*view).itemsize = 1;
+            (*view).format = if flags & ffi::PyBUF_FORMAT != 0 {
+                c"B".as_ptr() as *mut c_char
+            } else {
+                ptr::null_mut()
+            };
+            (*view).ndim = 1;
+            (*view).shape = if flags & ffi::PyBUF_ND != 0 {
+                &mut (*view).len
+            } else {
+                ptr::null_mut()
+            };
+            (*view).strides = if flags & ffi::PyBUF_STRIDES == ffi::PyBUF_STRIDES {
+                &mut (*view).itemsize
+            } else {
+                ptr::null_mut()
+            };
+            (*view).suboffsets = ptr::null_mut();
+            (*view).internal = ptr::null_mut();
+            (*view).obj = slf.clone().into_any().into_ptr();
+        }
+        Ok(())
+    }
+
+    unsafe fn __releasebuffer__(&self, _view: *mut ffi::Py_buffer) {
+        let mut bytes = self.key.get().bytes.lock().unwrap();
+        if let Some(unlocked) = bytes.unlocked.as_mut() {
+            unlocked.exports -= 1;
+        }
+    }
+}
+
+#[pyclass(frozen, module = "etsi014_client")]
+struct KeyUnlocked {
+    key: Py<Key>,
+}
+
+#[pymethods]
+impl KeyUnlocked {
+    fn __enter__(&self, py: Python<'_>) -> PyResult<Py<PyAny>> {
+        let key = self.key.get();
+        {
+            let mut bytes = key.bytes.lock().unwrap();
+            if bytes.unlocked.is_some() {
+                return Err(PyRuntimeError::new_err("Key is already unlocked"));
+            }
+            // SAFETY: the box is neither moved nor dropped while the borrow exists, see
+            // KeyBytes.
+            let secret: &'static SecretVec<u8> =
+                unsafe { &*(bytes.secret.as_ref() as *const SecretVec<u8>) };
+            let borrow = secret.borrow();
+            bytes.unlocked = Some(Unlocked {
+                view: None,
+                buf: borrow.as_ptr(),
+                len: borrow.len(),
+                exports: 0,
+                _borrow: Box::new(borrow),
+            });
+        }
+        // The lock is not held, as creating the memoryview exports the buffer
+        let exporter = Bound::new(
+            py,
+            KeyBuffer {
+                key: self.key.clone_ref(py),
+            },
+        )?;
+        let view = match PyMemoryView::from(exporter.as_any()) {
+            Ok(view) => view.into_any().unbind(),
+            Err(e) => {
+                key.bytes.lock().unwrap().unlocked = None;
+                return Err(e);
+            }
+        };
+        let mut bytes = key.bytes.lock().unwrap();
+        if let Some(unlocked) = bytes.unlocked.as_mut() {
+            unlocked.view = Some(view.clone_ref(py));
+        }
+        Ok(view)
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(&self, py: Python<'_>, _args: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let key = self.key.get();
+        let view = key
+            .bytes
+            .lock()
+            .unwrap()
+            .unlocked
+            .as_ref()
+            .and_then(|unlocked| unlocked.view.as_ref().map(|view| view.clone_ref(py)));
+        if let Some(view) = view {
+            // Fails if the memoryview is still exported, e.g. by a numpy array
+            view.call_method0(py, "release")?;
+        }
+        let mut bytes = key.bytes.lock().unwrap();
+        if let Some(unlocked) = bytes.unlocked.as_mut() {
+            unlocked.view = None;
+            // Protecting the memory would crash the process on the next access of another
+            // memoryview of the key, so it stays unlocked until they are all released
+            if unlocked.exports > 0 {
+                return Err(PyBufferError::new_err(
+                    "Key is still exported by other memoryviews, release them first",
+                ));
+            }
+        }
+        bytes.unlocked = None;
+        Ok(false)
+    }
+}
+
+fn to_keys(keys: Vec<(KeyId, SecretVec<u8>)>) -> Vec<Key> {
+    keys.into_iter()
+        .map(|(key_id, secret)| Key::new(key_id.into(), secret))
+        .collect()
+}
+
+/// ETSI GS QKD 014 client. Methods ending in `_async` return awaitables for asyncio, the other
+/// methods block without holding the GIL.
+#[pyclass(frozen, name = "ETSI014Client", module = "etsi014_client")]
+struct Client {
+    inner: Arc<::etsi014_client::ETSI014Client>,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_client(
+    host: &str,
+    port: u16,
+    cert: &[u8],
+    key: &[u8],
+    server_ca: &[u8],
+    timeout: Option<f64>,
+    connect_timeout: Option<f64>,
+    retries: u32,
+    retry_delay: f64,
+    verbose_diagnostics: bool,
+) -> PyResult<Client> {
+    let client = ETSI014ClientBuilder::new(host, port)
+        .identity_pem(cert, key)
+        .server_ca_pem(server_ca)
+        .timeout(timeout.map(Duration::from_secs_f64))
+        .connect_timeout(connect_timeout.map(Duration::from_secs_f64))
+        .retries(retries, Duration::from_secs_f64(retry_delay))
+        .verbose_diagnostics(verbose_diagnostics)
+        .build()
+        .map_err(to_py_err)?;
+    Ok(Client {
+        inner: Arc::new(client),
+    })
+}
+
+#[pymethods]
+impl Client {
+    /// Create a client from PEM files. Timeouts and the delay between retries are in seconds.
+    /// `verbose_diagnostics` includes request and response bodies, which may contain keys,
+    /// in errors.
+    #[new]
+    #[pyo3(signature = (
+        host, port, cert, key, server_ca, *,
+        timeout=None, connect_timeout=None, retries=0, retry_delay=1.0,
+        verbose_diagnostics=false
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        host: &str,
+        port: u16,
+        cert: PathBuf,
+        key: PathBuf,
+        server_ca: PathBuf,
+        timeout: Option<f64>,
+        connect_timeout: Option<f64>,
+        retries: u32,
+        retry_delay: f64,
+        verbose_diagnostics: bool,
+    ) -> PyResult<Self> {
+        build_client(
+            host,
+            port,
+            &fs::read(cert)?,
+            &fs::read(key)?,
+            &fs::read(server_ca)?,
+            timeout,
+            connect_timeout,
+            retries,
+            retry_delay,
+            verbose_diagnostics,
+        )
+    }
+
+    /// Create a client from PEM encoded credentials in memory.
+    #[staticmethod]
+    #[pyo3(signature = (
+        host, port, cert, key, server_ca, *,
+        timeout=None, connect_timeout=None, retries=0, retry_delay=1.0,
+        verbose_diagnostics=false
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn from_pem(
+        host: &str,
+        port: u16,
+        cert: &[u8],
+        key: &[u8],
+        server_ca: &[u8],
+        timeout: Option<f64>,
+        connect_timeout: Option<f64>,
+        retries: u32,
+        retry_delay: f64,
+        verbose_diagnostics: bool,
+    ) -> PyResult<Self> {
+        build_client(
+            host,
+            port,
+            cert,
+            key,
+            server_ca,
+            timeout,
+            connect_timeout,
+            retries,
+            retry_delay,
+            verbose_diagnostics,
+        )
+    }
+
+    fn get_status(&self, py: Python<'_>, target_sae_id: String) -> PyResult<Status> {
+        let target_sae_id = SaeId::new(target_sae_id).map_err(to_py_err)?;
+        let client = self.inner.clone();
+        block_on(py, async move {
+            let status = client.get_status(&target_sae_id).await;
+            status.map(Status::from).map_err(to_py_err)
+        })
+    }
+
+    fn get_status_async<'py>(
+        &self,
+        py: Python<'py>,
+        target_sae_id: String,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let target_sae_id = SaeId::new(target_sae_id).map_err(to_py_err)?;
+        let client = self.inner.clone();
+        future_into_py(py, async move {
+            let status = client.get_status(&target_sae_id).await;
+            status.map(Status::from).map_err(to_py_err)
+        })
+    }
+
+    #[pyo3(signature = (target_sae_id, amount=1, key_size=256, additional_target_sae_ids=vec![]))]
+    fn get_keys(
+        &self,
+        py: Python<'_>,
+        target_sae_id: String,
+        amount: u32,
+        key_size: u32,
+        additional_target_sae_ids: Vec<String>,
+    ) -> PyResult<Vec<Key>> {
+        let target_sae_id = SaeId::new(target_sae_id).map_err(to_py_err)?;
+        let additional = sae_ids(additional_target_sae_ids)?;
+        let client = self.inner.clone();
+        block_on(py, async move {
+            let keys = client
+                .get_keys(key_size, &target_sae_id, &additional, amount)
+                .await;
+            keys.map(to_keys).map_err(to_py_err)
+        })
+    }
+
+    #[pyo3(signature = (target_sae_id, amount=1, key_size=256, additional_target_sae_ids=vec![]))]
+    fn get_keys_async<'py>(
+        &self,
+        py: Python<'py>,
+        target_sae_id: String,
+        amount: u32,
+        key_size: u32,
+        additional_target_sae_ids: Vec<String>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let target_sae_id = SaeId::new(target_sae_id).map_err(to_py_err)?;
+        let additional = sae_ids(additional_target_sae_ids)?;
+        let client = self.inner.clone();
+        future_into_py(py, async move {
+            let keys = client
+                .get_keys(key_size, &target_sae_id, &additional, amount)
+                .await;
+            keys.map(to_keys).map_err(to_py_err)
+        })
+    }
+
+    fn get_keys_by_ids(
+        &self,
+        py: Python<'_>,
+        target_sae_id: String,
+        key_ids: Vec<String>,
+    ) -> PyResult<Vec<Key>> {
+        let target_sae_id = SaeId::new(target_sae_id).map_err(to_py_err)?;
+        let key_ids = key_ids
+            .into_iter()
+            .map(|id| KeyId::new(id).map_err(to_py_err))
+            .collect::<PyResult<Vec<_>>>()?;
+        let client = self.inner.clone();
+        block_on(py, async move {
+            let keys = client.get_keys_by_ids(&target_sae_id, &key_ids).await;
+            keys.map(to_keys).map_err(to_py_err)
+        })
+    }
+
+    fn get_keys_by_ids_async<'py>(
+        &self,
+        py: Python<'py>,
+        target_sae_id: String,
+        key_ids: Vec<String>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let target_sae_id = SaeId::new(target_sae_id).map_err(to_py_err)?;
+        let key_ids = key_ids
+            .into_iter()
+            .map(|id| KeyId::new(id).map_err(to_py_err))
+            .collect::<PyResult<Vec<_>>>()?;
+        let client = self.inner.clone();
+        future_into_py(py, async move {
+            let keys = client.get_keys_by_ids(&target_sae_id, &key_ids).await;
+            keys.map(to_keys).map_err(to_py_err)
+        })
+    }
+}
+
+#[pymodule(name = "etsi014_client")]
+fn py_etsi014_client(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Client>()?;
+    m.add_class::<Status>()?;
+    m.add_class::<Key>()?;
+    m.add_class::<KeyBuffer>()?;
+    m.add_class::<KeyUnlocked>()?;
+    m.add("ETSI014Error", m.py().get_type::<ETSI014Error>())?;
+    let atexit = m.py().import("atexit")?;
+    atexit.call_method1("register", (wrap_pyfunction!(wait_for_pending, m)?,))?;
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use pyo3::BoundObject;
+use pyo3::prelude::*;
+use pyo3::wrap_pyfunction;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+/// Futures that still have to pass their result to Python.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+fn runtime() -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("Error creating tokio runtime")
+    })
+}
+
+/// Run a future to completion without holding the GIL.
+pub fn block_on<F, T>(py: Python<'_>, future: F) -> PyResult<T>
+where
+    F: Future<Output = PyResult<T>> + Send,
+    T: Send,
+{
+    py.allow_threads(|| runtime().block_on(future))
+}
+
+#[pyfunction]
+fn set_future_result(
+    future: &Bound<'_, PyAny>,
+    ok: bool,
+    value: &Bound<'_, PyAny>,
+) -> PyResult<()> {
+    if future.call_method0("cancelled")?.is_truthy()? {
+        return Ok(());
+    }
+    let method = if ok { "set_result" } else { "set_exception" };
+    future.call_method1(method, (value,))?;
+    Ok(())
+}
+
+/// Run a future on the tokio runtime and return an asyncio future for its result. Must be
+/// called from a coroutine, as it uses the running event loop.
+pub fn future_into_py<'py, F, T>(
+    py: Python<'py>,
+    future: F,
+) -> PyResult<Bound<'py, PyAny>>
+where
+    F: Future<Output = PyResult<T>> + Send + 'static,
+    T: for<'a> IntoPyObject<'a> + Send + 'static,
+{
+    let event_loop = py.import("asyncio")?.call_method0("get_running_loop")?;
+    let py_future = event_loop.call_method0("create_future")?;
+    let event_loop = event_loop.unbind();
+    let result_future = py_future.clone().unbind();
+    PENDING.fetch_add(1, Ordering::SeqCst);
+    runtime().spawn(async move {
+        let result = future.await;
+        Python::with_gil(|py| {
+            let (ok, value) = match result.and_then(|value| {
+                Ok(value
+                    .into_pyobject(py)
+                    .map_err(Into::into)?
+                    .into_any()
+                    .into_bound())
+            }) {
+                Ok(value) => (true, value),
+                Err(e) => (false, e.into_value(py).into_bound(py).into_any()),
+            };
+            let callback = wrap_pyfunction!(set_future_result, py);
+            let scheduled = callback.and_then(|callback| {
+                event_loop.call_method1(
+                    py,
+                    "call_soon_threadsafe",
+                    (callback, result_future, ok, value),
+                )
+            });
+            // The event loop was closed, nobody is waiting for the result anymore
+            drop(scheduled);
+            drop(event_loop);
+        });
+        PENDING.fetch_sub(1, Ordering::SeqCst);
+    });
+    Ok(py_future)
+}
+
+/// Registered with atexit. Python objects must not be touched by runtime threads once the
+/// interpreter is finalizing, so wait for the futures that still hold the GIL or need it.
+#[pyfunction]
+pub fn wait_for_pending(py: Python<'_>) {
+    py.allow_threads(|| {
+        let start = Instant::now();
+        while PENDING.load(Ordering::SeqCst) != 0
+            && start.elapsed() < Duration::from_secs(5)
+        {
+            thread::sleep(Duration::from_millis(1));
+        }
+    })
+}
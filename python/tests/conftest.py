@@ -0,0 +1,44 @@
+import os
+import subprocess
+from pathlib import Path
+
+import pytest
+
+from etsi014_client import ETSI014Client
+
+ROOT = Path(__file__).resolve().parents[2]
+TESTDATA = ROOT / "mock-kme" / "testdata"
+
+
+@pytest.fixture(scope="session")
+def kme_port():
+    """Port of the mock KME in `mock-kme`, or of the binary in `ETSI014_MOCK_KME`."""
+    binary = os.environ.get("ETSI014_MOCK_KME")
+    command = [binary] if binary else ["cargo", "run", "--quiet", "-p", "etsi014-mock-kme", "--"]
+    process = subprocess.Popen(
+        [*command, "--listen", "127.0.0.1:0"], cwd=ROOT, stdout=subprocess.PIPE, text=True
+    )
+    try:
+        # Listening on 127.0.0.1:<port>, certificates in <dir>
+        line = process.stdout.readline()
+        assert line.startswith("Listening on "), "Mock KME did not start"
+        yield int(line.split(",")[0].rsplit(":", 1)[1])
+    finally:
+        process.terminate()
+        process.wait()
+
+
+@pytest.fixture(scope="session")
+def client(kme_port):
+    """Client of SAE `name` of the mock KME."""
+
+    def client(name):
+        return ETSI014Client(
+            "localhost",
+            kme_port,
+            TESTDATA / f"{name}.crt",
+            TESTDATA / f"{name}.key",
+            TESTDATA / "ca.crt",
+        )
+
+    return client
@@ -0,0 +1,113 @@
+import asyncio
+import gc
+
+import pytest
+
+from etsi014_client import ETSI014Error
+
+
+def key_bytes(key):
+    with key.unlocked() as buf:
+        return bytes(buf)
+
+
+def test_status(client):
+    status = client("client-1").get_status("client-2")
+    assert status.source_kme_id == "kme-1"
+    assert status.source_sae_id == "client-1"
+    assert status.target_sae_id == "client-2"
+    assert status.key_size == 256
+    assert 'source_sae_id="client-1"' in repr(status)
+
+
+def test_status_async(client):
+    async def status():
+        return await client("client-1").get_status_async("client-2")
+
+    status = asyncio.run(status())
+    assert status.source_sae_id == "client-1"
+    assert status.target_sae_id == "client-2"
+
+
+def test_get_keys(client):
+    keys = client("client-1").get_keys("client-2", amount=3, key_size=512)
+    assert len(keys) == 3
+    assert len({key.key_id for key in keys}) == 3
+    assert all(len(key) == 64 for key in keys)
+    assert all(len(key_bytes(key)) == 64 for key in keys)
+    assert repr(keys[0]) == f'Key(key_id="{keys[0].key_id}", size=64)'
+
+
+def test_get_keys_by_ids(client):
+    keys = client("client-1").get_keys("client-2", amount=2)
+    received = client("client-2").get_keys_by_ids("client-1", [key.key_id for key in keys])
+    assert [key.key_id for key in received] == [key.key_id for key in keys]
+    assert [key_bytes(key) for key in received] == [key_bytes(key) for key in keys]
+
+
+def test_get_keys_async(client):
+    async def exchange():
+        keys = await client("client-1").get_keys_async("client-3", amount=2, key_size=128)
+        key_ids = [key.key_id for key in keys]
+        received = await client("client-3").get_keys_by_ids_async("client-1", key_ids)
+        return keys, received
+
+    keys, received = asyncio.run(exchange())
+    assert all(len(key) == 16 for key in keys)
+    assert [key_bytes(key) for key in received] == [key_bytes(key) for key in keys]
+
+
+def test_errors(client):
+    keys = client("client-1").get_keys("client-2")
+    with pytest.raises(ETSI014Error) as e:
+        client("client-3").get_keys_by_ids("client-1", [keys[0].key_id])
+    assert e.value.args[1] == "InvalidResponse"
+    assert "client-3 may not retrieve key" in e.value.args[0]
+
+    with pytest.raises(ETSI014Error) as e:
+        client("client-1").get_status("")
+    assert e.value.args[1] == "InvalidArgument"
+
+    async def unknown_key():
+        await client("client-2").get_keys_by_ids_async("client-1", ["unknown"])
+
+    with pytest.raises(ETSI014Error) as e:
+        asyncio.run(unknown_key())
+    assert e.value.args[1] == "InvalidResponse"
+
+
+def test_unlock_and_release(client):
+    [key] = client("client-1").get_keys("client-2")
+    with key.unlocked() as buf:
+        assert buf.readonly
+        assert len(buf) == 32
+        content = bytes(buf)
+        with pytest.raises(TypeError):
+            buf[0] = 0
+        with pytest.raises(RuntimeError):
+            with key.unlocked():
+                pass
+        exporter = buf.obj
+    with pytest.raises(ValueError):
+        bytes(buf)
+    # No new memoryviews once the key is protected again
+    with pytest.raises(BufferError):
+        memoryview(exporter)
+    # Can be unlocked again
+    assert key_bytes(key) == content
+
+
+def test_key_stays_unlocked_while_exported(client):
+    [key] = client("client-1").get_keys("client-2")
+    unlocked = key.unlocked()
+    buf = unlocked.__enter__()
+    content = bytes(buf)
+    part = buf[:8]
+    with pytest.raises(BufferError):
+        unlocked.__exit__(None, None, None)
+    # The memoryview keeps the key alive, so its memory is neither freed nor protected
+    del key, buf
+    gc.collect()
+    assert bytes(part) == content[:8]
+    part.release()
+    unlocked.__exit__(None, None, None)